@@ -0,0 +1,285 @@
+use std::process::exit;
+
+use clio::{Input, Output};
+use num_bigint_dig::{BigInt, BigUint, RandBigInt, ToBigInt};
+use num_integer::Integer;
+use num_traits::{One, Signed, Zero};
+
+use crate::{key::{PrivateKey, PublicKey}, mainutil::{self, read_key}, millers, sieve};
+
+//Classic attacks on weak RSA keys, each with a bounded amount of work. A strong key
+//generated by generate-keys resists all of them; one that falls to any has a real problem.
+
+//Steps of Fermat's method, enough for primes that agree in about their top half
+const FERMAT_STEPS: u64 = 100_000;
+
+//Iterations of Pollard's rho, enough to find factors up to about 30 bits
+const RHO_ITERATIONS: u64 = 100_000;
+
+//Products of this many iterations are taken before each gcd in rho
+const RHO_BATCH: u64 = 100;
+
+//Moduli smaller than this are within reach of public factoring records
+const MIN_MODULUS_BITS: usize = 1024;
+
+//What an attack found out about the key
+pub enum Finding {
+    //A nontrivial factor of n
+    Factor(BigUint),
+    //The private exponent, with the factors it gives away
+    PrivateExponent(BigUint, BigUint)
+}
+
+//x = ceil(sqrt(n)), then x + 1, x + 2, ... until x^2 - n is a square y^2, so n = (x - y)(x + y).
+//Quick exactly when p and q are close together.
+pub fn fermat(n: &BigUint, steps: u64) -> Option<BigUint> {
+    if n.is_even() {
+        return Some(BigUint::from(2u8));
+    }
+    let mut x = n.sqrt();
+    if &x * &x < *n {
+        x += 1u8;
+    }
+    for _ in 0..steps {
+        let y_squared = &x * &x - n;
+        let y = y_squared.sqrt();
+        if &y * &y == y_squared {
+            let factor = &x - &y;
+            return Some(factor).filter(|f| !f.is_one());
+        }
+        x += 1u8;
+    }
+    None
+}
+
+//Wiener: if d < n^(1/4) / 3 then d is the denominator of a convergent of e / n. Each candidate
+//k / d gives phi = (ed - 1) / k, and phi is right when x^2 - (n - phi + 1)x + n has integer roots.
+//Returns d and a factor.
+pub fn wiener(n: &BigUint, e: &BigUint) -> Option<(BigUint, BigUint)> {
+    let (n_int, e_int) = (n.to_bigint()?, e.to_bigint()?);
+    let (mut numerator, mut denominator) = (e_int.clone(), n_int.clone());
+    //Convergents h / k from the continued fraction of e / n
+    let (mut h_prev, mut h) = (BigInt::zero(), BigInt::one());
+    let (mut k_prev, mut k) = (BigInt::one(), BigInt::zero());
+    while !denominator.is_zero() {
+        let (quotient, remainder) = numerator.div_rem(&denominator);
+        (h_prev, h) = (h.clone(), &quotient * &h + &h_prev);
+        (k_prev, k) = (k.clone(), &quotient * &k + &k_prev);
+        (numerator, denominator) = (denominator, remainder);
+
+        //h / k approximates e / n, so h plays the multiplier and k the candidate d
+        let (multiplier, d) = (&h, &k);
+        if multiplier.is_zero() || d.is_zero() {
+            continue;
+        }
+        let ed_minus_one: BigInt = &e_int * d - 1;
+        if !(&ed_minus_one % multiplier).is_zero() {
+            continue;
+        }
+        let phi = ed_minus_one / multiplier;
+        let sum: BigInt = &n_int - &phi + 1;
+        let discriminant: BigInt = &sum * &sum - &n_int * 4;
+        if discriminant.is_negative() {
+            continue;
+        }
+        let root = discriminant.to_biguint()?.sqrt().to_bigint()?;
+        if &root * &root == discriminant && (&sum + &root).is_even() {
+            let p = ((&sum + &root) >> 1usize).to_biguint()?;
+            if !p.is_one() && &p != n && (n % &p).is_zero() {
+                return Some((d.to_biguint()?, p));
+            }
+        }
+    }
+    None
+}
+
+//Pollard p - 1: raises 2 to every prime power below the sieve limit. Finds p when p - 1 has
+//no prime power factor above that.
+pub fn pollard_p_minus_1(n: &BigUint) -> Option<BigUint> {
+    let limit = 1u64 << 16;
+    let mut a = BigUint::from(2u8);
+    for prime in std::iter::once(2u32).chain(sieve::small_primes().iter().copied()) {
+        let mut power = prime as u64;
+        while power * prime as u64 <= limit {
+            power *= prime as u64;
+        }
+        a = a.modpow(&BigUint::from(power), n);
+    }
+    let factor = (a + n - 1u8).gcd(n);
+    Some(factor).filter(|f| !f.is_one() && f != n)
+}
+
+//Pollard rho with Brent's cycle finding and batched gcds, for small factors
+pub fn pollard_rho(n: &BigUint, iterations: u64) -> Option<BigUint> {
+    if n.is_even() {
+        return Some(BigUint::from(2u8));
+    }
+    let mut rng = rand::thread_rng();
+    let f = |x: &BigUint, c: &BigUint| (x * x + c) % n;
+    let c = rng.gen_biguint_range(&BigUint::one(), n);
+    let mut y = rng.gen_biguint_below(n);
+    let mut product = BigUint::one();
+    let mut length = 1u64;
+    let mut done = 0u64;
+    while done < iterations {
+        let x = y.clone();
+        for _ in 0..length {
+            y = f(&y, &c);
+        }
+        let mut k = 0;
+        while k < length && done < iterations {
+            let saved = y.clone();
+            for _ in 0..RHO_BATCH.min(length - k) {
+                y = f(&y, &c);
+                let difference = if x > y { &x - &y } else { &y - &x };
+                product = product * difference % n;
+            }
+            k += RHO_BATCH;
+            done += RHO_BATCH;
+            if !product.gcd(n).is_one() {
+                //The batch overshot, step through it one at a time
+                let mut y = saved;
+                loop {
+                    y = f(&y, &c);
+                    let difference = if x > y { &x - &y } else { &y - &x };
+                    let factor = difference.gcd(n);
+                    if !factor.is_one() {
+                        return Some(factor).filter(|f| f != n);
+                    }
+                }
+            }
+        }
+        length *= 2;
+    }
+    None
+}
+
+//One attack, run against the key being audited
+type Attack<'a> = Box<dyn Fn() -> Option<Finding> + 'a>;
+
+//Why e and the modulus size are weak together, if they are
+pub fn small_parameters(key: &PublicKey) -> Option<String> {
+    let bits = key.n.bits();
+    if bits < MIN_MODULUS_BITS {
+        return Some(format!("the {bits}-bit modulus is small enough to factor with public tools"));
+    }
+    if key.e < BigUint::from(65537u32) && bits < 2048 {
+        return Some(format!("e = {} with a {bits}-bit modulus lets short unpadded messages be recovered with an e-th root", key.e));
+    }
+    None
+}
+
+//The private key from a factor of a two prime modulus, if both halves are prime
+pub fn recover(key: &PublicKey, factor: &BigUint) -> Option<PrivateKey> {
+    let other = &key.n / factor;
+    if !millers::is_prime_miller(factor) || !millers::is_prime_miller(&other) {
+        return None;
+    }
+    PrivateKey::from_primes(&[factor.clone(), other], key.e.clone())
+}
+
+//The audit-key subcommand. Exits with 1 if any attack broke the key.
+pub fn audit_key(pubkey: Option<Input>, output: Option<Output>) {
+    let key = PublicKey::from_text(read_key(mainutil::input_or_default(pubkey, "./public.txt")).expose());
+    let (n, e) = (&key.n, &key.e);
+    println!("Auditing {}-bit key {}", n.bits(), key.fingerprint());
+
+    let attacks: [(&str, Attack); 4] = [
+        ("Fermat factorization (close primes)", Box::new(|| fermat(n, FERMAT_STEPS).map(Finding::Factor))),
+        ("Wiener's attack (small d)", Box::new(|| wiener(n, e).map(|(d, p)| Finding::PrivateExponent(d, p)))),
+        ("Pollard p - 1 (smooth p - 1)", Box::new(|| pollard_p_minus_1(n).map(Finding::Factor))),
+        ("Pollard rho (small factor)", Box::new(|| pollard_rho(n, RHO_ITERATIONS).map(Finding::Factor)))
+    ];
+
+    let mut recovered = None;
+    let mut broken = false;
+    for (name, attack) in attacks {
+        match attack() {
+            None => println!("  {name}: resisted"),
+            Some(finding) => {
+                broken = true;
+                let factor = match finding {
+                    Finding::Factor(p) => {
+                        println!("  {name}: BROKEN, found the factor {p}");
+                        p
+                    }
+                    Finding::PrivateExponent(d, p) => {
+                        println!("  {name}: BROKEN, d = {d} has only {} bits", d.bits());
+                        p
+                    }
+                };
+                if recovered.is_none() {
+                    recovered = recover(&key, &factor);
+                }
+            }
+        }
+    }
+    match small_parameters(&key) {
+        Some(warning) => println!("  Small e and modulus: WEAK, {warning}"),
+        None => println!("  Small e and modulus: ok")
+    }
+
+    match (recovered, output) {
+        (Some(private), Some(mut output)) => {
+            mainutil::write_output(&mut output, private.to_text().expose());
+            println!("Recovered the private key.");
+        }
+        (Some(_), None) => println!("Recovered the private key, give -o to save it."),
+        (None, _) if broken => println!("The key is broken, but it isn't two primes so no private key was rebuilt."),
+        (None, _) => ()
+    }
+    if broken {
+        exit(1);
+    }
+}
+
+#[test]
+fn attacks_break_weak_keys() {
+    use num_bigint_dig::ModInverse;
+
+    let random_prime = |bits: usize| sieve::random_prime(bits, &crate::primality::MillerRabin, &mut rand::thread_rng());
+
+    //Close primes
+    let p = random_prime(256);
+    let q = sieve::next_prime(&(&p + 1000u32), &crate::primality::MillerRabin, &mut rand::thread_rng());
+    let factor = fermat(&(&p * &q), FERMAT_STEPS).unwrap();
+    assert!(factor == p || factor == q);
+
+    //A tiny d
+    let (p, q) = (random_prime(256), random_prime(256));
+    let phi = (&p - 1u8) * (&q - 1u8);
+    let d = (20_000u32..).map(BigUint::from).find(|d| d.gcd(&phi).is_one()).unwrap();
+    let e = d.clone().mod_inverse(&phi).unwrap().to_biguint().unwrap();
+    let (found, factor) = wiener(&(&p * &q), &e).unwrap();
+    assert_eq!(found, d);
+    assert!(factor == p || factor == q);
+
+    //p - 1 = 2 * (a product of distinct small primes)
+    let smooth = loop {
+        let mut candidate = BigUint::from(2u8);
+        let mut rng = rand::thread_rng();
+        let mut used = Vec::new();
+        while candidate.bits() < 200 {
+            let prime = sieve::small_primes()[rand::Rng::gen_range(&mut rng, 0..sieve::small_primes().len())];
+            if !used.contains(&prime) {
+                used.push(prime);
+                candidate *= prime;
+            }
+        }
+        if millers::is_prime_miller(&(&candidate + 1u8)) {
+            break candidate + 1u8;
+        }
+    };
+    let strong = random_prime(256);
+    assert_eq!(pollard_p_minus_1(&(&smooth * &strong)).unwrap(), smooth);
+
+    //A small factor
+    let small = random_prime(24);
+    assert_eq!(pollard_rho(&(&small * random_prime(256)), RHO_ITERATIONS).unwrap(), small);
+
+    //A well made key resists all of them
+    let n = random_prime(256) * random_prime(256);
+    assert!(fermat(&n, 1000).is_none());
+    assert!(wiener(&n, &BigUint::from(65537u32)).is_none());
+    assert!(pollard_rho(&n, 10_000).is_none());
+}
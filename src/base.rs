@@ -21,7 +21,7 @@ fn parse_string(input: &str, alphabet: &str) -> String {
         new_string.push(input.chars().nth(i).unwrap());
     }
 
-    return new_string;
+    new_string
 } 
 
 
@@ -51,7 +51,7 @@ pub fn to_base10(input: &str, alphabet: &str) -> BigUint {
         output += multiplier * digit.to_biguint().unwrap();
     }
 
-    return output;
+    output
 }
 
 pub fn from_base10(input: BigUint, alphabet: &str) -> String {
@@ -68,7 +68,7 @@ pub fn from_base10(input: BigUint, alphabet: &str) -> String {
         output.push(new_char);
     }
 
-    return output.chars().rev().collect::<String>();
+    output.chars().rev().collect::<String>()
 }
 
 #[test]
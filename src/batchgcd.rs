@@ -0,0 +1,181 @@
+use std::{collections::HashMap, fs, path::{Path, PathBuf}, process::exit};
+
+use clio::ClioPath;
+use num_bigint_dig::BigUint;
+use num_integer::Integer;
+use num_traits::One;
+
+use crate::{audit::recover, key::PublicKey, keyring, metadata::split_key_text};
+
+//Bernstein's batch GCD: the gcd of every modulus with the product of all the others, for
+//thousands of keys in about the time of a few big multiplications instead of one gcd per
+//pair. Keys made from a bad random number generator, or from the same seed, often share a
+//prime, and a shared prime factors both moduli.
+
+//Name of the files scan-keys loads
+const PUBLIC_KEY_FILE: &str = "public.txt";
+
+//Levels of products, from the numbers themselves up to the product of all of them
+fn product_tree(numbers: &[BigUint]) -> Vec<Vec<BigUint>> {
+    let mut tree = vec![numbers.to_vec()];
+    while tree.last().is_some_and(|level| level.len() > 1) {
+        let level = tree.last().unwrap();
+        tree.push(Vec::from_iter(level.chunks(2).map(|pair| pair.iter().product())));
+    }
+    tree
+}
+
+//gcd(n_i, product of all the other moduli) for each n_i. Walks back down the product tree
+//keeping P mod n^2 for every node n, then (P mod n_i^2) / n_i is the other moduli's product
+//mod n_i. 1 means n_i shares nothing, n_i itself means it shares both its primes (or is
+//repeated).
+pub fn batch_gcd(moduli: &[BigUint]) -> Vec<BigUint> {
+    if moduli.len() < 2 {
+        return vec![BigUint::one(); moduli.len()];
+    }
+    let tree = product_tree(moduli);
+    let mut remainders = tree.last().unwrap().clone();
+    for level in tree.iter().rev().skip(1) {
+        remainders = Vec::from_iter(level.iter().enumerate().map(|(i, n)| &remainders[i / 2] % (n * n)));
+    }
+    Vec::from_iter(moduli.iter().zip(remainders).map(|(n, r)| (r / n).gcd(n)))
+}
+
+//A public key and the file it came from
+pub struct ScannedKey {
+    pub path: PathBuf,
+    pub key: PublicKey
+}
+
+//n and e from a public key file, None if it isn't one
+fn parse_public_key(text: &str) -> Option<PublicKey> {
+    let (numbers, _) = split_key_text(text);
+    let values = Vec::from_iter(numbers.trim().split('\n').map(|l| l.trim().parse::<BigUint>().ok()));
+    match values.as_slice() {
+        [Some(n), Some(e)] => Some(PublicKey::new(n.clone(), e.clone())),
+        _ => None
+    }
+}
+
+//Every public.txt under dir, at any depth. Files that don't parse are reported and skipped.
+pub fn load_keys(dir: &Path) -> Vec<ScannedKey> {
+    let mut keys = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let listing = match fs::read_dir(&dir) {
+            Ok(l) => l,
+            Err(e) => panic!("Could not read {}: {e}", dir.display())
+        };
+        for path in listing.filter_map(|d| d.ok()).map(|d| d.path()) {
+            if path.is_dir() {
+                pending.push(path);
+            } else if path.file_name().is_some_and(|name| name == PUBLIC_KEY_FILE) {
+                match fs::read_to_string(&path).ok().as_deref().and_then(parse_public_key) {
+                    Some(key) => keys.push(ScannedKey { path, key }),
+                    None => eprintln!("Skipping {}, it isn't a public key", path.display())
+                }
+            }
+        }
+    }
+    keys.sort_by(|a, b| a.path.cmp(&b.path));
+    keys
+}
+
+//Two keys whose moduli have a common factor
+pub struct SharedFactor {
+    pub first: usize,
+    pub second: usize,
+    //The common factor, or n itself when both keys have the same modulus
+    pub factor: BigUint
+}
+
+//Every pair of keys whose moduli share a factor. Only moduli batch_gcd flags are compared
+//pairwise, which are few in any sane fleet.
+pub fn shared_factors(keys: &[ScannedKey]) -> Vec<SharedFactor> {
+    let moduli = Vec::from_iter(keys.iter().map(|k| k.key.n.clone()));
+    let flagged = Vec::from_iter(batch_gcd(&moduli).iter().enumerate().filter(|(_, g)| !g.is_one()).map(|(i, _)| i));
+    let mut found = Vec::new();
+    for (a, &first) in flagged.iter().enumerate() {
+        for &second in &flagged[a + 1..] {
+            let factor = moduli[first].gcd(&moduli[second]);
+            if !factor.is_one() {
+                found.push(SharedFactor { first, second, factor });
+            }
+        }
+    }
+    found
+}
+
+//The scan-keys subcommand. Exits with 1 if any keys share a factor.
+pub fn scan_keys(dir: ClioPath, recovered_dir: Option<ClioPath>) {
+    let keys = load_keys(dir.path());
+    println!("Loaded {} public keys from {}", keys.len(), dir.path().display());
+    let found = shared_factors(&keys);
+    if found.is_empty() {
+        println!("No two keys share a factor.");
+        return;
+    }
+
+    //A proper factor of each broken modulus, to rebuild its private key from
+    let mut factors = HashMap::new();
+    for shared in &found {
+        let (first, second) = (&keys[shared.first], &keys[shared.second]);
+        if shared.factor == first.key.n && shared.factor == second.key.n {
+            println!("{} and {} have the same modulus", first.path.display(), second.path.display());
+            continue;
+        }
+        println!("{} and {} share the factor {}", first.path.display(), second.path.display(), shared.factor);
+        for scanned in [first, second] {
+            if shared.factor != scanned.key.n {
+                factors.entry(scanned.key.n.clone()).or_insert_with(|| shared.factor.clone());
+            }
+        }
+    }
+
+    let mut recovered = 0;
+    for scanned in &keys {
+        let private = match factors.get(&scanned.key.n).and_then(|f| recover(&scanned.key, f)) {
+            Some(private) => private,
+            None => continue
+        };
+        recovered += 1;
+        let path = match &recovered_dir {
+            Some(d) => d.path().join(format!("private-{}.txt", &scanned.key.fingerprint()[..16])),
+            None => {
+                println!("Recovered the private key of {}", scanned.path.display());
+                continue;
+            }
+        };
+        match keyring::write_private(&path, private.to_text().expose()) {
+            Ok(_) => println!("Recovered the private key of {} to {}", scanned.path.display(), path.display()),
+            Err(e) => panic!("Could not write {}: {e}", path.display())
+        }
+    }
+    if recovered > 0 && recovered_dir.is_none() {
+        println!("Give -d to write the recovered private keys.");
+    }
+    println!("Rotate every key listed above.");
+    exit(1);
+}
+
+#[test]
+fn batch_gcd_finds_shared_primes() {
+    let mut rng = rand::thread_rng();
+    let primes = Vec::from_iter((0..6).map(|_| {
+        let start = num_bigint_dig::RandBigInt::gen_biguint(&mut rng, 128) | (BigUint::one() << 127);
+        crate::sieve::next_prime(&start, &crate::primality::MillerRabin, &mut rng)
+    }));
+    let [p, q, r, s, t, u] = [0, 1, 2, 3, 4, 5].map(|i| &primes[i]);
+
+    //Two keys share p, two share s, and the first modulus is used twice
+    let moduli = [p * q, s * u, p * r, s * t, p * q];
+    assert_eq!(batch_gcd(&moduli), [p * q, s.clone(), p.clone(), s.clone(), p * q]);
+
+    let keys = Vec::from_iter(moduli.iter().enumerate().map(|(i, n)| ScannedKey {
+        path: PathBuf::from(i.to_string()),
+        key: PublicKey::new(n.clone(), BigUint::from(65537u32))
+    }));
+    let found = Vec::from_iter(shared_factors(&keys).into_iter().map(|f| (f.first, f.second, f.factor)));
+    assert_eq!(found, [(0, 2, p.clone()), (0, 4, p * q), (1, 3, s.clone()), (2, 4, p.clone())]);
+    assert_eq!(recover(&keys[2].key, p).unwrap().n, p * r);
+}
@@ -0,0 +1,149 @@
+use clio::{ClioPath, Input, Output};
+use num_bigint_dig::{BigUint, ModInverse, RandBigInt};
+use rand_core::CryptoRngCore;
+
+use crate::{key::{PrivateKey, PublicKey}, keyring, mainutil::{self, armored_fields, parse_hex, read_key, to_hex}, secret::Secret, signature::{self, DetachedSignature, MESSAGE_CONTEXT}, InputArgGroup};
+
+//Chaum blind signatures over the full domain hash signature.rs uses. The requester sends
+//H(m) * r^e for a random r, the signer returns (H(m) * r^e)^d = H(m)^d * r, and dividing by r
+//leaves an ordinary signature on m that the signer never saw and can't link to the request.
+//
+//The blinded value and the blind signature are armored as
+//
+//  -----BEGIN RSA_RUST BLINDED MESSAGE-----       -----BEGIN RSA_RUST BLIND SIGNATURE-----
+//  key <fingerprint of the signing key>           key <fingerprint>
+//  value <hex>                                    value <hex>
+//  -----END RSA_RUST BLINDED MESSAGE-----         -----END RSA_RUST BLIND SIGNATURE-----
+//
+//and the unblinding factor r, which stays with the requester, the same way as an
+//UNBLINDING FACTOR.
+const BLINDED: &str = "BLINDED MESSAGE";
+const BLIND_SIGNATURE: &str = "BLIND SIGNATURE";
+const FACTOR: &str = "UNBLINDING FACTOR";
+
+//The value for the signer and the factor that unblinds its answer
+pub fn blind<R: CryptoRngCore + ?Sized>(key: &PublicKey, message: &[u8], rng: &mut R) -> (BigUint, Secret<BigUint>) {
+    let n = &key.n;
+    let r = loop {
+        let r = Secret::new(rng.gen_biguint_range(&BigUint::from(2u8), n));
+        if r.expose().mod_inverse(n).is_some() {
+            break r;
+        }
+    };
+    let hashed = signature::full_domain_hash(MESSAGE_CONTEXT, message, n);
+    (hashed * key.encrypt_block(r.expose()) % n, r)
+}
+
+//The signer's side, a raw private key operation on whatever it's given
+pub fn sign_blinded<R: CryptoRngCore + ?Sized>(key: &PrivateKey, blinded: &BigUint, rng: &mut R) -> Result<BigUint, String> {
+    if *blinded >= key.n {
+        return Err("The blinded value is too big for this key".to_string());
+    }
+    Ok(key.private_op(blinded, rng).expose().clone())
+}
+
+//The signature on the original message, None if the blind signature doesn't unblind to one
+pub fn unblind(key: &PublicKey, message: &[u8], blind_signature: &BigUint, r: &BigUint) -> Option<BigUint> {
+    let r_inverse = r.mod_inverse(&key.n)?.to_biguint()?;
+    let signature = blind_signature * r_inverse % &key.n;
+    match signature::verify(key, MESSAGE_CONTEXT, message, &signature) {
+        true => Some(signature),
+        false => None
+    }
+}
+
+fn armor(kind: &str, fingerprint: &str, value: &BigUint) -> String {
+    format!("-----BEGIN RSA_RUST {kind}-----\nkey {fingerprint}\nvalue {}\n-----END RSA_RUST {kind}-----\n", to_hex(&value.to_bytes_be()))
+}
+
+//The fingerprint and value in an armor written by armor
+fn dearmor(text: &str, kind: &str) -> Result<(String, BigUint), String> {
+    let (mut fingerprint, mut value) = (None, None);
+    for (field, line) in armored_fields(text, kind)? {
+        match field {
+            "key" => fingerprint = Some(line.to_string()),
+            "value" => value = Some(parse_hex(line)?),
+            _ => return Err(format!("Unexpected line \"{field} {line}\""))
+        }
+    }
+    match (fingerprint, value) {
+        (Some(fingerprint), Some(value)) => Ok((fingerprint, value)),
+        _ => Err(format!("The {} is missing its key or value", kind.to_lowercase()))
+    }
+}
+
+//The blind subcommand. The factor goes to its own file, only readable by its owner, and is
+//needed again by unblind.
+pub fn blind_message(input: InputArgGroup, pubkey: Option<Input>, mut output: Output, factor_file: ClioPath) {
+    let message = mainutil::parse_input_group(input);
+    let key = PublicKey::from_text(read_key(mainutil::input_or_default(pubkey, "./public.txt")).expose());
+    let fingerprint = key.fingerprint();
+    let (blinded, r) = blind(&key, message.as_bytes(), &mut rand::thread_rng());
+
+    let factor = Secret::new(armor(FACTOR, &fingerprint, r.expose()));
+    if let Err(e) = keyring::write_private(factor_file.path(), factor.expose()) {
+        panic!("Could not write {}: {e}", factor_file.path().display());
+    }
+    mainutil::write_output(&mut output, &armor(BLINDED, &fingerprint, &blinded));
+}
+
+//The sign-blinded subcommand, run by the signer
+pub fn sign_blinded_message(input: InputArgGroup, privkey: Option<Input>, force: bool, mut output: Output) {
+    let (fingerprint, blinded) = match dearmor(&mainutil::parse_input_group(input), BLINDED) {
+        Ok(b) => b,
+        Err(e) => panic!("{e}")
+    };
+    let key = PrivateKey::from_text(read_key(mainutil::input_or_default(privkey, "./private.txt")).expose());
+    if key.public().fingerprint() != fingerprint {
+        panic!("The message was blinded for key {fingerprint}, not this one");
+    }
+    signature::check_signing_key(&key, force);
+
+    match sign_blinded(&key, &blinded, &mut rand::thread_rng()) {
+        Ok(signature) => mainutil::write_output(&mut output, &armor(BLIND_SIGNATURE, &fingerprint, &signature)),
+        Err(e) => panic!("{e}")
+    }
+}
+
+//The unblind subcommand, writing a detached signature that verify accepts
+pub fn unblind_signature(input: InputArgGroup, blind_signature: Input, factor: Input, pubkey: Option<Input>, mut output: Output) {
+    let message = mainutil::parse_input_group(input);
+    let key = PublicKey::from_text(read_key(mainutil::input_or_default(pubkey, "./public.txt")).expose());
+    let read = |input: Input, kind: &str| {
+        let text = read_key(input);
+        match dearmor(text.expose(), kind) {
+            Ok((fingerprint, _)) if fingerprint != key.fingerprint() => panic!("The {} is for key {fingerprint}, not the given key", kind.to_lowercase()),
+            Ok((_, value)) => Secret::new(value),
+            Err(e) => panic!("{e}")
+        }
+    };
+    let blind_signature = read(blind_signature, BLIND_SIGNATURE);
+    let r = read(factor, FACTOR);
+
+    match unblind(&key, message.as_bytes(), blind_signature.expose(), r.expose()) {
+        Some(signature) => {
+            let detached = DetachedSignature { fingerprint: key.fingerprint(), signature };
+            mainutil::write_output(&mut output, &detached.to_string());
+        }
+        None => panic!("The blind signature doesn't unblind to a valid signature on this message")
+    }
+}
+
+#[test]
+fn unblinded_signatures_verify() {
+    use crate::key::test_key;
+
+    let mut rng = rand::thread_rng();
+    let key = test_key(512);
+    let public = key.public();
+
+    let (blinded, r) = blind(&public, b"ballot 17", &mut rng);
+    assert_ne!(blinded, signature::full_domain_hash(MESSAGE_CONTEXT, b"ballot 17", &key.n));
+    let blind_signature = sign_blinded(&key, &blinded, &mut rng).unwrap();
+    let signature = unblind(&public, b"ballot 17", &blind_signature, r.expose()).unwrap();
+    assert!(signature::verify(&public, MESSAGE_CONTEXT, b"ballot 17", &signature));
+    assert!(unblind(&public, b"ballot 18", &blind_signature, r.expose()).is_none());
+
+    let (fingerprint, value) = dearmor(&armor(BLINDED, "ab", &blinded), BLINDED).unwrap();
+    assert_eq!((fingerprint.as_str(), value), ("ab", blinded));
+}
@@ -0,0 +1,86 @@
+use argon2::{Algorithm, Argon2, Params, Version};
+use rand::SeedableRng;
+use rand_chacha::ChaCha20Rng;
+use sha2::{Digest, Sha256};
+
+use crate::secret::Secret;
+
+//Argon2id cost for stretching a passphrase: 64 MiB, 3 passes, 1 lane (RFC 9106's second
+//recommended option). Changing these changes every brain key, so they're fixed.
+const KDF_MEMORY_KIB: u32 = 64 * 1024;
+const KDF_PASSES: u32 = 3;
+const KDF_LANES: u32 = 1;
+
+//Below this many estimated bits, offline guessing of the passphrase is realistic
+pub const MIN_ENTROPY_BITS: f64 = 80.0;
+
+//Stretches the passphrase and salt with Argon2id and seeds a ChaCha20 DRBG with the result.
+//The same passphrase and salt always give the same stream, so the same key.
+//The salt is hashed first, Argon2 wants at least 8 bytes of it.
+pub fn brain_key_rng(passphrase: &Secret<String>, salt: &str) -> ChaCha20Rng {
+    let salt = Sha256::digest(salt.as_bytes());
+    let params = match Params::new(KDF_MEMORY_KIB, KDF_PASSES, KDF_LANES, Some(32)) {
+        Ok(p) => p,
+        Err(e) => panic!("Bad Argon2 parameters: {e}")
+    };
+
+    let mut seed = Secret::new([0u8; 32]);
+    let res = Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.expose().as_bytes(), &salt, seed.expose_mut());
+    match res {
+        Ok(_) => (),
+        Err(e) => panic!("Could not stretch the passphrase: {e}")
+    }
+    ChaCha20Rng::from_seed(*seed.expose())
+}
+
+//A rough upper bound on the passphrase's entropy: its length times the bits per character of
+//the character classes it uses, with repeated characters counting for a quarter. Dictionary
+//words and patterns are worth far less than this, so a low estimate is certainly weak but a
+//high one isn't proof of strength.
+pub fn estimate_entropy(passphrase: &str) -> f64 {
+    let chars = Vec::from_iter(passphrase.chars());
+    let mut pool = 0u32;
+    if chars.iter().any(char::is_ascii_lowercase) {
+        pool += 26;
+    }
+    if chars.iter().any(char::is_ascii_uppercase) {
+        pool += 26;
+    }
+    if chars.iter().any(char::is_ascii_digit) {
+        pool += 10;
+    }
+    if chars.iter().any(|c| c.is_ascii_punctuation() || *c == ' ') {
+        pool += 33;
+    }
+    if chars.iter().any(|c| !c.is_ascii()) {
+        pool += 100;
+    }
+    if pool == 0 {
+        return 0.0;
+    }
+
+    let mut distinct = chars.clone();
+    distinct.sort_unstable();
+    distinct.dedup();
+    let repeats = chars.len() - distinct.len();
+    (distinct.len() as f64 + repeats as f64 / 4.0) * (pool as f64).log2()
+}
+
+#[test]
+fn brain_key_rng_is_deterministic() {
+    use rand::RngCore;
+
+    let passphrase = Secret::new("correct horse battery staple".to_string());
+    let first = brain_key_rng(&passphrase, "alice@example.com").next_u64();
+    assert_eq!(brain_key_rng(&passphrase, "alice@example.com").next_u64(), first);
+    assert_ne!(brain_key_rng(&passphrase, "bob@example.com").next_u64(), first);
+}
+
+#[test]
+fn entropy_estimate_orders_passphrases() {
+    assert_eq!(estimate_entropy(""), 0.0);
+    assert!(estimate_entropy("password") < MIN_ENTROPY_BITS);
+    assert!(estimate_entropy("aaaaaaaaaaaaaaaaaaaa") < estimate_entropy("abcdefghijklmnopqrst"));
+    assert!(estimate_entropy("Tr0ub4dor&3 plus-seven MORE w0rds!") > MIN_ENTROPY_BITS);
+}
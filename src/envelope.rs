@@ -0,0 +1,179 @@
+use chacha20poly1305::{aead::{Aead, Payload}, ChaCha20Poly1305, Key, KeyInit, Nonce};
+use rand_core::CryptoRngCore;
+
+use crate::{key::{PrivateKey, PublicKey}, mainutil::{from_hex, to_hex}, secret::Secret};
+
+//A message encrypted once with a random ChaCha20-Poly1305 content key, which is then wrapped
+//with RSA-OAEP for every recipient. The armored text looks like
+//
+//  -----BEGIN RSA_RUST MESSAGE-----
+//  recipient <key fingerprint> <wrapped content key>
+//  recipient ...
+//  nonce <nonce>
+//
+//  <ciphertext>
+//  -----END RSA_RUST MESSAGE-----
+//
+//with everything in hex. The header lines are authenticated along with the ciphertext.
+const BEGIN: &str = "-----BEGIN RSA_RUST MESSAGE-----";
+const END: &str = "-----END RSA_RUST MESSAGE-----";
+
+//Hex digits per line of ciphertext
+const LINE_LEN: usize = 64;
+
+const CONTENT_KEY_LEN: usize = 32;
+
+pub fn is_envelope(text: &str) -> bool {
+    text.trim_start().starts_with(BEGIN)
+}
+
+//Armor somewhere in text, even if something else was written in front of it
+pub fn contains_armor(text: &str) -> bool {
+    text.contains(BEGIN)
+}
+
+pub fn seal<R: CryptoRngCore + ?Sized>(plaintext: &[u8], recipients: &[PublicKey], rng: &mut R) -> Result<String, String> {
+    let mut content_key = Secret::new([0u8; CONTENT_KEY_LEN]);
+    rng.fill_bytes(content_key.expose_mut());
+    let mut nonce = [0u8; 12];
+    rng.fill_bytes(&mut nonce);
+
+    let mut header = String::new();
+    for recipient in recipients {
+        let wrapped = match recipient.encrypt_oaep(content_key.expose(), rng) {
+            Some(w) => w,
+            None => return Err(format!("The key {} is too small to wrap a content key", recipient.fingerprint()))
+        };
+        header.push_str(&format!("recipient {} {}\n", recipient.fingerprint(), to_hex(&wrapped)));
+    }
+    header.push_str(&format!("nonce {}\n", to_hex(&nonce)));
+
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(content_key.expose()));
+    let ciphertext = match cipher.encrypt(Nonce::from_slice(&nonce), Payload { msg: plaintext, aad: header.as_bytes() }) {
+        Ok(c) => to_hex(&c),
+        Err(e) => return Err(format!("Could not encrypt: {e}"))
+    };
+    let lines = Vec::from_iter(ciphertext.as_bytes().chunks(LINE_LEN).map(|l| String::from_utf8_lossy(l).into_owned()));
+
+    Ok(format!("{BEGIN}\n{header}\n{}\n{END}\n", lines.join("\n")))
+}
+
+//The parts of an armored message
+struct Parsed {
+    //As it was authenticated, with its final newline
+    header: String,
+    //Fingerprint and wrapped content key of each recipient
+    recipients: Vec<(String, Vec<u8>)>,
+    nonce: Vec<u8>,
+    ciphertext: Vec<u8>
+}
+
+fn parse(text: &str) -> Result<Parsed, String> {
+    let text = text.replace("\r\n", "\n");
+    let inner = match text.trim().strip_prefix(BEGIN).and_then(|t| t.strip_suffix(END)) {
+        Some(inner) => inner.trim_start_matches('\n'),
+        None => return Err("Not an encrypted message".to_string())
+    };
+    let (header, body) = match inner.split_once("\n\n") {
+        Some(parts) => parts,
+        None => return Err("The message has no body".to_string())
+    };
+    let header = format!("{header}\n");
+
+    let mut recipients = Vec::new();
+    let mut nonce = None;
+    for line in header.lines() {
+        match Vec::from_iter(line.split_whitespace()).as_slice() {
+            ["recipient", fingerprint, wrapped] => match from_hex(wrapped) {
+                Some(w) => recipients.push((fingerprint.to_string(), w)),
+                None => return Err(format!("Bad wrapped key for recipient {fingerprint}"))
+            },
+            ["nonce", n] => nonce = from_hex(n),
+            _ => return Err(format!("Unexpected header line \"{line}\""))
+        }
+    }
+    let nonce = match nonce {
+        Some(n) if n.len() == 12 => n,
+        _ => return Err("The message has no valid nonce".to_string())
+    };
+    let ciphertext = match from_hex(&body.split_whitespace().collect::<String>()) {
+        Some(c) => c,
+        None => return Err("The ciphertext isn't valid hex".to_string())
+    };
+    Ok(Parsed { header, recipients, nonce, ciphertext })
+}
+
+//Key fingerprints of the message's recipients
+pub fn recipients(text: &str) -> Result<Vec<String>, String> {
+    Ok(Vec::from_iter(parse(text)?.recipients.into_iter().map(|(f, _)| f)))
+}
+
+//The wrapped content key in the slot for the key with this fingerprint
+pub fn wrapped_key(text: &str, fingerprint: &str) -> Result<Vec<u8>, String> {
+    match parse(text)?.recipients.into_iter().find(|(f, _)| f == fingerprint) {
+        Some((_, wrapped)) => Ok(wrapped),
+        None => Err(format!("The message wasn't encrypted to {fingerprint}"))
+    }
+}
+
+//The plaintext under a content key unwrapped some other way, e.g. by threshold decryption
+pub fn open_with_content_key(text: &str, content_key: &[u8]) -> Result<Secret<Vec<u8>>, String> {
+    let Parsed { header, nonce, ciphertext, .. } = parse(text)?;
+    if content_key.len() != CONTENT_KEY_LEN {
+        return Err("The content key is the wrong length".to_string());
+    }
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(content_key));
+    match cipher.decrypt(Nonce::from_slice(&nonce), Payload { msg: &ciphertext, aad: header.as_bytes() }) {
+        Ok(plaintext) => Ok(Secret::new(plaintext)),
+        Err(_) => Err("The message has been tampered with or is corrupt".to_string())
+    }
+}
+
+//The plaintext, if key is one of the recipients. The slot carrying key's fingerprint is
+//tried first, then every other one by trial, so a slot labelled some other way still opens.
+pub fn open<R: CryptoRngCore + ?Sized>(text: &str, key: &PrivateKey, rng: &mut R) -> Result<Secret<Vec<u8>>, String> {
+    let Parsed { header, mut recipients, nonce, ciphertext } = parse(text)?;
+
+    let fingerprint = key.public().fingerprint();
+    recipients.sort_by_key(|(f, _)| *f != fingerprint);
+
+    let mut unwrapped_any = false;
+    for (_, wrapped) in &recipients {
+        let content_key = match key.decrypt_oaep(wrapped, rng) {
+            Some(k) if k.expose().len() == CONTENT_KEY_LEN => k,
+            _ => continue
+        };
+        unwrapped_any = true;
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(content_key.expose()));
+        if let Ok(plaintext) = cipher.decrypt(Nonce::from_slice(&nonce), Payload { msg: &ciphertext, aad: header.as_bytes() }) {
+            return Ok(Secret::new(plaintext));
+        }
+    }
+
+    if unwrapped_any {
+        Err("The message has been tampered with or is corrupt".to_string())
+    } else {
+        Err("This private key isn't one of the message's recipients".to_string())
+    }
+}
+
+#[test]
+fn every_recipient_can_open() {
+    use crate::key::test_key;
+
+    let mut rng = rand::thread_rng();
+    let keys = Vec::from_iter((0..3).map(|_| test_key(1024)));
+    let recipients = Vec::from_iter(keys[..2].iter().map(|k| k.public()));
+
+    let message = "Any bytes at all: ünïcode, $ signs and\nnewlines".as_bytes();
+    let sealed = seal(message, &recipients, &mut rng).unwrap();
+    assert!(is_envelope(&sealed));
+    for key in &keys[..2] {
+        assert_eq!(open(&sealed, key, &mut rng).unwrap().expose(), message);
+    }
+    assert!(open(&sealed, &keys[2], &mut rng).is_err());
+
+    //The header is authenticated too
+    let tampered = sealed.replacen("\n\n", "\nrecipient 00 00\n\n", 1);
+    assert_eq!(open(&tampered, &keys[0], &mut rng).unwrap_err(), "The message has been tampered with or is corrupt");
+}
@@ -0,0 +1,336 @@
+use std::{collections::BTreeMap, fmt, process::exit, time::{Duration, Instant}};
+
+use num_bigint_dig::{BigUint, ModInverse, RandBigInt};
+use num_integer::Integer;
+use num_traits::{One, Zero};
+
+use crate::{audit::pollard_rho, millers, sieve};
+
+//Complete factorization of integers: trial division by the sieve's small primes, then
+//for what is left, Pollard rho (Brent) for factors up to about 35 bits and Lenstra's
+//elliptic curve method for bigger ones. ECM's work depends on the size of the factor it
+//finds, not of n, so it pulls 20 to 40 digit factors out of numbers far too big for anything
+//else here. Products of two big primes of similar size, like RSA moduli, are out of reach.
+
+//Iterations of rho before moving on to ECM
+const RHO_ITERATIONS: u64 = 200_000;
+
+//Stage 1 bound and number of curves at each step, after GMP-ECM's table for factors of 15,
+//20, 25, 30, 35 and 40 digits. The last step repeats until the time runs out.
+const ECM_SCHEDULE: [(u64, u32); 6] = [(2_000, 25), (11_000, 90), (50_000, 300), (250_000, 700), (1_000_000, 1_800), (3_000_000, 5_100)];
+
+//Stage 2 covers primes up to this many times the stage 1 bound
+const STAGE_2_FACTOR: u64 = 100;
+
+//Stage 2 giant steps are multiples of D = 2 * 3 * 5 * 7 * 11
+const D: u64 = 2_310;
+
+//Prime factors with their exponents, and whatever composite parts were left when the time
+//ran out
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Factorization {
+    pub primes: BTreeMap<BigUint, u32>,
+    pub composites: Vec<(BigUint, u32)>
+}
+
+impl Factorization {
+    fn add_prime(&mut self, p: BigUint, exponent: u32) {
+        *self.primes.entry(p).or_insert(0) += exponent;
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.composites.is_empty()
+    }
+}
+
+impl fmt::Display for Factorization {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::from_iter(self.primes.iter().map(|(p, e)| match e {
+            1 => p.to_string(),
+            e => format!("{p}^{e}")
+        }));
+        parts.extend(self.composites.iter().map(|(c, e)| match e {
+            1 => format!("{c} (composite)"),
+            e => format!("({c} (composite))^{e}")
+        }));
+        match parts.is_empty() {
+            true => write!(f, "1"),
+            false => write!(f, "{}", parts.join(" * "))
+        }
+    }
+}
+
+fn timed_out(deadline: Option<Instant>) -> bool {
+    deadline.is_some_and(|d| Instant::now() >= d)
+}
+
+//r and the smallest k > 1 with r^k = n, if n is a perfect power. Only for n with no factor
+//below the sieve limit, so r > 2^16 and k <= bits / 16.
+fn perfect_power(n: &BigUint) -> Option<(BigUint, u32)> {
+    (2..=n.bits() as u32 / 16).find_map(|k| {
+        let root = n.nth_root(k);
+        Some((root.clone(), k)).filter(|_| num_traits::pow(root.clone(), k as usize) == *n)
+    })
+}
+
+//A point on a Montgomery curve By^2 = x^3 + Ax^2 + x in X:Z coordinates. y is never needed.
+#[derive(Clone)]
+struct Point {
+    x: BigUint,
+    z: BigUint
+}
+
+//Curve arithmetic mod n, which only knows a24 = (A + 2) / 4
+struct Curve<'a> {
+    n: &'a BigUint,
+    a24: BigUint
+}
+
+impl Curve<'_> {
+    fn sub(&self, a: &BigUint, b: &BigUint) -> BigUint {
+        (a + self.n - b) % self.n
+    }
+
+    fn double(&self, p: &Point) -> Point {
+        let sum = (&p.x + &p.z) % self.n;
+        let difference = self.sub(&p.x, &p.z);
+        let sum_squared = &sum * &sum % self.n;
+        let difference_squared = &difference * &difference % self.n;
+        let t = self.sub(&sum_squared, &difference_squared);
+        Point {
+            x: &sum_squared * &difference_squared % self.n,
+            z: &t * ((&difference_squared + &self.a24 * &t) % self.n) % self.n
+        }
+    }
+
+    //p + q, given p - q
+    fn add(&self, p: &Point, q: &Point, difference: &Point) -> Point {
+        let u = self.sub(&p.x, &p.z) * ((&q.x + &q.z) % self.n) % self.n;
+        let v = ((&p.x + &p.z) % self.n) * self.sub(&q.x, &q.z) % self.n;
+        let sum = (&u + &v) % self.n;
+        let diff = self.sub(&u, &v);
+        Point {
+            x: &difference.z * (&sum * &sum % self.n) % self.n,
+            z: &difference.x * (&diff * &diff % self.n) % self.n
+        }
+    }
+
+    //kp with the Montgomery ladder
+    fn multiply(&self, p: &Point, k: u64) -> Point {
+        let (mut r0, mut r1) = (p.clone(), self.double(p));
+        for bit in (0..63 - k.leading_zeros()).rev() {
+            if k >> bit & 1 == 1 {
+                r0 = self.add(&r1, &r0, p);
+                r1 = self.double(&r1);
+            } else {
+                r1 = self.add(&r1, &r0, p);
+                r0 = self.double(&r0);
+            }
+        }
+        r0
+    }
+}
+
+//gcd(x, n) if it is a proper factor of n
+fn proper_factor(x: &BigUint, n: &BigUint) -> Option<BigUint> {
+    Some(x.gcd(n)).filter(|g| !g.is_one() && g != n)
+}
+
+//A random curve and point on it by Suyama's parametrization, or a factor of n if setting up
+//the curve needed an inverse that doesn't exist
+fn suyama_curve<'a>(n: &'a BigUint, sigma: &BigUint) -> Result<(Curve<'a>, Point), Option<BigUint>> {
+    let u = (sigma * sigma + n - 5u8) % n;
+    let v = sigma * 4u8 % n;
+    let u_cubed = u.modpow(&BigUint::from(3u8), n);
+    let v_minus_u = (&v + n - &u) % n;
+    let numerator = v_minus_u.modpow(&BigUint::from(3u8), n) * ((&u * 3u8 + &v) % n) % n;
+    let denominator = &u_cubed * &v * 16u8 % n;
+    let inverse = match denominator.clone().mod_inverse(n).and_then(|i| i.to_biguint()) {
+        Some(inverse) => inverse,
+        None => return Err(proper_factor(&denominator, n))
+    };
+    let point = Point { x: u_cubed, z: v.modpow(&BigUint::from(3u8), n) };
+    Ok((Curve { n, a24: numerator * inverse % n }, point))
+}
+
+//Stage 2 for primes q in (b1, b2]: q = mD + j or mD - j with 0 < j < D / 2 coprime to D,
+//and qQ is the point at infinity mod p exactly when x(mDQ) = x(jQ) mod p. So multiply up
+//X_m Z_j - X_j Z_m over every such m and j and take one gcd at the end.
+fn stage_2(curve: &Curve, q: &Point, b1: u64, b2: u64, deadline: Option<Instant>) -> Option<BigUint> {
+    let n = curve.n;
+    //jQ for odd j, each from the two before it
+    let double = curve.double(q);
+    let mut baby = Vec::new();
+    let (mut previous, mut current) = (q.clone(), q.clone());
+    for j in (1..D / 2).step_by(2) {
+        if j > 1 {
+            let next = curve.add(&current, &double, &previous);
+            (previous, current) = (current, next);
+        }
+        if j.gcd(&D) == 1 {
+            baby.push(current.clone());
+        }
+    }
+
+    let giant = curve.multiply(q, D);
+    let first = (b1 / D).max(1);
+    let mut current = curve.multiply(q, first * D);
+    //Not used until it is (m - 1)DQ, which 0DQ can't be in X:Z
+    let mut previous = match first {
+        1 => giant.clone(),
+        _ => curve.multiply(q, (first - 1) * D)
+    };
+    let mut product = BigUint::one();
+    for m in first..=b2 / D + 1 {
+        if m % 100 == 0 && timed_out(deadline) {
+            return None;
+        }
+        for j in &baby {
+            let cross = curve.sub(&(&current.x * &j.z % n), &(&j.x * &current.z % n));
+            product = product * cross % n;
+        }
+        //(m + 1)DQ = mDQ + DQ, their difference (m - 1)DQ. From mD = D it is 2DQ.
+        let next = match m {
+            1 => curve.double(&giant),
+            _ => curve.add(&current, &giant, &previous)
+        };
+        (previous, current) = (current, next);
+    }
+    proper_factor(&product, n)
+}
+
+//One curve of ECM with stage 1 bound b1 over primes, the odd primes up to b1
+fn ecm_curve(n: &BigUint, sigma: &BigUint, b1: u64, primes: &[u64], deadline: Option<Instant>) -> Option<BigUint> {
+    let (curve, mut q) = match suyama_curve(n, sigma) {
+        Ok(setup) => setup,
+        Err(factor) => return factor
+    };
+    //Stage 1: multiply by every prime power up to b1
+    for (i, &p) in std::iter::once(&2).chain(primes).enumerate() {
+        if i % 1_000 == 0 && timed_out(deadline) {
+            return None;
+        }
+        let mut power = p;
+        while power * p <= b1 {
+            power *= p;
+        }
+        q = curve.multiply(&q, power);
+    }
+    if q.z.is_zero() {
+        return None;
+    }
+    match proper_factor(&q.z, n) {
+        Some(factor) => Some(factor),
+        None => stage_2(&curve, &q, b1, b1 * STAGE_2_FACTOR, deadline)
+    }
+}
+
+//A proper factor of the odd composite n by ECM, None if the time ran out first
+pub fn ecm(n: &BigUint, deadline: Option<Instant>) -> Option<BigUint> {
+    let mut rng = rand::thread_rng();
+    let steps = ECM_SCHEDULE.iter().chain(std::iter::repeat(ECM_SCHEDULE.last().unwrap()));
+    for &(b1, curves) in steps {
+        let primes = sieve::primes_up_to(b1);
+        for _ in 0..curves {
+            if timed_out(deadline) {
+                return None;
+            }
+            let sigma = rng.gen_biguint_range(&BigUint::from(6u8), n);
+            if let Some(factor) = ecm_curve(n, &sigma, b1, &primes, deadline) {
+                return Some(factor);
+            }
+        }
+    }
+    None
+}
+
+//A proper factor of the composite n, None if the time ran out first
+fn split(n: &BigUint, deadline: Option<Instant>) -> Option<BigUint> {
+    if timed_out(deadline) {
+        return None;
+    }
+    //Rho can fail by its sequence cycling mod n, another starting point usually doesn't
+    for _ in 0..3 {
+        if let Some(factor) = pollard_rho(n, RHO_ITERATIONS) {
+            return Some(factor);
+        }
+    }
+    ecm(n, deadline)
+}
+
+//The factorization of n, as far as it gets before the deadline
+pub fn factor(n: &BigUint, deadline: Option<Instant>) -> Factorization {
+    let mut result = Factorization::default();
+    let mut n = n.clone();
+    for p in std::iter::once(2).chain(sieve::small_primes().iter().copied()) {
+        if BigUint::from(p) * p > n {
+            break;
+        }
+        let mut exponent = 0;
+        while (&n % p).is_zero() {
+            n /= p;
+            exponent += 1;
+        }
+        if exponent > 0 {
+            result.add_prime(BigUint::from(p), exponent);
+        }
+    }
+
+    let mut pending = vec![(n, 1)];
+    while let Some((m, exponent)) = pending.pop() {
+        if m.is_one() {
+            continue;
+        }
+        if millers::is_prime_miller(&m) {
+            result.add_prime(m, exponent);
+        } else if let Some((root, k)) = perfect_power(&m) {
+            pending.push((root, exponent * k));
+        } else {
+            match split(&m, deadline) {
+                Some(factor) => {
+                    pending.push((&m / &factor, exponent));
+                    pending.push((factor, exponent));
+                }
+                None => result.composites.push((m, exponent))
+            }
+        }
+    }
+    result
+}
+
+//The factor subcommand. Exits with 1 if the time ran out before n was fully factored.
+pub fn factor_command(n: BigUint, time_limit: u64) {
+    if n.is_zero() {
+        panic!("0 has no factorization");
+    }
+    let start = Instant::now();
+    let factorization = factor(&n, Some(start + Duration::from_secs(time_limit)));
+    println!("{n} = {factorization}");
+    println!("Took {:.3}s", start.elapsed().as_secs_f64());
+    if !factorization.is_complete() {
+        println!("Ran out of time after {time_limit}s before finding every prime factor.");
+        exit(1);
+    }
+}
+
+#[test]
+fn factors_with_every_method() {
+    let prime = |bits: usize| sieve::random_prime(bits, &crate::primality::MillerRabin, &mut rand::thread_rng());
+
+    //Trial division, rho for the 24 bit prime, then the square of the 32 bit one is a perfect power
+    let (small, square) = (prime(24), prime(32));
+    let n = BigUint::from(32u8) * 9u8 * 65_537u32 * &small * &square * &square;
+    let factorization = factor(&n, None);
+    assert!(factorization.is_complete());
+    let expected = [(BigUint::from(2u8), 5), (BigUint::from(3u8), 2), (BigUint::from(65_537u32), 1), (small, 1), (square, 2)];
+    assert_eq!(factorization.primes, BTreeMap::from_iter(expected));
+
+    let (medium, large) = (prime(40), prime(100));
+    let found = ecm(&(&medium * &large), None).unwrap();
+    assert!(found == medium || found == large);
+
+    assert_eq!(factor(&BigUint::one(), None).to_string(), "1");
+    assert_eq!(factor(&BigUint::from(360u32), None).to_string(), "2^3 * 3^2 * 5");
+    let timed_out = factor(&(prime(100) * prime(100)), Some(Instant::now()));
+    assert_eq!(timed_out.composites.len(), 1);
+}
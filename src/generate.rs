@@ -2,56 +2,85 @@
 use std::io::{Read, Write};
 
 use clio::{ClioPath, Input, Output};
-use num_bigint_dig::{BigInt, BigUint, ModInverse, ToBigInt, ToBigUint};
-use num_traits::Pow;
+use num_bigint_dig::{BigUint, RandBigInt};
+use num_integer::Integer;
+use num_traits::One;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use rand_core::CryptoRngCore;
 
-use crate::{base, millers};
+use crate::{brainkey, key::PrivateKey, mainutil, metadata::KeyMetadata, primality::PrimalityTest, provable::{self, PrimeCertificate}, rabin, secret::Secret, sieve, KeyGenArgs};
+
+pub const E: u32 = 65537;
 
 pub fn generate_keys(
     key_dir: Option<ClioPath>, 
     file: Option<Input>, 
-    input_string_1: Option<String>, 
-    input_string_2: Option<String>) {
-    let mut string_1 = String::new();
-    let mut string_2 = String::new();
+    passphrase: Option<String>, 
+    salt: Option<String>,
+    options: KeyGenArgs) {
+    let provable_primes = options.provable_primes;
+    let bits = options.bits;
+
+    let prime_count = options.primes as usize;
+
+    let created = mainutil::now();
+    let metadata = KeyMetadata {
+        created: Some(created),
+        expires: options.expires_in_days.map(|days| created + days * 86400),
+        usages: if options.usage.is_empty() { KeyMetadata::default().usages } else { options.usage.clone() },
+        comment: options.comment.clone().unwrap_or_default()
+    };
+    if metadata.comment.contains('\n') {
+        panic!("The comment has to fit on one line");
+    }
 
-    let strings_parsed;
+    //A passphrase determines the whole key, so it's as secret as the private key
+    let mut brain_key: Option<(Secret<String>, String)> = None;
 
     match file {
-        None => strings_parsed = false,
+        None => (),
         Some(mut f) => {
-            let mut buf = String::new();
-            let res = f.read_to_string(&mut buf);
+            let mut buf = Secret::new(String::new());
+            let res = f.read_to_string(buf.expose_mut());
             let mut _bytes_read = 0;
             match res {
                 Ok(b) => _bytes_read = b,
                 Err(e) => panic!("Unable to read file: {e}")
             }
-            let keys = Vec::from_iter(buf.split('\n').into_iter());
-            assert_eq!(keys.len(), 2);
-            string_1 = keys.first().unwrap().to_string();
-            string_2 = keys.last().unwrap().to_string();
-            strings_parsed = true;
+            let lines = Vec::from_iter(buf.expose().split('\n'));
+            assert_eq!(lines.len(), 2, "The file has the passphrase on the first line and the salt on the second");
+            brain_key = Some((Secret::new(lines[0].to_string()), lines[1].to_string()));
         }
     }
 
-    if strings_parsed == false {
-        match input_string_1 {
-            None => {panic!("Input string one does not exist, and a file was not passed!")},
-            Some(s) => {
-                string_1 = s;
-            }
+    if brain_key.is_none() {
+        if let (Some(passphrase), Some(salt)) = (passphrase, salt) {
+            brain_key = Some((Secret::new(passphrase), salt));
+        }
+    }
+
+    //Brain keys come out of a DRBG seeded from the stretched passphrase, test keys from one
+    //seeded with --seed, and other keys from one seeded by the OS
+    let mut rng = match (&brain_key, options.seed) {
+        (_, Some(seed)) => {
+            eprintln!("Warning: --seed makes the keys predictable, use them for tests and demos only.");
+            ChaCha20Rng::seed_from_u64(seed)
         }
-        match input_string_2 {
-            None => {panic!("Input string two does not exist, and a file was not passed!")},
-            Some(s) => {
-                string_2 = s;
+        (Some((passphrase, salt)), None) => {
+            let entropy = brainkey::estimate_entropy(passphrase.expose());
+            if entropy < brainkey::MIN_ENTROPY_BITS {
+                eprintln!("Warning: the passphrase has at most about {entropy:.0} bits of entropy, brain keys need {:.0} or more. \
+                    Anyone who guesses it can rebuild your private key.", brainkey::MIN_ENTROPY_BITS);
             }
+            brainkey::brain_key_rng(passphrase, salt)
         }
-    }
+        (None, None) => ChaCha20Rng::from_entropy()
+    };
 
     let pubkey_file;
     let privkey_file;
+    let mut certificate_file = None;
 
     match key_dir {
         Some(mut d) => {
@@ -61,6 +90,11 @@ pub fn generate_keys(
             privkey_file = d.join("private.txt")
             .create()
             .unwrap_or(Output::std_err());
+            if provable_primes {
+                certificate_file = Some(d.join("certificate.txt")
+                .create()
+                .unwrap_or(Output::std_err()));
+            }
         }
         None => {
             let res = Output::new("./public.txt");
@@ -78,52 +112,76 @@ pub fn generate_keys(
                 },
                 Err(e) => {panic!("Unable to create local public.txt: {e}")} 
             }
+
+            if provable_primes {
+                match Output::new("./certificate.txt") {
+                    Ok(c) => certificate_file = Some(c),
+                    Err(e) => {panic!("Unable to create local certificate.txt: {e}")}
+                }
+            }
         }
     }
-    
-    const ALPHABET: &str = "abcdefghijklmnopqrstuvwxyz";
 
-    let mut string_1_base_10 = base::to_base10(&string_1, ALPHABET);
-    let mut string_2_base_10 = base::to_base10(&string_2, ALPHABET);
-
-    if string_1_base_10.clone() % BigUint::from(2u8) == BigUint::from(0u8) {
-        string_1_base_10 += BigUint::from(1u8);
-    }
-    if string_2_base_10.clone() % BigUint::from(2u8) == BigUint::from(0u8) {
-        string_2_base_10 += BigUint::from(1u8);
+    if options.scheme == rabin::Scheme::Rabin {
+        if provable_primes || prime_count != 2 {
+            panic!("Rabin keys are always two random primes, drop --provable-primes and --primes");
+        }
+        rabin::write_keys(pubkey_file, privkey_file, bits, metadata, &mut rng);
+        return;
     }
 
-    while millers::is_prime_miller(&string_1_base_10) == false {
-        string_1_base_10 += BigUint::from(2u8);
-    }
-    while millers::is_prime_miller(&string_2_base_10) == false {
-        string_2_base_10 += BigUint::from(2u8);
-    }
+    if let Some(certificate_file) = certificate_file {
+        let seeds = Vec::from_iter((0..prime_count).map(|_| {
+            let mut seed = Secret::new(vec![0u8; 32]);
+            rng.fill(seed.expose_mut().as_mut_slice());
+            seed
+        }));
 
-    let p: BigUint = string_1_base_10.clone();
-    let q: BigUint = string_2_base_10.clone();
+        let certificates = Vec::from_iter(seeds.iter().enumerate().map(|(i, seed)| {
+            Secret::new(provable_rsa_prime(prime_bits(bits, prime_count, i), seed.expose()))
+        }));
+        let certificates = Vec::from_iter(certificates.iter().map(|c| c.expose()));
+        let primes = Secret::new(Vec::from_iter(certificates.iter().map(|c| c.prime().clone())));
 
+        if !all_distinct(primes.expose()) {
+            panic!("Two of the primes came out the same, which should never happen");
+        }
 
-    let ten_to_200 = 10u8.to_biguint().unwrap().pow(200u8);
+        let res = write_certificates(certificate_file, &certificates);
+        match res {
+            Ok(_) => (),
+            Err(e) => {panic!("Could not write certificate: {e}")}
+        }
 
-    if q < ten_to_200 || p < ten_to_200 {
-        panic!("Input strings are too short");
+        write_key_pair(pubkey_file, privkey_file, primes.expose(), metadata);
+        return;
     }
 
-    let n: BigUint = p.clone() * q.clone();
-    let r: BigUint = (p.clone() - BigUint::from(1u8)) * (q.clone() - BigUint::from(1u8));
+    let threads = options.threads.unwrap_or_else(|| {
+        std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+    });
+    let test = options.primality_test.test();
 
-    const E: u32 = 65537;
+    let primes = random_primes(bits, prime_count, &*test, threads, &mut rng);
+    write_key_pair(pubkey_file, privkey_file, primes.expose(), metadata);
+}
 
-    let d = BigUint::from(E).mod_inverse(r).unwrap();
+fn write_key_pair(pubkey_file: Output, privkey_file: Output, primes: &[BigUint], metadata: KeyMetadata) {
+    let mut key = match PrivateKey::from_primes(primes, BigUint::from(E)) {
+        Some(key) => key,
+        None => panic!("e = {E} has no inverse for these primes")
+    };
+    key.metadata = metadata;
 
-    let res = write_to_output(pubkey_file, n.to_bigint().unwrap(), E.to_bigint().unwrap());
+    let res = write_to_output(pubkey_file, &key.public().to_text());
     match res {
         Ok(_) => (),
         Err(e) => {panic!("Could not write output: {e}")}
     }
 
-    let res = write_to_output(privkey_file, n.to_bigint().unwrap(), d);
+    //The private key keeps e on a third line, blinding needs it, then the CRT values and
+    //the metadata
+    let res = write_to_output(privkey_file, key.to_text().expose());
     match res {
         Ok(_) => (),
         Err(e) => {panic!("Could not write output: {e}")}
@@ -131,8 +189,91 @@ pub fn generate_keys(
 
 }
 
-fn write_to_output(mut file: Output, a: BigInt, b: BigInt) -> std::io::Result<()> {
-    file.write(format!("{a}\n").as_bytes())?;
-    file.write(format!("{b}").as_bytes())?;
+//Smallest prime generate-keys will make. random_primes and Rabin's blum_prime set the top
+//two bits of each start, and anything much smaller than this would be trivial to factor anyway.
+const MIN_PRIME_BITS: usize = 64;
+
+//Smallest RSA modulus that can receive a message. The envelope wraps a 32 byte content key with
+//OAEP-SHA256, which adds 2 * 32 + 2 bytes, so n needs at least 98 bytes.
+pub const MIN_RSA_BITS: usize = 784;
+
+//Whether --bits makes a usable key and leaves every prime at least MIN_PRIME_BITS. Below 2
+//bits Shawe-Taylor would retry new seeds forever and the random starts would underflow.
+pub fn check_key_size(bits: usize, primes: usize, scheme: rabin::Scheme) -> Result<(), String> {
+    if bits / primes < MIN_PRIME_BITS {
+        return Err(format!("--bits {bits} is too small for {primes} primes, each one needs at least {MIN_PRIME_BITS} bits"));
+    }
+    if scheme == rabin::Scheme::Rsa && bits < MIN_RSA_BITS {
+        return Err(format!("--bits {bits} is too small for an RSA key, encrypted messages need at least {MIN_RSA_BITS} bits"));
+    }
     Ok(())
 }
+
+//Size of the i-th of count primes making up a bits sized modulus
+fn prime_bits(bits: usize, count: usize, i: usize) -> usize {
+    bits * (i + 1) / count - bits * i / count
+}
+
+fn all_distinct(primes: &[BigUint]) -> bool {
+    primes.iter().enumerate().all(|(i, p)| !primes[..i].contains(p))
+}
+
+//count random primes r with gcd(e, r - 1) = 1 whose product has exactly bits bits.
+//Every start has its top two bits set, so the product rarely comes out short. The primes
+//depend only on what rng produces, not on the thread count.
+pub fn random_primes<R: CryptoRngCore>(bits: usize, count: usize, test: &(dyn PrimalityTest + Sync), threads: usize, rng: &mut R) -> Secret<Vec<BigUint>> {
+    let e = BigUint::from(E);
+    loop {
+        let starts = Secret::new(Vec::from_iter((0..count).map(|i| {
+            let size = prime_bits(bits, count, i);
+            rng.gen_biguint(size) | (BigUint::from(3u8) << (size - 2))
+        })));
+        let primes = Secret::new(sieve::parallel_next_primes(starts.expose(), test, threads, rng));
+        let n = primes.expose().iter().fold(BigUint::one(), |acc, p| acc * p);
+        let usable = primes.expose().iter().all(|p| (p - BigUint::one()).gcd(&e).is_one());
+        if usable && n.bits() == bits && all_distinct(primes.expose()) {
+            return primes;
+        }
+    }
+}
+
+//Runs Shawe-Taylor from the seed until it yields a prime p with gcd(e, p - 1) = 1
+fn provable_rsa_prime(bits: usize, seed: &[u8]) -> PrimeCertificate {
+    let e = BigUint::from(E);
+    let mut prime_seed = provable::seed_from_bytes(seed);
+    loop {
+        match provable::shawe_taylor_prime(bits, &prime_seed) {
+            Some((certificate, next_seed)) => {
+                if (certificate.prime() - BigUint::one()).gcd(&e).is_one() {
+                    return certificate;
+                }
+                prime_seed = next_seed;
+            }
+            None => prime_seed += BigUint::one()
+        }
+    }
+}
+
+fn write_certificates(mut file: Output, certificates: &[&PrimeCertificate]) -> std::io::Result<()> {
+    let text = Secret::new(Vec::from_iter(certificates.iter().map(|c| c.to_string())).join("\n\n"));
+    file.write_all(text.expose().as_bytes())?;
+    Ok(())
+}
+
+fn write_to_output(mut file: Output, text: &str) -> std::io::Result<()> {
+    file.write_all(text.as_bytes())?;
+    Ok(())
+}
+
+#[test]
+fn seeded_generation_is_reproducible() {
+    use crate::primality::MillerRabin;
+
+    let first = random_primes(512, 3, &MillerRabin, 1, &mut ChaCha20Rng::seed_from_u64(7));
+    for threads in [1, 4] {
+        let again = random_primes(512, 3, &MillerRabin, threads, &mut ChaCha20Rng::seed_from_u64(7));
+        assert_eq!(again.expose(), first.expose());
+    }
+    let other = random_primes(512, 3, &MillerRabin, 1, &mut ChaCha20Rng::seed_from_u64(8));
+    assert_ne!(other.expose(), first.expose());
+}
@@ -0,0 +1,481 @@
+use num_bigint_dig::{BigUint, ModInverse, RandBigInt};
+use num_traits::{One, Zero};
+use rand_core::CryptoRngCore;
+use sha2::{Digest, Sha256};
+use zeroize::Zeroize;
+
+use crate::{generate::E, mainutil::{parse_key, parse_key_lines, to_hex}, metadata::{split_key_text, KeyMetadata}, montgomery::MontgomeryContext, oaep, secret::Secret};
+
+//Bits of randomness in the multiple of (ed - 1) added to d for exponent blinding
+const EXPONENT_BLINDING_BITS: usize = 64;
+
+//n and e, with the Montgomery setup for n computed once when the key is loaded
+#[derive(Debug, Clone)]
+pub struct PublicKey {
+    pub n: BigUint,
+    pub e: BigUint,
+    pub metadata: KeyMetadata,
+    montgomery: Option<MontgomeryContext>
+}
+
+//n and d, with the Montgomery setup for n computed once when the key is loaded.
+//e is kept too, blinding needs it. d is wiped when the key is dropped.
+//Keys that know the primes of n work modulo each prime and recombine (RFC 8017 CRT).
+#[derive(Debug, Clone)]
+pub struct PrivateKey {
+    pub n: BigUint,
+    pub d: Secret<BigUint>,
+    pub e: BigUint,
+    pub metadata: KeyMetadata,
+    montgomery: Option<MontgomeryContext>,
+    crt: Secret<Vec<CrtPrime>>
+}
+
+//One prime factor r_i of n with d mod (r_i - 1) and its CRT coefficient. Following RFC 8017
+//the first prime (p) has no coefficient, the second (q) has q^-1 mod p, and every later
+//prime has (r_1 * ... * r_(i-1))^-1 mod r_i.
+#[derive(Clone)]
+struct CrtPrime {
+    prime: BigUint,
+    exponent: BigUint,
+    coefficient: BigUint,
+    montgomery: Option<MontgomeryContext>
+}
+
+impl CrtPrime {
+    fn new(prime: BigUint, exponent: BigUint, coefficient: BigUint) -> CrtPrime {
+        CrtPrime { montgomery: MontgomeryContext::new(&prime), prime, exponent, coefficient }
+    }
+}
+
+impl Zeroize for CrtPrime {
+    fn zeroize(&mut self) {
+        self.prime.zeroize();
+        self.exponent.zeroize();
+        self.coefficient.zeroize();
+        self.montgomery.zeroize();
+    }
+}
+
+//base^exponent mod n, through the cached context when n allows one
+fn modpow(base: &BigUint, exponent: &BigUint, n: &BigUint, montgomery: &Option<MontgomeryContext>) -> BigUint {
+    match montgomery {
+        Some(ctx) => ctx.modpow(base, exponent),
+        None => base.modpow(exponent, n)
+    }
+}
+
+impl PublicKey {
+    pub fn new(n: BigUint, e: BigUint) -> PublicKey {
+        PublicKey { montgomery: MontgomeryContext::new(&n), n, e, metadata: KeyMetadata::default() }
+    }
+
+    //Parses the two line "n, e" key file format, with its metadata section if there is one
+    pub fn from_text(text: &str) -> PublicKey {
+        let (numbers, metadata) = split_key_text(text);
+        let (n, e) = parse_key(numbers);
+        let mut key = PublicKey::new(n, e);
+        key.metadata = parse_metadata(metadata);
+        key
+    }
+
+    //from_text for files that may be broken, giving an error instead of panicking
+    pub fn try_from_text(text: &str) -> Result<PublicKey, String> {
+        let (numbers, metadata) = split_key_text(text);
+        let values = Vec::from_iter(numbers.split('\n').map(|l| l.trim().parse::<BigUint>()));
+        let mut key = match values.as_slice() {
+            [Ok(n), Ok(e)] => PublicKey::new(n.clone(), e.clone()),
+            _ => return Err("A public key is n and e on two lines".to_string())
+        };
+        key.metadata = match metadata.map(KeyMetadata::parse) {
+            None => KeyMetadata::default(),
+            Some(Ok(metadata)) => metadata,
+            Some(Err(e)) => return Err(e)
+        };
+        Ok(key)
+    }
+
+    //The key file text from_text reads back
+    pub fn to_text(&self) -> String {
+        with_metadata(format!("{}\n{}", self.n, self.e), &self.metadata)
+    }
+
+    pub fn encrypt_block(&self, m: &BigUint) -> BigUint {
+        modpow(m, &self.e, &self.n, &self.montgomery)
+    }
+
+    //c1 * c2 mod n, which decrypts to m1 * m2 mod n. Only textbook RSA behaves this way,
+    //padding is there to stop exactly this.
+    pub fn multiply_ciphertexts(&self, c1: &BigUint, c2: &BigUint) -> Result<BigUint, String> {
+        if *c1 >= self.n || *c2 >= self.n {
+            return Err("A ciphertext block is bigger than n, it wasn't encrypted to this key".to_string());
+        }
+        Ok(c1 * c2 % &self.n)
+    }
+
+    //SHA-256 of the public key file text, in hex
+    pub fn fingerprint(&self) -> String {
+        to_hex(&Sha256::digest(format!("{}\n{}", self.n, self.e)))
+    }
+
+    //Encrypts a short message (a content key) with OAEP padding. None if it doesn't fit.
+    pub fn encrypt_oaep<R: CryptoRngCore + ?Sized>(&self, message: &[u8], rng: &mut R) -> Option<Vec<u8>> {
+        let k = byte_len(&self.n);
+        let encoded = oaep::encode(message, k, rng)?;
+        Some(to_bytes_padded(&self.encrypt_block(&BigUint::from_bytes_be(encoded.expose())), k))
+    }
+}
+
+pub(crate) fn parse_metadata(text: Option<&str>) -> KeyMetadata {
+    match text.map(KeyMetadata::parse) {
+        None => KeyMetadata::default(),
+        Some(Ok(metadata)) => metadata,
+        Some(Err(e)) => panic!("Could not parse the key metadata! Error: {e}")
+    }
+}
+
+pub(crate) fn with_metadata(numbers: String, metadata: &KeyMetadata) -> String {
+    let section = metadata.to_text();
+    if section.is_empty() {
+        numbers
+    } else {
+        format!("{numbers}\n\n{section}")
+    }
+}
+
+pub fn byte_len(n: &BigUint) -> usize {
+    n.bits().div_ceil(8)
+}
+
+//Big endian bytes of x, zero padded on the left to len
+pub fn to_bytes_padded(x: &BigUint, len: usize) -> Vec<u8> {
+    let bytes = x.to_bytes_be();
+    let mut padded = vec![0u8; len.saturating_sub(bytes.len())];
+    padded.extend(bytes);
+    padded
+}
+
+//x^exponent mod modulus with the constant time ladder when the modulus allows a context
+fn ladder(x: &BigUint, exponent: &BigUint, exponent_bits: usize, modulus: &BigUint, montgomery: &Option<MontgomeryContext>) -> Secret<BigUint> {
+    Secret::new(match montgomery {
+        Some(ctx) => ctx.modpow_ladder(x, exponent, exponent_bits),
+        None => x.modpow(exponent, modulus)
+    })
+}
+
+impl PrivateKey {
+    pub fn new(n: BigUint, d: BigUint, e: BigUint) -> PrivateKey {
+        PrivateKey {
+            montgomery: MontgomeryContext::new(&n),
+            n,
+            d: Secret::new(d),
+            e,
+            metadata: KeyMetadata::default(),
+            crt: Secret::new(Vec::new())
+        }
+    }
+
+    //The key for n = the product of the primes, with every CRT value worked out.
+    //None when e has no inverse modulo the totient.
+    pub fn from_primes(primes: &[BigUint], e: BigUint) -> Option<PrivateKey> {
+        let n = primes.iter().fold(BigUint::one(), |acc, p| acc * p);
+        let phi = Secret::new(primes.iter().fold(BigUint::one(), |acc, p| acc * (p - BigUint::one())));
+        let d = Secret::new((&e).mod_inverse(phi.expose())?.to_biguint()?);
+
+        let mut crt = Secret::new(Vec::new());
+        let mut product = Secret::new(BigUint::one());
+        for (i, prime) in primes.iter().enumerate() {
+            let exponent = d.expose() % (prime - BigUint::one());
+            let coefficient = match i {
+                0 => BigUint::zero(),
+                1 => prime.mod_inverse(&primes[0])?.to_biguint()?,
+                _ => product.expose().mod_inverse(prime)?.to_biguint()?
+            };
+            *product.expose_mut() *= prime;
+            crt.expose_mut().push(CrtPrime::new(prime.clone(), exponent, coefficient));
+        }
+
+        let mut key = PrivateKey::new(n, d.expose().clone(), e);
+        key.crt = crt;
+        Some(key)
+    }
+
+    //Parses the "n, d, e" key file format. Older files stop after d, and every key
+    //generate-keys has written uses e = 65537. Keys with CRT values go on with
+    //p, q, d mod (p - 1), d mod (q - 1), q^-1 mod p, then a prime, exponent and
+    //coefficient line for each extra prime, in RFC 8017 order. The metadata section
+    //comes last.
+    pub fn from_text(text: &str) -> PrivateKey {
+        let (numbers, metadata) = split_key_text(text);
+        let values = parse_key_lines(numbers);
+        let mut key = match values.expose().as_slice() {
+            [n, d] => PrivateKey::new(n.clone(), d.clone(), BigUint::from(E)),
+            [n, d, e] => PrivateKey::new(n.clone(), d.clone(), e.clone()),
+            [n, d, e, p, q, dp, dq, q_inverse, rest @ ..] if rest.len() % 3 == 0 => {
+                let mut crt = Secret::new(vec![
+                    CrtPrime::new(p.clone(), dp.clone(), BigUint::zero()),
+                    CrtPrime::new(q.clone(), dq.clone(), q_inverse.clone())
+                ]);
+                for extra in rest.chunks(3) {
+                    crt.expose_mut().push(CrtPrime::new(extra[0].clone(), extra[1].clone(), extra[2].clone()));
+                }
+                if crt.expose().iter().fold(BigUint::one(), |acc, r| acc * &r.prime) != *n {
+                    panic!("The primes in the private key file don't multiply to n");
+                }
+
+                let mut key = PrivateKey::new(n.clone(), d.clone(), e.clone());
+                key.crt = crt;
+                key
+            }
+            _ => panic!("A private key file has n, d, optionally e, and optionally the CRT values of two or more primes")
+        };
+        key.metadata = parse_metadata(metadata);
+        key
+    }
+
+    //The key file text from_text reads back
+    pub fn to_text(&self) -> Secret<String> {
+        let mut lines = Secret::new(vec![self.n.clone(), self.d.expose().clone(), self.e.clone()]);
+        let crt = self.crt.expose();
+        if let [p, q, extra @ ..] = crt.as_slice() {
+            lines.expose_mut().extend([p.prime.clone(), q.prime.clone(), p.exponent.clone(), q.exponent.clone(), q.coefficient.clone()]);
+            for r in extra {
+                lines.expose_mut().extend([r.prime.clone(), r.exponent.clone(), r.coefficient.clone()]);
+            }
+        }
+        let numbers = Vec::from_iter(lines.expose().iter().map(|l| l.to_string())).join("\n");
+        Secret::new(with_metadata(numbers, &self.metadata))
+    }
+
+    //Why this key isn't the private half of public, if it isn't: n and e must match, the
+    //CRT values must agree with d, and d must undo e on a random number
+    pub fn validate<R: CryptoRngCore + ?Sized>(&self, public: &PublicKey, rng: &mut R) -> Result<(), String> {
+        if self.n != public.n || self.e != public.e {
+            return Err("n and e don't match the public key".to_string());
+        }
+        let d = self.d.expose();
+        let primes = self.crt.expose();
+        if !primes.is_empty() && primes.iter().fold(BigUint::one(), |acc, r| acc * &r.prime) != self.n {
+            return Err("the primes don't multiply to n".to_string());
+        }
+        let mut product = BigUint::one();
+        for (i, r) in primes.iter().enumerate() {
+            if r.exponent != d % (&r.prime - BigUint::one()) {
+                return Err(format!("the CRT exponent of prime {} isn't d mod (prime - 1)", i + 1));
+            }
+            let inverts = match i {
+                0 => true,
+                1 => ((&r.coefficient * &r.prime) % &primes[0].prime).is_one(),
+                _ => ((&r.coefficient * &product) % &r.prime).is_one()
+            };
+            if !inverts {
+                return Err(format!("the CRT coefficient of prime {} is wrong", i + 1));
+            }
+            product *= &r.prime;
+        }
+        let m = rng.gen_biguint_range(&BigUint::from(2u8), &self.n);
+        let c = modpow(&m, &self.e, &self.n, &self.montgomery);
+        if *ladder(&c, d, self.n.bits(), &self.n, &self.montgomery).expose() != m {
+            return Err("d doesn't invert e".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn public(&self) -> PublicKey {
+        let mut public = PublicKey::new(self.n.clone(), self.e.clone());
+        public.metadata = self.metadata.clone();
+        public
+    }
+
+    //Undoes PublicKey::encrypt_oaep. None if c wasn't made for this key.
+    pub fn decrypt_oaep<R: CryptoRngCore + ?Sized>(&self, c: &[u8], rng: &mut R) -> Option<Secret<Vec<u8>>> {
+        let k = byte_len(&self.n);
+        let c = BigUint::from_bytes_be(c);
+        if c >= self.n {
+            return None;
+        }
+        let m = self.private_op(&c, rng);
+        let encoded = Secret::new(to_bytes_padded(m.expose(), k));
+        oaep::decode(encoded.expose(), k)
+    }
+
+    pub fn decrypt_block<R: CryptoRngCore + ?Sized>(&self, c: &BigUint, rng: &mut R) -> Secret<BigUint> {
+        self.private_op(c, rng)
+    }
+
+    //x^d mod n, hardened against timing attacks. The input is blinded with r^e for a fresh
+    //random r, the exponent is blinded by adding a random multiple of e * d - 1 (a multiple
+    //of the group order, so the result is unchanged), and the exponentiation itself is a
+    //constant time ladder. Every private key operation should come through here, with
+    //the blinding factors drawn from rng.
+    pub fn private_op<R: CryptoRngCore + ?Sized>(&self, x: &BigUint, rng: &mut R) -> Secret<BigUint> {
+        let n = &self.n;
+        let d = self.d.expose();
+
+        let (r, r_inverse) = loop {
+            let r = Secret::new(rng.gen_biguint_range(&BigUint::from(2u8), n));
+            if let Some(inverse) = r.expose().mod_inverse(n).and_then(|i| i.to_biguint()) {
+                break (r, Secret::new(inverse));
+            }
+        };
+        let blinded = Secret::new(x * modpow(r.expose(), &self.e, n, &self.montgomery) % n);
+
+        let blinded_result = if self.crt.expose().is_empty() {
+            let k = rng.gen_biguint(EXPONENT_BLINDING_BITS);
+            let blinded_d = Secret::new(d + k * (&self.e * d - BigUint::one()));
+            let ladder_bits = n.bits() + self.e.bits() + EXPONENT_BLINDING_BITS;
+            ladder(blinded.expose(), blinded_d.expose(), ladder_bits, n, &self.montgomery)
+        } else {
+            self.crt_pow(blinded.expose(), rng)
+        };
+        let result = Secret::new(blinded_result.expose() * r_inverse.expose() % n);
+
+        //With the wrong e the unblinding silently gives garbage, and a fault in the
+        //exponentiation shouldn't make it out either
+        if modpow(result.expose(), &self.e, n, &self.montgomery) != x % n {
+            panic!("Private key operation failed its consistency check, is e = {} right for this key?", self.e);
+        }
+        result
+    }
+
+    //x^d mod n from x^(d mod (r_i - 1)) mod r_i for each prime, recombined with Garner's
+    //method as in RFC 8017. Each exponent is blinded with a random multiple of r_i - 1.
+    fn crt_pow<R: CryptoRngCore + ?Sized>(&self, x: &BigUint, rng: &mut R) -> Secret<BigUint> {
+        let primes = self.crt.expose();
+        let mut partials = Vec::new();
+        for r in primes {
+            let k = rng.gen_biguint(EXPONENT_BLINDING_BITS);
+            let exponent = Secret::new(&r.exponent + k * (&r.prime - BigUint::one()));
+            let ladder_bits = r.prime.bits() + EXPONENT_BLINDING_BITS;
+            let reduced = Secret::new(x % &r.prime);
+            partials.push(ladder(reduced.expose(), exponent.expose(), ladder_bits, &r.prime, &r.montgomery));
+        }
+
+        let (p, q) = (&primes[0], &primes[1]);
+        let h = Secret::new((partials[0].expose() + &p.prime - partials[1].expose() % &p.prime) * &q.coefficient % &p.prime);
+        let mut result = Secret::new(partials[1].expose() + &q.prime * h.expose());
+        let mut product = Secret::new(&p.prime * &q.prime);
+        for (r, m) in primes[2..].iter().zip(&partials[2..]) {
+            let h = Secret::new((m.expose() + &r.prime - result.expose() % &r.prime) * &r.coefficient % &r.prime);
+            *result.expose_mut() += product.expose() * h.expose();
+            *product.expose_mut() *= &r.prime;
+        }
+        result
+    }
+}
+
+//A random two prime key with e = 65537 and a modulus of about bits bits, for tests
+#[cfg(test)]
+pub fn test_key(bits: usize) -> PrivateKey {
+    use crate::{primality::MillerRabin, sieve::next_prime};
+
+    let mut rng = rand::thread_rng();
+    loop {
+        let primes = [0, 1].map(|_| next_prime(&rng.gen_biguint(bits / 2), &MillerRabin, &mut rand::thread_rng()));
+        if let Some(key) = PrivateKey::from_primes(&primes, BigUint::from(E)) {
+            return key;
+        }
+    }
+}
+
+#[test]
+fn key_round_trip_through_text() {
+    let public = PublicKey::from_text("3233\n17");
+    let private = PrivateKey::from_text("3233\n2753\n17");
+    for m in 0u32..3233 {
+        let m = BigUint::from(m);
+        assert_eq!(*private.decrypt_block(&public.encrypt_block(&m), &mut rand::thread_rng()).expose(), m);
+    }
+}
+
+#[test]
+fn blinded_private_op_matches_unblinded() {
+    use crate::sieve::next_prime;
+    use crate::primality::MillerRabin;
+
+    let mut rng = rand::thread_rng();
+    for bits in [128, 512] {
+        let p = next_prime(&rng.gen_biguint(bits), &MillerRabin, &mut rand::thread_rng());
+        let q = next_prime(&rng.gen_biguint(bits), &MillerRabin, &mut rand::thread_rng());
+        let phi = (&p - 1u8) * (&q - 1u8);
+        let e = BigUint::from(E);
+        let d = match (&e).mod_inverse(&phi).and_then(|d| d.to_biguint()) {
+            Some(d) => d,
+            None => continue
+        };
+        let key = PrivateKey::new(&p * &q, d.clone(), e);
+
+        for _ in 0..10 {
+            let c = rng.gen_biguint_below(&key.n);
+            let first = key.private_op(&c, &mut rng);
+            assert_eq!(*first.expose(), c.modpow(&d, &key.n));
+            //Fresh blinding factors every time, same answer
+            assert_eq!(key.private_op(&c, &mut rng).expose(), first.expose());
+        }
+    }
+}
+
+#[test]
+fn two_line_private_keys_default_to_65537() {
+    let key = PrivateKey::from_text(include_str!("../private.txt"));
+    assert_eq!(key.e, BigUint::from(E));
+    let public = PublicKey::from_text(include_str!("../public.txt"));
+    let m = BigUint::from(123456789u32);
+    assert_eq!(*key.private_op(&public.encrypt_block(&m), &mut rand::thread_rng()).expose(), m);
+}
+
+#[test]
+fn textbook_ciphertexts_multiply() {
+    let public = PublicKey::from_text("3233\n17");
+    let private = PrivateKey::new(BigUint::from(3233u32), BigUint::from(2753u32), BigUint::from(17u32));
+    let mut rng = rand::thread_rng();
+    let (m1, m2) = (BigUint::from(42u32), BigUint::from(1000u32));
+    let product = public.multiply_ciphertexts(&public.encrypt_block(&m1), &public.encrypt_block(&m2)).unwrap();
+    assert_eq!(*private.decrypt_block(&product, &mut rng).expose(), &m1 * &m2 % &public.n);
+    assert!(public.multiply_ciphertexts(&BigUint::from(3233u32), &BigUint::from(2u8)).is_err());
+}
+
+#[test]
+fn private_key_debug_hides_d() {
+    let key = PrivateKey::from_text("3233\n2753\n17");
+    let printed = format!("{key:?}");
+    assert!(printed.contains("3233"));
+    assert!(!printed.contains("2753"));
+}
+
+#[test]
+fn multi_prime_crt_matches_modpow() {
+    use crate::sieve::next_prime;
+    use crate::primality::MillerRabin;
+
+    let mut rng = rand::thread_rng();
+    for count in 2..=5 {
+        let primes = Vec::from_iter((0..count).map(|_| next_prime(&rng.gen_biguint(128), &MillerRabin, &mut rand::thread_rng())));
+        let key = match PrivateKey::from_primes(&primes, BigUint::from(E)) {
+            Some(key) => key,
+            None => continue
+        };
+        let reloaded = PrivateKey::from_text(key.to_text().expose());
+        assert_eq!(reloaded.to_text().expose(), key.to_text().expose());
+
+        for _ in 0..10 {
+            let c = rng.gen_biguint_below(&key.n);
+            let expected = c.modpow(key.d.expose(), &key.n);
+            assert_eq!(*key.private_op(&c, &mut rng).expose(), expected);
+            assert_eq!(*reloaded.private_op(&c, &mut rng).expose(), expected);
+        }
+    }
+}
+
+#[test]
+fn key_files_carry_metadata() {
+    use crate::metadata::Usage;
+
+    let mut private = PrivateKey::from_text("3233\n2753\n17");
+    private.metadata = KeyMetadata { created: Some(1700000000), expires: None, usages: vec![Usage::Sign], comment: "test".to_string() };
+    let reloaded = PrivateKey::from_text(private.to_text().expose());
+    assert_eq!(reloaded.metadata, private.metadata);
+    let public = PublicKey::from_text(&private.public().to_text());
+    assert_eq!(public.metadata, private.metadata);
+    assert_eq!(public.fingerprint(), PublicKey::from_text("3233\n17").fingerprint());
+}
@@ -0,0 +1,413 @@
+use std::{fs, io::Write, path::{Path, PathBuf}};
+
+use crate::{key::{PrivateKey, PublicKey}, mainutil::{format_time, now, read_key, write_output}, revocation::Revocation, secret::Secret, KeyCommand};
+
+//A directory of named keys, one subdirectory per key named after its fingerprint:
+//
+//  <fingerprint>/meta.txt     name, email and creation time as "field: value" lines
+//  <fingerprint>/public.txt   the usual key files
+//  <fingerprint>/private.txt  only for our own keys
+//  <fingerprint>/revocation.txt  once the key has been revoked
+pub struct Keyring {
+    dir: PathBuf
+}
+
+pub struct KeyEntry {
+    pub name: String,
+    pub email: String,
+    //Seconds since the Unix epoch
+    pub created: u64,
+    pub fingerprint: String,
+    pub public: PublicKey,
+    pub revocation: Option<Revocation>,
+    dir: PathBuf
+}
+
+//Fingerprint prefixes shorter than this are too likely to be ambiguous
+const MIN_PREFIX_LEN: usize = 8;
+
+impl Keyring {
+    //$XDG_DATA_HOME/rsa_rust, or ~/.local/share/rsa_rust when that isn't set
+    pub fn default_location() -> Result<PathBuf, String> {
+        let data_home = match std::env::var_os("XDG_DATA_HOME") {
+            Some(d) if !d.is_empty() => PathBuf::from(d),
+            _ => match std::env::var_os("HOME") {
+                Some(home) => Path::new(&home).join(".local").join("share"),
+                None => return Err("Neither XDG_DATA_HOME nor HOME is set, so there's nowhere to keep the keyring".to_string())
+            }
+        };
+        Ok(data_home.join("rsa_rust"))
+    }
+
+    //Opens the keyring at dir, creating it if needed. Only for adding keys, reading goes
+    //through existing so it never leaves an empty keyring behind.
+    pub fn open(dir: PathBuf) -> Keyring {
+        if let Err(e) = fs::create_dir_all(&dir) {
+            panic!("Could not create the keyring at {}: {e}", dir.display());
+        }
+        Keyring { dir }
+    }
+
+    //The keyring at the default location, if there is one
+    pub fn existing() -> Result<Keyring, String> {
+        let dir = Self::default_location()?;
+        if !dir.is_dir() {
+            return Err(format!("There is no keyring at {}, import a key first", dir.display()));
+        }
+        Ok(Keyring { dir })
+    }
+
+    //Every key, sorted by name. Broken entries are reported and skipped, so one half-written
+    //directory doesn't take the rest of the keyring down with it.
+    pub fn entries(&self) -> Vec<KeyEntry> {
+        let listing = match fs::read_dir(&self.dir) {
+            Ok(l) => l,
+            Err(e) => panic!("Could not read the keyring at {}: {e}", self.dir.display())
+        };
+        let mut entries = Vec::from_iter(listing
+            .filter_map(|d| d.ok())
+            .filter(|d| d.path().join("meta.txt").is_file())
+            .filter_map(|d| match KeyEntry::load(d.path()) {
+                Ok(entry) => Some(entry),
+                Err(e) => {
+                    eprintln!("Warning: skipping the broken keyring entry {}, {e}", d.path().display());
+                    None
+                }
+            }));
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        entries
+    }
+
+    //The key with this name, or else the one key whose fingerprint starts with it
+    pub fn find(&self, name: &str) -> Result<KeyEntry, String> {
+        let mut entries = self.entries();
+        if let Some(i) = entries.iter().position(|e| e.name == name) {
+            return Ok(entries.swap_remove(i));
+        }
+        let prefix = name.to_lowercase();
+        if prefix.len() >= MIN_PREFIX_LEN {
+            entries.retain(|e| e.fingerprint.starts_with(&prefix));
+            match entries.len() {
+                0 => (),
+                1 => return Ok(entries.remove(0)),
+                _ => return Err(format!("More than one key has a fingerprint starting with {name}"))
+            }
+        }
+        Err(format!("No key named {name} in the keyring"))
+    }
+
+    //Adds a key. The public half can be left out when the private key is given.
+    pub fn import(&self, name: &str, email: &str, public: Option<PublicKey>, private: Option<&PrivateKey>) -> Result<KeyEntry, String> {
+        check_field("name", name)?;
+        check_field("email", email)?;
+        let public = match (public, private) {
+            (Some(public), Some(private)) if public.fingerprint() != private.public().fingerprint() => {
+                return Err("The public and private keys don't belong together".to_string());
+            }
+            (Some(public), _) => public,
+            (None, Some(private)) => private.public(),
+            (None, None) => return Err("Give a public key, a private key or both".to_string())
+        };
+
+        let entries = self.entries();
+        if entries.iter().any(|e| e.name == name) {
+            return Err(format!("There is already a key named {name}"));
+        }
+        let fingerprint = public.fingerprint();
+        if let Some(existing) = entries.iter().find(|e| e.fingerprint == fingerprint) {
+            return Err(format!("This key is already in the keyring as {}", existing.name));
+        }
+
+        let created = public.metadata.created.unwrap_or_else(now);
+        let entry = KeyEntry {
+            name: name.to_string(),
+            email: email.to_string(),
+            created,
+            dir: self.dir.join(&fingerprint),
+            fingerprint,
+            public,
+            revocation: None
+        };
+
+        let res = fs::create_dir_all(&entry.dir)
+            .and_then(|_| fs::write(entry.dir.join("public.txt"), entry.public.to_text()))
+            .and_then(|_| match private {
+                Some(private) => write_private(&entry.dir.join("private.txt"), private.to_text().expose()),
+                None => Ok(())
+            })
+            .and_then(|_| entry.save());
+        match res {
+            Ok(_) => Ok(entry),
+            Err(e) => Err(format!("Could not write to the keyring: {e}"))
+        }
+    }
+
+    //Marks the revoked key's entry, once the revocation checks out against it
+    pub fn import_revocation(&self, revocation: &Revocation) -> Result<KeyEntry, String> {
+        let mut entry = match self.entries().into_iter().find(|e| e.fingerprint == revocation.fingerprint) {
+            Some(e) => e,
+            None => return Err(format!("The revoked key {} isn't in the keyring", revocation.fingerprint))
+        };
+        if !revocation.verify(&entry.public) {
+            return Err(format!("The revocation isn't signed by {}", entry.name));
+        }
+        match fs::write(entry.dir.join("revocation.txt"), revocation.to_string()) {
+            Ok(_) => {
+                entry.revocation = Some(revocation.clone());
+                Ok(entry)
+            }
+            Err(e) => Err(format!("Could not write to the keyring: {e}"))
+        }
+    }
+
+    pub fn delete(&self, name: &str) -> Result<KeyEntry, String> {
+        let entry = self.find(name)?;
+        match fs::remove_dir_all(&entry.dir) {
+            Ok(_) => Ok(entry),
+            Err(e) => Err(format!("Could not delete {}: {e}", entry.name))
+        }
+    }
+
+    pub fn rename(&self, name: &str, new_name: &str) -> Result<(), String> {
+        check_field("name", new_name)?;
+        let mut entry = self.find(name)?;
+        if self.entries().iter().any(|e| e.name == new_name) {
+            return Err(format!("There is already a key named {new_name}"));
+        }
+        entry.name = new_name.to_string();
+        match entry.save() {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("Could not rename {name}: {e}"))
+        }
+    }
+}
+
+impl KeyEntry {
+    fn load(dir: PathBuf) -> Result<KeyEntry, String> {
+        let read = |file: &str| match fs::read_to_string(dir.join(file)) {
+            Ok(text) => Ok(text),
+            Err(e) => Err(format!("could not read {file}: {e}"))
+        };
+        let meta = read("meta.txt")?;
+        let field = |key: &str| meta.lines()
+            .find_map(|l| l.strip_prefix(key).and_then(|v| v.strip_prefix(": ")))
+            .unwrap_or("")
+            .to_string();
+
+        let public = match PublicKey::try_from_text(read("public.txt")?.trim()) {
+            Ok(p) => p,
+            Err(e) => return Err(format!("could not parse public.txt: {e}"))
+        };
+        let revocation = match fs::read_to_string(dir.join("revocation.txt")) {
+            Ok(text) => match text.parse::<Revocation>() {
+                Ok(r) => Some(r),
+                Err(e) => return Err(format!("could not parse revocation.txt: {e}"))
+            },
+            Err(_) => None
+        };
+        Ok(KeyEntry {
+            name: field("name"),
+            email: field("email"),
+            created: field("created").parse().unwrap_or(0),
+            fingerprint: public.fingerprint(),
+            public,
+            revocation,
+            dir
+        })
+    }
+
+    fn save(&self) -> std::io::Result<()> {
+        let meta = format!("name: {}\nemail: {}\ncreated: {}\n", self.name, self.email, self.created);
+        fs::write(self.dir.join("meta.txt"), meta)
+    }
+
+    pub fn has_private(&self) -> bool {
+        self.dir.join("private.txt").is_file()
+    }
+
+    pub fn private_key(&self) -> Result<PrivateKey, String> {
+        match fs::read_to_string(self.dir.join("private.txt")) {
+            Ok(text) => Ok(PrivateKey::from_text(Secret::new(text).expose().trim())),
+            Err(e) => Err(format!("No private key for {}: {e}", self.name))
+        }
+    }
+}
+
+fn check_field(field: &str, value: &str) -> Result<(), String> {
+    if value.contains('\n') || value.contains('\r') {
+        return Err(format!("The {field} can't contain line breaks"));
+    }
+    if field == "name" && value.trim().is_empty() {
+        return Err("Keys need a name".to_string());
+    }
+    Ok(())
+}
+
+//Private keys are only readable by their owner
+pub fn write_private(path: &Path, text: &str) -> std::io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(text.as_bytes())
+}
+
+pub fn key_command(command: KeyCommand) {
+    let location = match Keyring::default_location() {
+        Ok(l) => l,
+        Err(e) => panic!("{e}")
+    };
+    let existing = || match Keyring::existing() {
+        Ok(k) => k,
+        Err(e) => panic!("{e}")
+    };
+    match command {
+        KeyCommand::List => {
+            let entries = Keyring::existing().map(|k| k.entries()).unwrap_or_default();
+            if entries.is_empty() {
+                println!("The keyring at {} is empty.", location.display());
+            }
+            for e in entries {
+                let kind = match (e.revocation.is_some(), e.has_private()) {
+                    (true, _) => "revoked",
+                    (false, true) => "pub+priv",
+                    (false, false) => "pub"
+                };
+                println!("{:<16} {:<28} {} {:<8} {}", e.name, e.email, format_time(e.created), kind, e.fingerprint);
+            }
+        }
+        KeyCommand::Import { name, email, pubkey, privkey } => {
+            let public = pubkey.map(|p| PublicKey::from_text(read_key(p).expose().trim()));
+            let private = privkey.map(|p| PrivateKey::from_text(read_key(p).expose().trim()));
+            match Keyring::open(location).import(&name, &email.unwrap_or_default(), public, private.as_ref()) {
+                Ok(e) => println!("Imported {} ({})", e.name, e.fingerprint),
+                Err(e) => panic!("{e}")
+            }
+        }
+        KeyCommand::Export { name, private, mut output_file } => {
+            let entry = match existing().find(&name) {
+                Ok(e) => e,
+                Err(e) => panic!("{e}")
+            };
+            let text = if private {
+                match entry.private_key() {
+                    Ok(key) => key.to_text(),
+                    Err(e) => panic!("{e}")
+                }
+            } else {
+                Secret::new(entry.public.to_text())
+            };
+            write_output(&mut output_file, text.expose());
+        }
+        KeyCommand::Delete { name, force } => {
+            let entry = match existing().find(&name) {
+                Ok(e) => e,
+                Err(e) => panic!("{e}")
+            };
+            if entry.has_private() && !force {
+                panic!("{} has a private key, which can't be recovered once deleted. Use --force to delete it anyway.", entry.name);
+            }
+            match existing().delete(&name) {
+                Ok(e) => println!("Deleted {} ({})", e.name, e.fingerprint),
+                Err(e) => panic!("{e}")
+            }
+        }
+        KeyCommand::Rename { name, new_name } => {
+            match existing().rename(&name, &new_name) {
+                Ok(_) => println!("Renamed {name} to {new_name}"),
+                Err(e) => panic!("{e}")
+            }
+        }
+    }
+}
+
+//The keys named with --to
+pub fn resolve_recipients(names: &[String]) -> Vec<PublicKey> {
+    if names.is_empty() {
+        return Vec::new();
+    }
+    let keyring = match Keyring::existing() {
+        Ok(k) => k,
+        Err(e) => panic!("{e}")
+    };
+    Vec::from_iter(names.iter().map(|name| match keyring.find(name) {
+        Ok(e) => e.public,
+        Err(e) => panic!("{e}")
+    }))
+}
+
+//The directory of the key with this fingerprint. Fingerprints can come from a message, so
+//anything but hex is turned away before it gets near a path.
+fn entry_dir(keyring: &Keyring, fingerprint: &str) -> Option<PathBuf> {
+    if fingerprint.is_empty() || !fingerprint.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some(keyring.dir.join(fingerprint.to_lowercase())).filter(|d| d.is_dir())
+}
+
+//The revocation the keyring holds for this key, if it has been revoked. Without a keyring
+//nothing has been revoked. Only this key's entry is read, so a broken entry for some other
+//key doesn't get in the way.
+pub fn revocation_for(fingerprint: &str) -> Result<Option<Revocation>, String> {
+    let dir = match Keyring::existing().ok().and_then(|k| entry_dir(&k, fingerprint)) {
+        Some(d) => d,
+        None => return Ok(None)
+    };
+    match fs::read_to_string(dir.join("revocation.txt")) {
+        Ok(text) => match text.parse() {
+            Ok(r) => Ok(Some(r)),
+            Err(e) => Err(format!("Could not parse {}: {e}", dir.join("revocation.txt").display()))
+        },
+        Err(_) => Ok(None)
+    }
+}
+
+//The first private key in the keyring for one of these fingerprints
+pub fn resolve_private(fingerprints: &[String]) -> Option<PrivateKey> {
+    let keyring = Keyring::existing().ok()?;
+    let file = fingerprints.iter()
+        .filter_map(|f| entry_dir(&keyring, f))
+        .map(|d| d.join("private.txt"))
+        .find(|f| f.is_file())?;
+    match fs::read_to_string(&file) {
+        Ok(text) => Some(PrivateKey::from_text(Secret::new(text).expose().trim())),
+        Err(e) => panic!("Could not read {}: {e}", file.display())
+    }
+}
+
+#[test]
+fn keyring_import_find_rename_delete() {
+    use crate::key::test_key;
+    use num_bigint_dig::BigUint;
+
+    let dir = std::env::temp_dir().join(format!("rsa_rust_keyring_test_{}", std::process::id()));
+    let keyring = Keyring::open(dir.clone());
+    let private = test_key(512);
+    let public = private.public();
+
+    let entry = keyring.import("alice", "alice@example.com", None, Some(&private)).unwrap();
+    assert_eq!(entry.fingerprint, public.fingerprint());
+    assert!(keyring.import("alice", "", Some(PublicKey::new(BigUint::from(3233u32), BigUint::from(17u8))), None).is_err());
+    assert!(keyring.import("alice again", "", Some(public.clone()), None).is_err());
+
+    //A half-written entry is skipped instead of breaking every lookup
+    fs::create_dir_all(dir.join("broken")).unwrap();
+    fs::write(dir.join("broken").join("meta.txt"), "name: broken\n").unwrap();
+    fs::write(dir.join("broken").join("public.txt"), "not a key").unwrap();
+    assert_eq!(keyring.entries().len(), 1);
+
+    let found = keyring.find(&public.fingerprint()[..12]).unwrap();
+    assert_eq!(found.name, "alice");
+    assert_eq!(found.email, "alice@example.com");
+    assert_eq!(found.private_key().unwrap().to_text().expose(), private.to_text().expose());
+    assert_eq!(entry_dir(&keyring, &public.fingerprint().to_uppercase()), Some(dir.join(public.fingerprint())));
+    assert!(entry_dir(&keyring, "../rsa_rust").is_none());
+
+    keyring.rename("alice", "alice work").unwrap();
+    assert!(keyring.find("alice").is_err());
+    assert_eq!(keyring.find("alice work").unwrap().created, entry.created);
+
+    keyring.delete("alice work").unwrap();
+    assert!(keyring.entries().is_empty());
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -1,17 +1,43 @@
 use base::to_base10;
-use num_bigint_dig::BigUint;
-use std::{io::Write, process::exit, str::FromStr};
+use std::{io::{Read, Write}, process::exit};
 
 mod millers;
 mod base;
+mod audit;
+mod batchgcd;
+mod blind;
+mod brainkey;
+mod envelope;
+mod factor;
 mod generate;
+mod key;
+mod keyring;
 mod mainutil;
+mod metadata;
+mod montgomery;
+mod oaep;
+mod primality;
+mod prime_cli;
+mod provable;
+mod rabin;
+mod revocation;
+mod secret;
+mod shamir;
+mod sieve;
+mod signature;
+mod threshold;
+mod timelock;
 
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, Parser, Subcommand};
 use clio::*;
+use num_bigint_dig::BigUint;
+use rand::SeedableRng;
+use rand_chacha::ChaCha20Rng;
 
-use crate::{base::from_base10, mainutil::{parse_input_group, read_key, split_string_at_n}};
+use crate::{base::from_base10, mainutil::{parse_input_group, read_key}};
 
+//Digits of the textbook RSA blocks older versions wrote, separated by '$'
+const TEXTBOOK_ALPHABET: &str = ".,?! \t\n\rabcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
 
 #[derive(Parser,Debug)]
 #[clap(author="Logan Mathis", version, about="Use Bart's RSA algorithm to encrypt and decrypt messages.")]
@@ -32,29 +58,92 @@ struct InputArgGroup {
     input: Option<String>
 }
 
+#[derive(Debug, clap::Args)]
+struct KeyGenArgs {
+    /// Which cryptosystem the keys are for.
+    #[clap(long, value_enum, default_value_t)]
+    scheme: rabin::Scheme,
+
+    /// Build the primes with the Shawe-Taylor construction and write a primality certificate
+    /// to certificate.txt next to the private key.
+    #[clap(long)]
+    provable_primes: bool,
+
+    /// Size of the modulus in bits. RSA keys need at least 784 to receive messages.
+    #[clap(long, default_value_t=2048)]
+    bits: usize,
+
+    /// Number of primes in the modulus (multi-prime RSA, RFC 8017), each of about --bits / k bits.
+    #[clap(long, default_value_t=2, value_parser=clap::value_parser!(u8).range(2..=5))]
+    primes: u8,
+
+    /// Primality test used when searching for p and q.
+    #[clap(long, value_enum, default_value_t)]
+    primality_test: primality::PrimalityTestKind,
+
+    /// Number of worker threads testing candidates for p and q. Defaults to one per core.
+    #[clap(long)]
+    threads: Option<usize>,
+
+    /// INSECURE, for tests and demos only: seed the key generator with this number, so the
+    /// same seed always gives the same keys. Anyone who knows the seed has the private key.
+    #[clap(long, conflicts_with_all=["passphrase", "file"])]
+    seed: Option<u64>,
+
+    /// Days until the key expires. Keys don't expire by default.
+    #[clap(long)]
+    expires_in_days: Option<u64>,
+
+    /// What the key may be used for, comma separated. Certify lets the key sign its own
+    /// revocation. Defaults to everything.
+    #[clap(long, value_enum, value_delimiter=',')]
+    usage: Vec<metadata::Usage>,
+
+    /// A comment stored with the key.
+    #[clap(long)]
+    comment: Option<String>
+}
+
 #[derive(Subcommand, Debug)]
 enum SubCommand {
 
     /// Generate keys required to encrypt. By default, keys go next to the executable.
+    /// With a passphrase and salt the keys are a "brain key": the same passphrase and salt
+    /// always give the same key pair. Without them the keys are random.
     GenerateKeys {
 
         /// Specify a directory to put the keys.
         #[clap(short='d', long)]
         key_directory: Option<ClioPath>,
         
-        /// Specify a file for the input strings. They're separated by a newline.
+        /// Specify a file with the passphrase and the salt, separated by a newline.
         #[clap(short, long)]
         file: Option<Input>,
         
-        /// The first string, enclosed in quotes.
-        #[clap(requires="input_string_2")]
-        input_string_1: Option<String>,
+        /// The passphrase, enclosed in quotes. Anyone who guesses it can rebuild the private key.
+        #[clap(requires="salt")]
+        passphrase: Option<String>,
+
+        /// The salt, enclosed in quotes, e.g. your email address. It doesn't need to be secret.
+        #[clap(requires="passphrase")]
+        salt: Option<String>,
+
+        #[clap(flatten)]
+        options: KeyGenArgs
+    },
+
+    /// Check a primality certificate written by generate-keys --provable-primes.
+    VerifyPrimeCertificate {
+        /// The certificate file. Defaults to "./certificate.txt"
+        #[clap(short, long, default_value="./certificate.txt")]
+        certificate: Input,
 
-        /// The second string, enclosed in quotes.
-        #[clap(requires="input_string_1")]
-        input_string_2: Option<String>
+        /// Also check that the certified primes multiply to n in this public key.
+        #[clap(short='p', long)]
+        pubkey: Option<Input>
     },
 
+    /// Encrypt a message for one or more recipients. Any of their private keys can decrypt it.
     Encrypt {
         /// Specify a file to read from. Defaults to stdin.
         #[clap(flatten)]
@@ -64,9 +153,33 @@ enum SubCommand {
         #[clap(short, long, default_value="-")]
         output_file: Output,
 
-        /// Specify a private key to use for encrypting. Defaults to "./private.txt"
-        #[clap(short='p', long, default_value="./public.txt")]
-        pubkey: Input
+        /// Public key of a recipient, repeat for more recipients. Defaults to "./public.txt"
+        /// unless --to is given.
+        #[clap(short='p', long, visible_alias="recipient")]
+        pubkey: Vec<Input>,
+
+        /// Name of a recipient in the keyring, repeat for more recipients.
+        #[clap(short, long)]
+        to: Vec<String>,
+
+        /// INSECURE, for tests and demos only: seed the content key and padding with this
+        /// number, so the same seed always gives the same ciphertext.
+        #[clap(long)]
+        seed: Option<u64>,
+
+        /// Encrypt even to recipients whose keys have expired or aren't marked for encryption.
+        #[clap(long)]
+        force: bool,
+
+        /// Which cryptosystem the recipient's key is for. Rabin takes a single recipient.
+        #[clap(long, value_enum, default_value_t)]
+        scheme: rabin::Scheme,
+
+        /// INSECURE: write unpadded textbook RSA blocks to a single recipient, the way older
+        /// versions did, for homomorphic-multiply. Equal messages give equal blocks and anyone
+        /// can tamper with them.
+        #[clap(long, conflicts_with="seed")]
+        textbook: bool
     },
 
     Decrypt {
@@ -76,8 +189,412 @@ enum SubCommand {
         #[clap(short, long, default_value="-")]
         output_file: Output,
 
-        #[clap(short='P', long, default_value="./private.txt")]
-        privkey: Input
+        /// The private key. Defaults to a key in the keyring the message was encrypted to,
+        /// then "./private.txt"
+        #[clap(short='P', long)]
+        privkey: Option<Input>,
+
+        /// Which cryptosystem the private key is for.
+        #[clap(long, value_enum, default_value_t)]
+        scheme: rabin::Scheme
+    },
+
+    /// Sign a message, writing a detached signature.
+    Sign {
+        #[clap(flatten)]
+        group: InputArgGroup,
+
+        #[clap(short, long, default_value="-")]
+        output_file: Output,
+
+        /// The private key. Defaults to "./private.txt"
+        #[clap(short='P', long)]
+        privkey: Option<Input>,
+
+        /// Sign even if the key has expired or isn't marked for signing.
+        #[clap(long)]
+        force: bool
+    },
+
+    /// Check a detached signature over a message. Exits with 1 if it doesn't check out.
+    Verify {
+        #[clap(flatten)]
+        group: InputArgGroup,
+
+        /// The signature written by sign.
+        #[clap(short, long)]
+        signature: Input,
+
+        /// The signer's public key. Defaults to the key in the keyring that made the signature.
+        #[clap(short='p', long)]
+        pubkey: Option<Input>
+    },
+
+    /// Write a certificate revoking a key, signed by the key itself.
+    GenerateRevocation {
+        /// The private key to revoke. Defaults to "./private.txt"
+        #[clap(short='P', long)]
+        privkey: Option<Input>,
+
+        /// Why the key is revoked.
+        #[clap(long, value_enum, default_value_t)]
+        reason: revocation::RevocationReason,
+
+        #[clap(short, long, default_value="-")]
+        output_file: Output
+    },
+
+    /// Mark a key in the keyring as revoked. Nothing will be encrypted to it or accepted
+    /// as signed by it afterwards.
+    ImportRevocation {
+        /// The certificate written by generate-revocation.
+        revocation: Input
+    },
+
+    /// Split a private key into Shamir shares, written to share-1.txt, share-2.txt, ...
+    /// Any --threshold of them rebuild the key with combine-key.
+    SplitKey {
+        /// The private key to split. Defaults to "./private.txt"
+        #[clap(short='P', long)]
+        privkey: Option<Input>,
+
+        /// Number of shares needed to rebuild the key.
+        #[clap(short, long, value_parser=clap::value_parser!(u8).range(2..))]
+        threshold: u8,
+
+        /// Number of shares to write.
+        #[clap(short, long, value_parser=clap::value_parser!(u8).range(2..))]
+        shares: u8,
+
+        /// Directory to write the shares to. Defaults to the current directory.
+        #[clap(short='d', long)]
+        share_directory: Option<ClioPath>
+    },
+
+    /// Rebuild a private key from shares written by split-key.
+    CombineKey {
+        /// The share files, at least as many as the threshold.
+        #[clap(required=true)]
+        shares: Vec<Input>,
+
+        /// The public key to check the rebuilt key against. Defaults to the key in the keyring.
+        #[clap(short='p', long)]
+        pubkey: Option<Input>,
+
+        #[clap(short, long, default_value="-")]
+        output_file: Output
+    },
+
+    /// Generate a key whose private exponent is shared between several people from the start
+    /// (Shoup threshold RSA). Any --threshold of them can decrypt or sign together, with
+    /// partial-decrypt and combine-partials, without the private key ever being put together.
+    GenerateThresholdKey {
+        /// Size of the modulus in bits.
+        #[clap(long, default_value_t=2048)]
+        bits: usize,
+
+        /// Number of shareholders needed to decrypt or sign.
+        #[clap(short, long, value_parser=clap::value_parser!(u8).range(2..))]
+        threshold: u8,
+
+        /// Number of shares to write.
+        #[clap(short, long, value_parser=clap::value_parser!(u8).range(2..))]
+        shares: u8,
+
+        /// Directory to write the keys to. Defaults to the current directory.
+        #[clap(short='d', long)]
+        key_directory: Option<ClioPath>
+    },
+
+    /// Work out one shareholder's part of decrypting a message to a threshold key, or with
+    /// --sign of signing a message.
+    PartialDecrypt {
+        #[clap(flatten)]
+        group: InputArgGroup,
+
+        /// This shareholder's share.
+        #[clap(short='S', long)]
+        share: Input,
+
+        /// The threshold key. Defaults to "./threshold.txt"
+        #[clap(short='k', long, default_value="./threshold.txt")]
+        threshold_key: Input,
+
+        /// Sign the message instead of decrypting it.
+        #[clap(long)]
+        sign: bool,
+
+        #[clap(short, long, default_value="-")]
+        output_file: Output
+    },
+
+    /// Put the shareholders' partial results together into the plaintext, or with --sign
+    /// into a signature.
+    CombinePartials {
+        /// The message that was partially decrypted or signed.
+        #[clap(flatten)]
+        group: InputArgGroup,
+
+        /// The partial results, at least as many as the threshold.
+        #[clap(required=true)]
+        partials: Vec<Input>,
+
+        /// The threshold key. Defaults to "./threshold.txt"
+        #[clap(short='k', long, default_value="./threshold.txt")]
+        threshold_key: Input,
+
+        /// The partial results are of signing the message.
+        #[clap(long)]
+        sign: bool,
+
+        #[clap(short, long, default_value="-")]
+        output_file: Output
+    },
+
+    /// Blind a message for someone else to sign without seeing it. The unblinding factor
+    /// goes to its own file, keep it for unblind.
+    Blind {
+        #[clap(flatten)]
+        group: InputArgGroup,
+
+        /// The signer's public key. Defaults to "./public.txt"
+        #[clap(short='p', long)]
+        pubkey: Option<Input>,
+
+        /// Where the blinded message goes. Defaults to stdout.
+        #[clap(short, long, default_value="-")]
+        output_file: Output,
+
+        /// Where the unblinding factor goes.
+        #[clap(short='u', long, default_value="./unblind.txt")]
+        factor_file: ClioPath
+    },
+
+    /// Sign a message blinded with blind, without learning what it is.
+    SignBlinded {
+        /// The blinded message.
+        #[clap(flatten)]
+        group: InputArgGroup,
+
+        /// The private key. Defaults to "./private.txt"
+        #[clap(short='P', long)]
+        privkey: Option<Input>,
+
+        /// Sign even if the key has expired or isn't marked for signing.
+        #[clap(long)]
+        force: bool,
+
+        #[clap(short, long, default_value="-")]
+        output_file: Output
+    },
+
+    /// Turn a blind signature into a signature on the original message that verify accepts.
+    Unblind {
+        /// The original message.
+        #[clap(flatten)]
+        group: InputArgGroup,
+
+        /// The blind signature from sign-blinded.
+        #[clap(short, long)]
+        signature: Input,
+
+        /// The unblinding factor written by blind.
+        #[clap(short='u', long, default_value="./unblind.txt")]
+        factor_file: Input,
+
+        /// The signer's public key. Defaults to "./public.txt"
+        #[clap(short='p', long)]
+        pubkey: Option<Input>,
+
+        #[clap(short, long, default_value="-")]
+        output_file: Output
+    },
+
+    /// Multiply two textbook RSA ciphertext blocks under the same key. The product decrypts
+    /// to the product of the plaintexts. Padded (OAEP) messages are refused.
+    HomomorphicMultiply {
+        /// File with the first ciphertext block, as written by encrypt --textbook.
+        first: Input,
+
+        /// File with the second ciphertext block.
+        second: Input,
+
+        /// The public key both blocks were encrypted to. Defaults to "./public.txt"
+        #[clap(short='p', long)]
+        pubkey: Option<Input>,
+
+        #[clap(short, long, default_value="-")]
+        output_file: Output
+    },
+
+    /// Lock a message in a time-lock puzzle that takes about --seconds of sequential work on
+    /// this machine to open. Nobody, including you, can open it faster with more computers.
+    TimelockCreate {
+        #[clap(flatten)]
+        group: InputArgGroup,
+
+        /// About how long solving the puzzle should take, in seconds on this machine.
+        #[clap(long)]
+        seconds: u64,
+
+        /// Size of the puzzle's modulus in bits.
+        #[clap(long, default_value_t=2048)]
+        bits: usize,
+
+        #[clap(short, long, default_value="-")]
+        output_file: Output
+    },
+
+    /// Open a time-lock puzzle by doing its squarings, reporting progress as it goes.
+    TimelockSolve {
+        #[clap(flatten)]
+        group: InputArgGroup,
+
+        #[clap(short, long, default_value="-")]
+        output_file: Output
+    },
+
+    /// Try the classic attacks on weak RSA keys against a public key: Fermat factorization for
+    /// close primes, Wiener's attack for a small d, Pollard p - 1 and rho for smooth or small
+    /// factors, and a check for a small e with a small modulus. Exits with 1 if any attack breaks
+    /// the key.
+    AuditKey {
+        /// The public key to audit. Defaults to "./public.txt"
+        #[clap(short='p', long)]
+        pubkey: Option<Input>,
+
+        /// Where to write the private key if an attack recovers it.
+        #[clap(short, long)]
+        output_file: Option<Output>
+    },
+
+    /// Look for public keys that share a prime, with Bernstein's batch GCD over every
+    /// public.txt under a directory. Reports the pairs, rebuilds their private keys and exits
+    /// with 1 if there are any.
+    ScanKeys {
+        /// Directory to search for public.txt files, at any depth.
+        directory: ClioPath,
+
+        /// Directory to write the recovered private keys to. They are only reported without it.
+        #[clap(short='d', long)]
+        recovered_directory: Option<ClioPath>
+    },
+
+    /// Factor an integer completely with trial division, Pollard rho and the elliptic curve
+    /// method. Prints the prime factors with their exponents and exits with 1 if the time limit
+    /// runs out first.
+    Factor {
+        /// The integer to factor, in decimal.
+        n: BigUint,
+
+        /// Seconds to spend before giving up on what is left.
+        #[clap(long, default_value_t=60)]
+        time_limit: u64
+    },
+
+    /// Test whether a number is prime. Exits with 1 if it is composite.
+    IsPrime {
+        n: String,
+
+        #[clap(flatten)]
+        args: prime_cli::NumberArgs
+    },
+
+    /// Print the smallest prime bigger than a number.
+    NextPrime {
+        n: String,
+
+        #[clap(flatten)]
+        args: prime_cli::NumberArgs
+    },
+
+    /// Print the largest prime smaller than a number.
+    PrevPrime {
+        n: String,
+
+        #[clap(flatten)]
+        args: prime_cli::NumberArgs
+    },
+
+    /// Print a random prime of exactly --bits bits.
+    RandomPrime {
+        #[clap(long, value_parser=clap::value_parser!(u64).range(2..))]
+        bits: u64,
+
+        /// Print the prime in hex with a 0x prefix.
+        #[clap(long, conflicts_with="alphabet")]
+        hex: bool,
+
+        #[clap(flatten)]
+        args: prime_cli::NumberArgs
+    },
+
+    /// Count the primes between two numbers, both included. Long ranges below 2^64 are
+    /// counted exactly with a segmented sieve, others by testing each candidate.
+    PrimeCount {
+        a: String,
+
+        b: String,
+
+        #[clap(flatten)]
+        args: prime_cli::NumberArgs
+    },
+
+    /// Manage the keyring in $XDG_DATA_HOME/rsa_rust.
+    Key {
+        #[clap(subcommand)]
+        command: KeyCommand
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum KeyCommand {
+    /// List the keys in the keyring.
+    List,
+
+    /// Add a key to the keyring. Give its public key, private key or both.
+    Import {
+        /// Name to refer to the key by, e.g. with encrypt --to.
+        name: String,
+
+        /// Email address of the key's owner.
+        #[clap(short, long)]
+        email: Option<String>,
+
+        #[clap(short='p', long)]
+        pubkey: Option<Input>,
+
+        #[clap(short='P', long)]
+        privkey: Option<Input>
+    },
+
+    /// Write a key from the keyring out in the usual key file format.
+    Export {
+        /// Name or fingerprint of the key.
+        name: String,
+
+        /// Export the private key instead of the public key.
+        #[clap(long)]
+        private: bool,
+
+        #[clap(short, long, default_value="-")]
+        output_file: Output
+    },
+
+    /// Remove a key from the keyring.
+    Delete {
+        /// Name or fingerprint of the key.
+        name: String,
+
+        /// Also delete keys with a private half.
+        #[clap(long)]
+        force: bool
+    },
+
+    /// Give a key in the keyring a new name.
+    Rename {
+        name: String,
+        new_name: String
     }
 }
 
@@ -89,154 +606,418 @@ fn main() {
         SubCommand::GenerateKeys { 
             key_directory,
             file,
-            input_string_1,
-            input_string_2 
-        } => generate::generate_keys(key_directory, file, input_string_1, input_string_2),
+            passphrase,
+            salt,
+            options
+        } => {
+            if let Err(e) = generate::check_key_size(options.bits, options.primes as usize, options.scheme) {
+                invalid_value("generate-keys", e);
+            }
+            generate::generate_keys(key_directory, file, passphrase, salt, options)
+        }
+        SubCommand::VerifyPrimeCertificate {
+            certificate,
+            pubkey
+        } => provable::verify_prime_certificate(certificate, pubkey),
+        SubCommand::Encrypt { 
+            scheme: rabin::Scheme::Rabin,
+            textbook: true,
+            ..
+        } => panic!("Only RSA messages can be written as textbook blocks, drop --textbook or --scheme rabin"),
         SubCommand::Encrypt { 
             group,
             output_file, 
-            pubkey 
-        } => encrypt(group, output_file, pubkey),
+            pubkey,
+            to,
+            seed,
+            force,
+            scheme: rabin::Scheme::Rabin,
+            textbook: false
+        } => encrypt_rabin(group, output_file, pubkey, to, seed, force),
+        SubCommand::Encrypt { 
+            group,
+            output_file, 
+            pubkey,
+            to,
+            seed,
+            force,
+            scheme: rabin::Scheme::Rsa,
+            textbook
+        } => encrypt(group, output_file, pubkey, to, seed, force, textbook),
         SubCommand::Decrypt { 
             group, 
             output_file, 
-            privkey 
-        } => decrypt(group, output_file, privkey)
+            privkey,
+            scheme: rabin::Scheme::Rabin
+        } => decrypt_rabin(group, output_file, privkey),
+        SubCommand::Decrypt { 
+            group, 
+            output_file, 
+            privkey,
+            scheme: rabin::Scheme::Rsa
+        } => decrypt(group, output_file, privkey),
+        SubCommand::Sign {
+            group,
+            output_file,
+            privkey,
+            force
+        } => signature::sign_message(group, output_file, privkey, force),
+        SubCommand::Verify {
+            group,
+            signature,
+            pubkey
+        } => signature::verify_message(group, signature, pubkey),
+        SubCommand::GenerateRevocation {
+            privkey,
+            reason,
+            output_file
+        } => revocation::generate_revocation(privkey, reason, output_file),
+        SubCommand::ImportRevocation { revocation } => revocation::import_revocation(revocation),
+        SubCommand::SplitKey {
+            privkey,
+            threshold,
+            shares,
+            share_directory
+        } => shamir::split_key(privkey, threshold, shares, share_directory),
+        SubCommand::CombineKey {
+            shares,
+            pubkey,
+            output_file
+        } => shamir::combine_key(shares, pubkey, output_file),
+        SubCommand::GenerateThresholdKey {
+            bits,
+            threshold,
+            shares,
+            key_directory
+        } => {
+            if let Err(e) = generate::check_key_size(bits, 2, rabin::Scheme::Rsa) {
+                invalid_value("generate-threshold-key", e);
+            }
+            threshold::generate_threshold_key(bits, threshold, shares, key_directory)
+        }
+        SubCommand::PartialDecrypt {
+            group,
+            share,
+            threshold_key,
+            sign,
+            output_file
+        } => threshold::partial_decrypt(group, share, threshold_key, sign, output_file),
+        SubCommand::CombinePartials {
+            group,
+            partials,
+            threshold_key,
+            sign,
+            output_file
+        } => threshold::combine_partials(group, partials, threshold_key, sign, output_file),
+        SubCommand::Blind {
+            group,
+            pubkey,
+            output_file,
+            factor_file
+        } => blind::blind_message(group, pubkey, output_file, factor_file),
+        SubCommand::SignBlinded {
+            group,
+            privkey,
+            force,
+            output_file
+        } => blind::sign_blinded_message(group, privkey, force, output_file),
+        SubCommand::Unblind {
+            group,
+            signature,
+            factor_file,
+            pubkey,
+            output_file
+        } => blind::unblind_signature(group, signature, factor_file, pubkey, output_file),
+        SubCommand::HomomorphicMultiply {
+            first,
+            second,
+            pubkey,
+            output_file
+        } => homomorphic_multiply(first, second, pubkey, output_file),
+        SubCommand::TimelockCreate {
+            group,
+            seconds,
+            bits,
+            output_file
+        } => {
+            if let Err(e) = generate::check_key_size(bits, 2, rabin::Scheme::Rsa) {
+                invalid_value("timelock-create", e);
+            }
+            timelock::timelock_create(group, seconds, bits, output_file)
+        }
+        SubCommand::TimelockSolve {
+            group,
+            output_file
+        } => timelock::timelock_solve(group, output_file),
+        SubCommand::AuditKey {
+            pubkey,
+            output_file
+        } => audit::audit_key(pubkey, output_file),
+        SubCommand::ScanKeys {
+            directory,
+            recovered_directory
+        } => batchgcd::scan_keys(directory, recovered_directory),
+        SubCommand::Factor { n, time_limit } => factor::factor_command(n, time_limit),
+        SubCommand::IsPrime { n, args } => prime_cli::is_prime(n, args),
+        SubCommand::NextPrime { n, args } => prime_cli::next_prime(n, args),
+        SubCommand::PrevPrime { n, args } => prime_cli::prev_prime(n, args),
+        SubCommand::RandomPrime { bits, hex, args } => prime_cli::random_prime(bits as usize, hex, args),
+        SubCommand::PrimeCount { a, b, args } => prime_cli::prime_count(a, b, args),
+        SubCommand::Key { command } => keyring::key_command(command)
     }
 }
 
-fn encrypt(input: InputArgGroup, mut output: Output, pubkey:Input) {
+//Reports a bad argument the way clap reports its own, with the subcommand's usage
+fn invalid_value(subcommand: &str, message: String) -> ! {
+    let mut command = Arguments::command();
+    command.build();
+    command.find_subcommand_mut(subcommand).unwrap().error(clap::error::ErrorKind::ValueValidation, message).exit()
+}
 
-    //Have to do some matching to get the inpu
-    let input_string = parse_input_group(input);
+fn encrypt(input: InputArgGroup, mut output: Output, mut pubkeys: Vec<Input>, to: Vec<String>, seed: Option<u64>, force: bool, textbook: bool) {
 
-    //Split the string every 215 chars into a vec of strings
-    let input_string_vec = split_string_at_n(215, input_string);
+    //Have to do some matching to get the inpu
+    let input_string = secret::Secret::new(parse_input_group(input));
 
-    let mut input_vec = Vec::new();
-    
-    for i in input_string_vec {
-        let input_as_int = to_base10(&i, ".,?! \t\n\rabcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789");
-        input_vec.push(input_as_int);
+    //Parse pubkeys
+    if pubkeys.is_empty() && to.is_empty() {
+        match Input::new("./public.txt") {
+            Ok(p) => pubkeys.push(p),
+            Err(e) => panic!("No recipients given and could not open ./public.txt: {e}")
+        }
     }
+    let mut recipients = Vec::from_iter(pubkeys.into_iter().map(|pubkey| {
+        let pubkey_text = read_key(pubkey);
+        key::PublicKey::from_text(pubkey_text.expose())
+    }));
+    recipients.extend(keyring::resolve_recipients(&to));
 
-    //Parse pubkey
-    let pubkey_text = read_key(pubkey);
-
-    let keys = Vec::from_iter(pubkey_text.split('\n').into_iter());
-    assert_eq!(keys.len(), 2);
-    let n_string = keys.first().unwrap().to_string();
-    let e_string = keys.last().unwrap().to_string();
-
-    let n_res = BigUint::from_str(&n_string);
-    let e_res = BigUint::from_str(&e_string);
-
-    let e;
-    let n;
-
-    match n_res {
-        Ok(i) => {
-            n = i;
+    for recipient in &recipients {
+        match keyring::revocation_for(&recipient.fingerprint()) {
+            Ok(Some(revocation)) => panic!("Refusing to encrypt to {}, the key was {}.", recipient.fingerprint(), revocation.describe()),
+            Ok(None) => (),
+            Err(e) => panic!("{e}")
         }
-        Err(e) => {
-            panic!("Could not parse n from the provided pubkey file! Error: {e}");
+        if let Err(e) = recipient.metadata.check(metadata::Usage::Encrypt, mainutil::now()) {
+            if force {
+                eprintln!("Warning: encrypting to {} anyway, {e}.", recipient.fingerprint());
+            } else {
+                panic!("Refusing to encrypt to {}, {e}. Use --force to encrypt anyway.", recipient.fingerprint());
+            }
         }
     }
-    
-    match e_res {
-        Ok(i) => {
-            e = i;
-        }
-        Err(e) => {
-            panic!("Could not parse e from the provided pubkey file! Error: {e}");
+
+    let mut rng = match seed {
+        Some(seed) => {
+            eprintln!("Warning: --seed makes the ciphertext predictable, use it for tests and demos only.");
+            ChaCha20Rng::seed_from_u64(seed)
         }
-    }
+        None => ChaCha20Rng::from_entropy()
+    };
 
     //Actually encrypt
-    let mut encrypted = String::new();
-
-    
-    
-    for block in input_vec {
-        let out = block.modpow(&e, &n);
-        let new_string = from_base10(out, ".,?! \t\n\rabcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789");
-        encrypted.push_str(new_string.as_str());
-        encrypted.push('$');
-    }
+    let encrypted = if textbook {
+        match recipients.as_slice() {
+            [recipient] => textbook_encrypt(recipient, input_string.expose()),
+            _ => panic!("Textbook blocks are encrypted to a single recipient")
+        }
+    } else {
+        match envelope::seal(input_string.expose().as_bytes(), &recipients, &mut rng) {
+            Ok(e) => e,
+            Err(e) => panic!("{e}")
+        }
+    };
 
-    let res = output.write(encrypted.to_string().as_bytes());
+    let res = output.write(encrypted.as_bytes());
     match res {
         Ok(u) => {
             if output.path().to_string() == "\"-\"" {
                 exit(0);
             } else {
-                println!("Wrote {u} bytes to output file.");
+                eprintln!("Wrote {u} bytes to output file.");
                 exit(0);
             }
         },
         Err(e) => {
-            println!("Failed to write to output file. Error: {e}");
+            eprintln!("Failed to write to output file. Error: {e}");
         }
     }
 
 }
 
 
-fn decrypt(input: InputArgGroup, mut output_file: Output, privkey: Input){ 
+fn decrypt(input: InputArgGroup, output_file: Output, privkey: Option<Input>){ 
     let input_string = parse_input_group(input);
+    if rabin::is_rabin_message(&input_string) {
+        panic!("This message was encrypted with Rabin, decrypt it with --scheme rabin");
+    }
 
-    let privkey_text = read_key(privkey);
+    //Without -P, look for a recipient's private key in the keyring
+    let keyring_key = match (&privkey, envelope::recipients(&input_string)) {
+        (None, Ok(fingerprints)) => keyring::resolve_private(&fingerprints),
+        _ => None
+    };
 
-    let keys = Vec::from_iter(privkey_text.split('\n').into_iter());
-    assert_eq!(keys.len(), 2);
-    let n_string = keys.first().unwrap().to_string();
-    let d_string = keys.last().unwrap().to_string();
+    let key = match keyring_key {
+        Some(key) => key,
+        None => {
+            let privkey_text = read_key(mainutil::input_or_default(privkey, "./private.txt"));
+            key::PrivateKey::from_text(privkey_text.expose())
+        }
+    };
 
-    let n_res = BigUint::from_str(&n_string);
-    let d_res = BigUint::from_str(&d_string);
+    let mut rng = rand::thread_rng();
 
-    let d;
-    let n;
+    if envelope::is_envelope(&input_string) {
+        let decrypted = match envelope::open(&input_string, &key, &mut rng) {
+            Ok(d) => d,
+            Err(e) => panic!("Could not decrypt: {e}")
+        };
+        write_decrypted(output_file, decrypted.expose());
+    }
 
-    match n_res {
-        Ok(i) => {
-            n = i;
-        }
-        Err(e) => {
-            panic!("Could not parse n from the provided pubkey file! Error: {e}");
-        }
+    if envelope::contains_armor(&input_string) {
+        panic!("Could not decrypt: there is other text in front of the message armor, remove it and try again");
     }
-    
-    match d_res {
-        Ok(i) => {
-            d = i;
+
+    //Messages from before envelopes are blocks of textbook RSA
+    let blocks = match textbook_blocks(&input_string, &key.n) {
+        Ok(b) => b,
+        Err(e) => panic!("Could not decrypt: {e}")
+    };
+    let mut decrypted_string = secret::Secret::new(String::new());
+
+    for block in blocks {
+        let decrypted = key.decrypt_block(&block, &mut rng);
+        let decrypted_as_text = secret::Secret::new(from_base10(decrypted.expose().clone(), TEXTBOOK_ALPHABET));
+        decrypted_string.expose_mut().push_str(decrypted_as_text.expose());
+    }
+
+
+    write_decrypted(output_file, decrypted_string.expose().as_bytes());
+}
+
+//Unpadded RSA on the text, as many characters to a block as stay below n, each block written
+//in the alphabet and ended with '$'
+fn textbook_encrypt(key: &key::PublicKey, text: &str) -> String {
+    if let Some(c) = text.chars().find(|c| !TEXTBOOK_ALPHABET.contains(*c)) {
+        panic!("{c:?} can't be encrypted as a textbook block, only letters, digits, whitespace and .,?! can");
+    }
+    let base = BigUint::from(TEXTBOOK_ALPHABET.len());
+    let mut per_block = 0;
+    let mut limit = base.clone();
+    while limit <= key.n {
+        per_block += 1;
+        limit *= &base;
+    }
+    if per_block == 0 {
+        panic!("The key is too small to hold even one character");
+    }
+
+    let chars = Vec::from_iter(text.chars());
+    let mut encrypted = String::new();
+    for chunk in chars.chunks(per_block) {
+        let block = to_base10(&String::from_iter(chunk), TEXTBOOK_ALPHABET);
+        encrypted.push_str(&from_base10(key.encrypt_block(&block), TEXTBOOK_ALPHABET));
+        encrypted.push('$');
+    }
+    encrypted
+}
+
+//The '$' separated textbook RSA blocks of a legacy message. Anything outside the alphabet or
+//not below n means the input isn't one.
+fn textbook_blocks(text: &str, n: &BigUint) -> std::result::Result<Vec<BigUint>, String> {
+    let mut blocks = Vec::new();
+    for block in text.split('$').filter(|b| !b.is_empty()) {
+        if let Some(c) = block.chars().find(|c| !TEXTBOOK_ALPHABET.contains(*c)) {
+            return Err(format!("{c:?} can't appear in a ciphertext block, this isn't an encrypted message"));
         }
-        Err(e) => {
-            panic!("Could not parse e from the provided pubkey file! Error: {e}");
+        let value = to_base10(block, TEXTBOOK_ALPHABET);
+        if value >= *n {
+            return Err("a ciphertext block is bigger than n, it wasn't encrypted to this key".to_string());
         }
+        blocks.push(value);
+    }
+    if blocks.is_empty() {
+        return Err("there are no ciphertext blocks in the input".to_string());
     }
+    Ok(blocks)
+}
 
-    let mut decrypted_string = String::new();
+fn homomorphic_multiply(first: Input, second: Input, pubkey: Option<Input>, mut output: Output) {
+    let key = key::PublicKey::from_text(read_key(mainutil::input_or_default(pubkey, "./public.txt")).expose());
 
-    let input_vec: Vec<&str> = input_string.split('$').collect();
+    let parse_block = |mut input: Input| {
+        let mut text = String::new();
+        if let Err(e) = input.read_to_string(&mut text) {
+            panic!("Failed to read {}. Error: {e}", input.path());
+        }
+        if envelope::is_envelope(&text) {
+            panic!("Refusing to multiply a padded ciphertext, OAEP is there to make RSA non-malleable. Only textbook RSA blocks can be multiplied.");
+        }
+        match Vec::from_iter(text.split('$').filter(|b| !b.trim().is_empty())).as_slice() {
+            [block] => to_base10(block, TEXTBOOK_ALPHABET),
+            _ => panic!("Give exactly one ciphertext block for each operand")
+        }
+    };
+    let product = match key.multiply_ciphertexts(&parse_block(first), &parse_block(second)) {
+        Ok(p) => p,
+        Err(e) => panic!("{e}")
+    };
+    mainutil::write_output(&mut output, &format!("{}$", from_base10(product, TEXTBOOK_ALPHABET)));
+}
 
-    for s in input_vec {
-        if s.len() != 0 {
-            let as_base_10 = to_base10(s, ".,?! \t\n\rabcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789");
-            let decrypted = as_base_10.modpow(&d, &n);
-            let decrypted_as_text = from_base10(decrypted, ".,?! \t\n\rabcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789");
-            decrypted_string.push_str(&decrypted_as_text);
+//Rabin keys aren't in the keyring and a Rabin message goes to one key, the blocks are
+//encrypted to it directly
+fn encrypt_rabin(input: InputArgGroup, mut output: Output, pubkeys: Vec<Input>, to: Vec<String>, seed: Option<u64>, force: bool) {
+    if !to.is_empty() {
+        panic!("The keyring only holds RSA keys, give the Rabin public key with -p");
+    }
+    let mut pubkeys = pubkeys.into_iter();
+    let pubkey = mainutil::input_or_default(pubkeys.next(), "./public.txt");
+    if pubkeys.next().is_some() {
+        panic!("Rabin messages have a single recipient");
+    }
+    let key = rabin::RabinPublicKey::from_text(read_key(pubkey).expose());
+    if let Err(e) = key.metadata.check(metadata::Usage::Encrypt, mainutil::now()) {
+        if force {
+            eprintln!("Warning: encrypting anyway, {e}.");
+        } else {
+            panic!("Refusing to encrypt to this key, {e}. Use --force to encrypt anyway.");
+        }
+    }
+
+    let input_string = secret::Secret::new(parse_input_group(input));
+    let mut rng = match seed {
+        Some(seed) => {
+            eprintln!("Warning: --seed makes the ciphertext predictable, use it for tests and demos only.");
+            ChaCha20Rng::seed_from_u64(seed)
         }
+        None => ChaCha20Rng::from_entropy()
+    };
+    match rabin::encrypt(&key, input_string.expose().as_bytes(), &mut rng) {
+        Ok(encrypted) => mainutil::write_output(&mut output, &encrypted),
+        Err(e) => panic!("{e}")
     }
+}
 
+fn decrypt_rabin(input: InputArgGroup, output_file: Output, privkey: Option<Input>) {
+    let input_string = parse_input_group(input);
+    let key = rabin::RabinPrivateKey::from_text(read_key(mainutil::input_or_default(privkey, "./private.txt")).expose());
+    match rabin::decrypt(&key, &input_string) {
+        Ok(decrypted) => write_decrypted(output_file, decrypted.expose()),
+        Err(e) => panic!("Could not decrypt: {e}")
+    }
+}
 
-    let res = output_file.write(format!("{decrypted_string}").as_bytes());
+fn write_decrypted(mut output_file: Output, decrypted: &[u8]) -> ! {
+    let res = output_file.write(decrypted);
     match res {
         Ok(r) => {
             if output_file.path().to_string() == "\"-\"" {
                 exit(0);
             } else {
-                println!("Wrote {r} bytes to output file.");
+                eprintln!("Wrote {r} bytes to output file.");
                 exit(0);
             }
         }
@@ -1,65 +1,157 @@
-use std::{io::Read, usize};
-use clio::Input;
+use std::{io::{Read, Write}, str::FromStr, time::{SystemTime, UNIX_EPOCH}};
+use clio::{Input, Output};
+use num_bigint_dig::BigUint;
+
+use crate::secret::Secret;
 
 use crate::InputArgGroup;
 
 pub fn parse_input_group(input: InputArgGroup) -> String {
-    let input_string;
-
     match input.file {
         Some(mut f) => {
             let mut string_buf = String::new();
             let res = f.read_to_string(&mut string_buf);
             match res {
-                Ok(_) => input_string = string_buf,
+                Ok(_) => string_buf,
                 Err(e) => {panic!("Failed to read input, either specify a file or include input on stdin. Error: {e}")}
             }
         }
         None => {
             match input.input {
-                Some(s) => {
-                    input_string = s;
-                }
+                Some(s) => s,
                 None => {
                     panic!("Somehow, there was no file or input specified.");
                 }
             }
         }
     }
-    
-    return input_string;
 }
 
-pub fn split_string_at_n(n: usize, string: String) -> Vec<String> {
-    let mut input_string_vec = Vec::new();
-    let mut temp_string = String::new();
-    let mut counter = 0usize;
-    for char in string.bytes() {
-        if counter > n {
-            temp_string.push(char::from(char));
-            input_string_vec.push(temp_string.clone());
-            temp_string.clear();
-            counter = 0;
-        } else {
-            temp_string.push(char::from(char));
-            counter += 1;
-        }
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+//None unless text is an even number of hex digits
+pub fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok()).collect()
+}
+
+//The key/value lines between an armor's BEGIN and END lines
+pub fn armored_fields<'a>(text: &'a str, kind: &str) -> Result<Vec<(&'a str, &'a str)>, String> {
+    let begin = format!("-----BEGIN RSA_RUST {kind}-----");
+    let end = format!("-----END RSA_RUST {kind}-----");
+    let inner = match text.trim().strip_prefix(&begin).and_then(|t| t.strip_suffix(&end)) {
+        Some(inner) => inner,
+        None => return Err(format!("Not a {}", kind.to_lowercase()))
+    };
+    inner.lines().map(str::trim).filter(|l| !l.is_empty()).map(|line| match line.split_once(' ') {
+        Some(field) => Ok(field),
+        None => Err(format!("Unexpected line \"{line}\""))
+    }).collect()
+}
+
+pub fn parse_hex(value: &str) -> Result<BigUint, String> {
+    match from_hex(value) {
+        Some(bytes) => Ok(BigUint::from_bytes_be(&bytes)),
+        None => Err(format!("\"{value}\" isn't hex"))
+    }
+}
+
+//Reads and parses one of the armored files
+pub fn read_armored<T: std::str::FromStr<Err = String>>(mut input: Input) -> T {
+    let mut text = Secret::new(String::new());
+    if let Err(e) = input.read_to_string(text.expose_mut()) {
+        panic!("Failed to read {}. Error: {e}", input.path());
+    }
+    match text.expose().parse() {
+        Ok(t) => t,
+        Err(e) => panic!("{}: {e}", input.path())
+    }
+}
+
+pub fn write_output(output: &mut Output, text: &str) {
+    if let Err(e) = output.write_all(text.as_bytes()) {
+        panic!("Failed to write to the output. Error: {e}");
     }
-    if temp_string.len() != 0 {
-        input_string_vec.push(temp_string);
+}
+
+//The given file, or else the default path
+pub fn input_or_default(input: Option<Input>, default: &str) -> Input {
+    match input {
+        Some(i) => i,
+        None => match Input::new(default) {
+            Ok(i) => i,
+            Err(e) => panic!("No file given and could not open {default}: {e}")
+        }
     }
-    return input_string_vec;
 }
 
-pub fn read_key(mut input: Input) -> String {
+//Key files may hold private material, so the text comes back wrapped
+pub fn read_key(mut input: Input) -> Secret<String> {
 
-    let mut ret_text = String::new();
-    let res = input.read_to_string(&mut ret_text);
+    let mut ret_text = Secret::new(String::new());
+    let res = input.read_to_string(ret_text.expose_mut());
     match res {
-        Ok(u) => {println!("Read {u} bytes")},
+        Ok(u) => {eprintln!("Read {u} bytes")},
         Err(e) => {
-            panic!("Could not read from file at {} \n{}", input.path().to_string(), e);
+            panic!("Could not read from file at {} \n{}", input.path(), e);
+        }
+    }
+    ret_text
+}
+
+//Parses the two lines of a key file, n and then the exponent
+pub fn parse_key(key_text: &str) -> (BigUint, BigUint) {
+    let values = parse_key_lines(key_text);
+    let values = values.expose();
+    assert_eq!(values.len(), 2);
+    (values[0].clone(), values[1].clone())
+}
+
+//Parses every line of a key file as a number
+pub fn parse_key_lines(key_text: &str) -> Secret<Vec<BigUint>> {
+    let names = ["n", "the exponent", "e", "p", "q", "dP", "dQ", "qInv"];
+    let mut values = Secret::new(Vec::new());
+    for (i, line) in key_text.split('\n').enumerate() {
+        match BigUint::from_str(line.trim()) {
+            Ok(v) => values.expose_mut().push(v),
+            Err(e) => {
+                let name = names.get(i).copied().unwrap_or("a value");
+                panic!("Could not parse {name} from the provided key file! Error: {e}");
+            }
         }
     }
-    return ret_text;
-}
\ No newline at end of file
+    values
+}
+
+//Seconds since the Unix epoch
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+//"2024-10-19 04:59 UTC" for seconds since the epoch
+pub fn format_time(seconds: u64) -> String {
+    //Days to civil date, from Howard Hinnant's date algorithms
+    let days = (seconds / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+    let minutes = seconds % 86400 / 60;
+    format!("{year:04}-{month:02}-{day:02} {:02}:{:02} UTC", minutes / 60, minutes % 60)
+}
+
+#[test]
+fn times_format_as_utc_dates() {
+    assert_eq!(format_time(0), "1970-01-01 00:00 UTC");
+    assert_eq!(format_time(951782400), "2000-02-29 00:00 UTC");
+    assert_eq!(format_time(1729313999), "2024-10-19 04:59 UTC");
+}
@@ -0,0 +1,145 @@
+use crate::mainutil::format_time;
+
+//What a key may be used for. Certify is signing its own revocation.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Usage {
+    Encrypt,
+    Sign,
+    Certify
+}
+
+const ALL_USAGES: [Usage; 3] = [Usage::Encrypt, Usage::Sign, Usage::Certify];
+
+impl Usage {
+    fn name(self) -> &'static str {
+        match self {
+            Usage::Encrypt => "encrypt",
+            Usage::Sign => "sign",
+            Usage::Certify => "certify"
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Usage> {
+        ALL_USAGES.into_iter().find(|u| u.name() == name)
+    }
+}
+
+//The optional section at the end of a key file, after a blank line:
+//
+//  created: <seconds since the Unix epoch>
+//  expires: <seconds since the Unix epoch>
+//  usage: encrypt, sign, certify
+//  comment: <free text>
+//
+//Every field is optional. Files from before metadata have no section, which means no
+//creation time, no expiry and every usage.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyMetadata {
+    pub created: Option<u64>,
+    pub expires: Option<u64>,
+    pub usages: Vec<Usage>,
+    pub comment: String
+}
+
+impl Default for KeyMetadata {
+    fn default() -> KeyMetadata {
+        KeyMetadata { created: None, expires: None, usages: ALL_USAGES.to_vec(), comment: String::new() }
+    }
+}
+
+//Splits key file text into its numbers and its metadata section, if it has one
+pub fn split_key_text(text: &str) -> (&str, Option<&str>) {
+    let text = text.trim();
+    match text.split_once("\n\n").or_else(|| text.split_once("\r\n\r\n")) {
+        Some((numbers, metadata)) => (numbers.trim_end(), Some(metadata)),
+        None => (text, None)
+    }
+}
+
+impl KeyMetadata {
+    pub fn parse(text: &str) -> Result<KeyMetadata, String> {
+        let mut metadata = KeyMetadata::default();
+        for line in text.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            let (field, value) = match line.split_once(':') {
+                Some((f, v)) => (f.trim(), v.trim()),
+                None => return Err(format!("Expected \"field: value\" in the key metadata, found \"{line}\""))
+            };
+            let time = || value.parse::<u64>().map_err(|e| format!("Bad {field} time \"{value}\": {e}"));
+            match field {
+                "created" => metadata.created = Some(time()?),
+                "expires" => metadata.expires = Some(time()?),
+                "usage" => {
+                    metadata.usages = Vec::new();
+                    for name in value.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+                        match Usage::from_name(name) {
+                            Some(u) => metadata.usages.push(u),
+                            None => return Err(format!("Unknown key usage \"{name}\""))
+                        }
+                    }
+                }
+                "comment" => metadata.comment = value.to_string(),
+                _ => return Err(format!("Unknown key metadata field \"{field}\""))
+            }
+        }
+        Ok(metadata)
+    }
+
+    //The section parse reads back. Empty for keys with default metadata, so older key
+    //files are written back unchanged.
+    pub fn to_text(&self) -> String {
+        if *self == KeyMetadata::default() {
+            return String::new();
+        }
+        let mut lines = Vec::new();
+        if let Some(created) = self.created {
+            lines.push(format!("created: {created}"));
+        }
+        if let Some(expires) = self.expires {
+            lines.push(format!("expires: {expires}"));
+        }
+        lines.push(format!("usage: {}", Vec::from_iter(self.usages.iter().map(|u| u.name())).join(", ")));
+        if !self.comment.is_empty() {
+            lines.push(format!("comment: {}", self.comment));
+        }
+        lines.join("\n")
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires.is_some_and(|e| e <= now)
+    }
+
+    //Why the key can't be used for usage at time now, if it can't
+    pub fn check(&self, usage: Usage, now: u64) -> Result<(), String> {
+        if let Some(expires) = self.expires.filter(|_| self.is_expired(now)) {
+            return Err(format!("the key expired on {}", format_time(expires)));
+        }
+        if !self.usages.contains(&usage) {
+            return Err(format!("the key's usage flags don't include {}", usage.name()));
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn metadata_round_trips() {
+    let metadata = KeyMetadata {
+        created: Some(1700000000),
+        expires: Some(1800000000),
+        usages: vec![Usage::Sign, Usage::Certify],
+        comment: "work key: rotate yearly".to_string()
+    };
+    assert_eq!(KeyMetadata::parse(&metadata.to_text()).unwrap(), metadata);
+    assert_eq!(KeyMetadata::default().to_text(), "");
+    assert_eq!(KeyMetadata::parse("").unwrap(), KeyMetadata::default());
+    assert!(KeyMetadata::parse("usage: encrypt, decrypt").is_err());
+    assert_eq!(split_key_text("3233\n17\n\ncreated: 5\n"), ("3233\n17", Some("created: 5")));
+}
+
+#[test]
+fn checks_expiry_and_usage() {
+    let metadata = KeyMetadata { expires: Some(1000), usages: vec![Usage::Encrypt], ..KeyMetadata::default() };
+    assert!(metadata.check(Usage::Encrypt, 999).is_ok());
+    assert!(metadata.check(Usage::Encrypt, 1000).unwrap_err().contains("expired"));
+    assert!(metadata.check(Usage::Sign, 999).unwrap_err().contains("sign"));
+    assert!(KeyMetadata::default().check(Usage::Certify, u64::MAX).is_ok());
+}
@@ -1,63 +1,120 @@
-use num_bigint_dig::{BigUint, ToBigUint};
-
-//Make function public
+use num_bigint_dig::{BigUint, RandBigInt, ToBigUint};
+use num_integer::Integer;
+use rand::Rng;
+
+use crate::montgomery::MontgomeryContext;
+
+//A composite passes one random round with probability at most 1/4, so the
+//default of 20 bits keeps the 10 rounds this always ran. That worst case bound is far
+//too pessimistic for random candidates, which is what key generation tests; callers
+//that want 2^-128 against adversarial inputs pass 128 to is_prime_miller_rng.
+pub const DEFAULT_ERROR_BITS: u32 = 20;
+
+//The first 12 prime bases are a proof for every n < 2^64, and the first 13 for
+//every n < 3317044064679887385961981 (Sorenson and Webster)
+const BASES_64: [u8; 12] = [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37];
+const BASES_81: [u8; 13] = [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41];
+const BOUND_81: &str = "3317044064679887385961981";
+
+//Make function public. Uses the thread RNG, callers that need reproducible runs pass
+//their own to is_prime_miller_rng.
 pub fn is_prime_miller(n: &BigUint) -> bool {
-    if *n == 1.to_biguint().unwrap() {
+    is_prime_miller_rng(n, DEFAULT_ERROR_BITS, &mut rand::thread_rng())
+}
+
+//Miller-Rabin with witnesses drawn from rng, running enough rounds that a composite
+//passes with probability at most 2^-error_bits. Small n never touch the rng.
+pub fn is_prime_miller_rng<R: Rng + ?Sized>(n: &BigUint, error_bits: u32, rng: &mut R) -> bool {
+    if *n < 2.to_biguint().unwrap() {
         return false;
-    } else if *n == 2.to_biguint().unwrap() {
+    } else if *n == 2.to_biguint().unwrap() || *n == 3.to_biguint().unwrap() {
         return true;
-    } else if *n == 3.to_biguint().unwrap() {
-        return true
+    } else if n.is_even() {
+        return false;
     }
-    for _i in 0..10 {
-        let ret = miller_test(&n);
-        if ret == false {
+
+    if let Some(result) = is_prime_miller_deterministic(n) {
+        return result;
+    }
+
+    let ctx = MontgomeryContext::new(n).unwrap();
+    for _i in 0..rounds_for_error(error_bits) {
+        let ret = miller_test(&ctx, n, rng);
+        if !ret {
             return false
         }
     }
-    return true
+    true
 }
 
+//Number of random rounds needed for a worst case error of 2^-error_bits
+fn rounds_for_error(error_bits: u32) -> u32 {
+    error_bits.div_ceil(2).max(1)
+}
 
-fn miller_test(n: &BigUint) -> bool {
+//Exact answer using the known fixed witness sets, or None if n is too big for them
+pub fn is_prime_miller_deterministic(n: &BigUint) -> Option<bool> {
+    let bases: &[u8] = if n.bits() <= 64 {
+        &BASES_64
+    } else if *n < BOUND_81.parse::<BigUint>().unwrap() {
+        &BASES_81
+    } else {
+        return None;
+    };
+
+    for b in bases {
+        let b = BigUint::from(*b);
+        if *n == b {
+            return Some(true);
+        }
+        if !strong_probable_prime(n, &b) {
+            return Some(false);
+        }
+    }
+    Some(true)
+}
+
+//One round with a witness drawn uniformly from [2, n - 2], expects an odd n > 3
+fn miller_test<R: Rng + ?Sized>(ctx: &MontgomeryContext, n: &BigUint, rng: &mut R) -> bool {
+    let b = rng.gen_biguint_range(&2.to_biguint().unwrap(), &(n - 1u8));
+    strong_probable_prime_with(ctx, n, &b)
+}
+
+//One round of Miller-Rabin with a fixed base b, true if n is a strong probable prime to base b.
+//Expects an odd n > 1.
+pub fn strong_probable_prime(n: &BigUint, b: &BigUint) -> bool {
+    strong_probable_prime_with(&MontgomeryContext::new(n).unwrap(), n, b)
+}
+
+//Same as strong_probable_prime, reusing the Montgomery setup for n across rounds
+fn strong_probable_prime_with(ctx: &MontgomeryContext, n: &BigUint, b: &BigUint) -> bool {
     let two_as_bigint: BigUint = BigUint::from(2u8);
     let zero_as_bigint: BigUint = BigUint::from(0u8);
     let one_as_bigint: BigUint = BigUint::from(1u8);
 
-    if *n == zero_as_bigint {
-        return false;
-    }
-
-    let mut t = n - &one_as_bigint;
+    let n_minus_one = n - &one_as_bigint;
+    let mut t = n_minus_one.clone();
     let mut s: u64 = 0;
 
     while &t % &two_as_bigint == zero_as_bigint {
         t = &t / &two_as_bigint;
         s += 1;
     }
-    // Generate a bigint random number...
-    let b1: u128 = rand::random();
-    let b2: u128 = rand::random();
-    let mut b = (b1.to_biguint().unwrap() * b2.to_biguint().unwrap()) % n;
-
-    if b == zero_as_bigint {
-        b += &one_as_bigint;
-    }
 
     //Pow bigint
-    let ret = b.modpow(&t, &n);
+    let mut x = ctx.modpow(b, &t);
 
-    if ret == one_as_bigint {
+    if x == one_as_bigint {
         return true
     }
 
     for _i in 0..s {
-        if b.modpow(&t, &n) == n-&one_as_bigint {
+        if x == n_minus_one {
             return true
         }
-        t *= &two_as_bigint;
+        x = &x * &x % n;
     }
-    return false
+    false
 }
 
 #[test]
@@ -149,4 +206,41 @@ fn millers_bigint_not_prime() {
     assert!(!is_prime_miller(
         &BigUint::from_str(large_number_3).unwrap()
     ))
-}
\ No newline at end of file
+}
+#[test]
+fn millers_small_inputs_need_no_randomness() {
+    //Any call into this rng would mean a random witness was drawn
+    struct NoRandomness;
+    impl rand::RngCore for NoRandomness {
+        fn next_u32(&mut self) -> u32 { panic!("drew a random witness") }
+        fn next_u64(&mut self) -> u64 { panic!("drew a random witness") }
+        fn fill_bytes(&mut self, _dest: &mut [u8]) { panic!("drew a random witness") }
+        fn try_fill_bytes(&mut self, _dest: &mut [u8]) -> Result<(), rand::Error> { panic!("drew a random witness") }
+    }
+
+    for i in 0..(1u64 << 12) {
+        assert_eq!(is_prime_miller_rng(&i.to_biguint().unwrap(), DEFAULT_ERROR_BITS, &mut NoRandomness), primes::is_prime(i));
+    }
+
+    //Largest 64 bit prime, a strong pseudoprime to bases 2..37, and a prime just above 2^64
+    assert!(is_prime_miller_rng(&18446744073709551557u64.to_biguint().unwrap(), DEFAULT_ERROR_BITS, &mut NoRandomness));
+    assert!(!is_prime_miller_rng(&3825123056546413051u64.to_biguint().unwrap(), DEFAULT_ERROR_BITS, &mut NoRandomness));
+    assert!(is_prime_miller_rng(&18446744073709551629u128.to_biguint().unwrap(), DEFAULT_ERROR_BITS, &mut NoRandomness));
+}
+
+#[test]
+fn millers_rounds_scale_with_error() {
+    assert_eq!(rounds_for_error(0), 1);
+    assert_eq!(rounds_for_error(20), 10);
+    assert_eq!(rounds_for_error(81), 41);
+    assert_eq!(rounds_for_error(DEFAULT_ERROR_BITS), 10);
+    assert_eq!(rounds_for_error(128), 64);
+}
+
+#[test]
+fn millers_even_numbers_are_composite() {
+    use std::str::FromStr;
+    let even = BigUint::from_str("643808006803554439230129854961492699151386107534013432918073439524138264842370630061369715394739134090922937332590384720397133335969549256322620979036686633213903952966175107096769180017646161851573147596390154").unwrap();
+    assert!(!is_prime_miller(&even));
+    assert!(!is_prime_miller(&4u8.to_biguint().unwrap()));
+}
@@ -0,0 +1,380 @@
+use num_bigint_dig::BigUint;
+use num_integer::Integer;
+use num_traits::One;
+use zeroize::Zeroize;
+
+//Everything modpow needs for one odd modulus, worked out once and reused for every exponentiation.
+//Numbers are little endian 64 bit limbs, all exactly as long as the modulus.
+#[derive(Debug, Clone)]
+pub struct MontgomeryContext {
+    modulus: BigUint,
+    n: Vec<u64>,
+    //-n^-1 mod 2^64
+    n_prime: u64,
+    //R^2 mod n with R = 2^(64 * limbs), used to move numbers into Montgomery form
+    r2: Vec<u64>,
+    //R mod n, which is 1 in Montgomery form
+    one: Vec<u64>
+}
+
+//A context for one of the primes of a private key gives the prime away
+impl Zeroize for MontgomeryContext {
+    fn zeroize(&mut self) {
+        self.modulus.zeroize();
+        self.n.zeroize();
+        self.n_prime.zeroize();
+        self.r2.zeroize();
+        self.one.zeroize();
+    }
+}
+
+fn to_limbs(x: &BigUint, len: usize) -> Vec<u64> {
+    let mut limbs = vec![0u64; len];
+    for (i, chunk) in x.to_bytes_le().chunks(8).enumerate() {
+        let mut bytes = [0u8; 8];
+        bytes[..chunk.len()].copy_from_slice(chunk);
+        limbs[i] = u64::from_le_bytes(bytes);
+    }
+    limbs
+}
+
+fn from_limbs(limbs: &[u64]) -> BigUint {
+    let bytes = Vec::from_iter(limbs.iter().flat_map(|l| l.to_le_bytes()));
+    BigUint::from_bytes_le(&bytes)
+}
+
+//out = value - n if value (with its extra top word) is at least n, otherwise value.
+//Always does the subtraction and picks the result with a mask, so the timing doesn't
+//reveal which case happened.
+fn reduce_once(out: &mut [u64], value: &[u64], top: u64, n: &[u64]) {
+    let mut borrow = 0u64;
+    for ((o, &v), &m) in out.iter_mut().zip(value).zip(n) {
+        let (d1, b1) = v.overflowing_sub(m);
+        let (d2, b2) = d1.overflowing_sub(borrow);
+        *o = d2;
+        borrow = (b1 | b2) as u64;
+    }
+    let keep_difference = (top | (borrow ^ 1)) & 1;
+    let mask = keep_difference.wrapping_neg();
+    for (o, &v) in out.iter_mut().zip(value) {
+        *o = (*o & mask) | (v & !mask);
+    }
+}
+
+//Swaps a and b when swap is 1 and leaves them alone when it is 0, without branching on it
+fn conditional_swap(a: &mut [u64], b: &mut [u64], swap: u64) {
+    let mask = swap.wrapping_neg();
+    for (x, y) in a.iter_mut().zip(b.iter_mut()) {
+        let t = (*x ^ *y) & mask;
+        *x ^= t;
+        *y ^= t;
+    }
+}
+
+impl MontgomeryContext {
+    //None for even moduli (and 1), which Montgomery reduction can't handle
+    pub fn new(modulus: &BigUint) -> Option<MontgomeryContext> {
+        if modulus.is_even() || modulus.is_one() {
+            return None;
+        }
+        let len = modulus.bits().div_ceil(64);
+        let n = to_limbs(modulus, len);
+
+        //Newton iteration for n[0]^-1 mod 2^64, each step doubles the correct bits
+        let mut inverse = 1u64;
+        for _ in 0..6 {
+            inverse = inverse.wrapping_mul(2u64.wrapping_sub(n[0].wrapping_mul(inverse)));
+        }
+
+        let r = BigUint::one() << (64 * len);
+        Some(MontgomeryContext {
+            modulus: modulus.clone(),
+            r2: to_limbs(&(&r * &r % modulus), len),
+            one: to_limbs(&(&r % modulus), len),
+            n,
+            n_prime: inverse.wrapping_neg()
+        })
+    }
+
+    //out = a * b * R^-1 mod n, coarsely integrated operand scanning (CIOS).
+    //scratch must hold at least limbs + 2 words.
+    fn mul(&self, a: &[u64], b: &[u64], out: &mut [u64], scratch: &mut [u64]) {
+        let s = self.n.len();
+        let n = &self.n[..s];
+        let a = &a[..s];
+        let t = &mut scratch[..s + 2];
+        t.fill(0);
+
+        for &bi in &b[..s] {
+            let mut carry = 0u64;
+            for (tj, &aj) in t[..s].iter_mut().zip(a) {
+                let sum = *tj as u128 + aj as u128 * bi as u128 + carry as u128;
+                *tj = sum as u64;
+                carry = (sum >> 64) as u64;
+            }
+            let sum = t[s] as u128 + carry as u128;
+            t[s] = sum as u64;
+            t[s + 1] = (sum >> 64) as u64;
+
+            //Add m * n so the lowest word becomes zero, then shift everything down a word
+            let m = t[0].wrapping_mul(self.n_prime);
+            let sum = t[0] as u128 + m as u128 * n[0] as u128;
+            let mut carry = (sum >> 64) as u64;
+            for j in 1..s {
+                let sum = t[j] as u128 + m as u128 * n[j] as u128 + carry as u128;
+                t[j - 1] = sum as u64;
+                carry = (sum >> 64) as u64;
+            }
+            let sum = t[s] as u128 + carry as u128;
+            t[s - 1] = sum as u64;
+            t[s] = t[s + 1] + (sum >> 64) as u64;
+        }
+
+        reduce_once(out, &t[..s], t[s], n);
+    }
+
+    //out = a^2 * R^-1 mod n. Squaring only needs half of the cross products, and squarings
+    //are most of the work in an exponentiation. scratch must hold 2 * limbs + 1 words.
+    fn sqr(&self, a: &[u64], out: &mut [u64], scratch: &mut [u64]) {
+        let s = self.n.len();
+        let n = &self.n[..s];
+        let a = &a[..s];
+        let t = &mut scratch[..2 * s + 1];
+        t.fill(0);
+
+        //Cross products a[i] * a[j] for i < j, doubled, plus the squares on the diagonal
+        for i in 0..s {
+            let mut carry = 0u64;
+            let ai = a[i] as u128;
+            for (tk, &aj) in t[2 * i + 1..i + s].iter_mut().zip(&a[i + 1..]) {
+                let sum = *tk as u128 + ai * aj as u128 + carry as u128;
+                *tk = sum as u64;
+                carry = (sum >> 64) as u64;
+            }
+            t[i + s] = carry;
+        }
+        let mut top = 0u64;
+        for word in t[..2 * s].iter_mut() {
+            let next_top = *word >> 63;
+            *word = (*word << 1) | top;
+            top = next_top;
+        }
+        let mut carry = 0u128;
+        for i in 0..s {
+            let square = a[i] as u128 * a[i] as u128;
+            let low = t[2 * i] as u128 + (square as u64) as u128 + carry;
+            t[2 * i] = low as u64;
+            let high = t[2 * i + 1] as u128 + (square >> 64) + (low >> 64);
+            t[2 * i + 1] = high as u64;
+            carry = high >> 64;
+        }
+
+        //Montgomery reduction of the 2s word product, one word at a time. The carry out of
+        //each row lands exactly on the word the next row finishes on.
+        let mut top_carry = 0u64;
+        for i in 0..s {
+            let m = t[i].wrapping_mul(self.n_prime);
+            let mut carry = 0u64;
+            for (tk, &nj) in t[i..i + s].iter_mut().zip(n) {
+                let sum = *tk as u128 + m as u128 * nj as u128 + carry as u128;
+                *tk = sum as u64;
+                carry = (sum >> 64) as u64;
+            }
+            let sum = t[i + s] as u128 + carry as u128 + top_carry as u128;
+            t[i + s] = sum as u64;
+            top_carry = (sum >> 64) as u64;
+        }
+        t[2 * s] = top_carry;
+
+        reduce_once(out, &t[s..2 * s], t[2 * s], n);
+    }
+
+    //Window width for a sliding window over an exponent of this many bits
+    fn window_bits(exponent_bits: usize) -> usize {
+        match exponent_bits {
+            0..=24 => 1,
+            25..=80 => 3,
+            81..=240 => 4,
+            241..=672 => 5,
+            _ => 6
+        }
+    }
+
+    //base^exponent mod n with a left to right sliding window over the exponent
+    pub fn modpow(&self, base: &BigUint, exponent: &BigUint) -> BigUint {
+        let s = self.n.len();
+        let mut scratch = vec![0u64; 2 * s + 1];
+
+        let base_mont = self.to_montgomery(base, &mut scratch);
+
+        //Odd powers base^1, base^3, ..., base^(2^w - 1) in Montgomery form
+        let width = Self::window_bits(exponent.bits());
+        let mut base_squared = vec![0u64; s];
+        self.sqr(&base_mont, &mut base_squared, &mut scratch);
+        let mut table = vec![base_mont];
+        for i in 1..(1usize << (width - 1)) {
+            let mut next = vec![0u64; s];
+            self.mul(&table[i - 1], &base_squared, &mut next, &mut scratch);
+            table.push(next);
+        }
+
+        let exp_bytes = exponent.to_bytes_le();
+        let bit = |i: usize| (exp_bytes[i / 8] >> (i % 8)) & 1 == 1;
+
+        let mut acc = self.one.clone();
+        let mut tmp = vec![0u64; s];
+        let mut i = exponent.bits();
+        while i > 0 {
+            if !bit(i - 1) {
+                self.sqr(&acc, &mut tmp, &mut scratch);
+                std::mem::swap(&mut acc, &mut tmp);
+                i -= 1;
+                continue;
+            }
+
+            //Longest window of at most width bits that starts at bit i - 1 and ends on a one
+            let mut low = i.saturating_sub(width);
+            while !bit(low) {
+                low += 1;
+            }
+            let mut value = 0usize;
+            for j in (low..i).rev() {
+                value = (value << 1) | bit(j) as usize;
+                self.sqr(&acc, &mut tmp, &mut scratch);
+                std::mem::swap(&mut acc, &mut tmp);
+            }
+            self.mul(&acc, &table[value >> 1], &mut tmp, &mut scratch);
+            std::mem::swap(&mut acc, &mut tmp);
+            i = low;
+        }
+
+        self.to_plain(&acc, &mut scratch)
+    }
+
+    fn to_montgomery(&self, x: &BigUint, scratch: &mut [u64]) -> Vec<u64> {
+        let reduced = to_limbs(&(x % &self.modulus), self.n.len());
+        let mut out = vec![0u64; self.n.len()];
+        self.mul(&reduced, &self.r2, &mut out, scratch);
+        out
+    }
+
+    //Multiplying by plain 1 takes a number out of Montgomery form
+    fn to_plain(&self, x: &[u64], scratch: &mut [u64]) -> BigUint {
+        let mut plain_one = vec![0u64; self.n.len()];
+        plain_one[0] = 1;
+        let mut out = vec![0u64; self.n.len()];
+        self.mul(x, &plain_one, &mut out, scratch);
+        from_limbs(&out)
+    }
+
+    //base^exponent mod n with a Montgomery ladder over exactly exponent_bits bits. Every bit costs
+    //one multiplication and one squaring whatever its value, and the registers are swapped with
+    //masks rather than branches, so the work done only depends on exponent_bits. Used for
+    //private key operations, where the exponent is secret.
+    pub fn modpow_ladder(&self, base: &BigUint, exponent: &BigUint, exponent_bits: usize) -> BigUint {
+        assert!(exponent.bits() <= exponent_bits, "exponent is longer than the ladder");
+        let s = self.n.len();
+        let mut scratch = vec![0u64; 2 * s + 1];
+
+        let mut r0 = self.one.clone();
+        let mut r1 = self.to_montgomery(base, &mut scratch);
+        let mut tmp = vec![0u64; s];
+
+        let exp_bytes = exponent.to_bytes_le();
+        for i in (0..exponent_bits).rev() {
+            let bit = (exp_bytes.get(i / 8).copied().unwrap_or(0) >> (i % 8)) as u64 & 1;
+            conditional_swap(&mut r0, &mut r1, bit);
+            self.mul(&r0, &r1, &mut tmp, &mut scratch);
+            std::mem::swap(&mut r1, &mut tmp);
+            self.sqr(&r0, &mut tmp, &mut scratch);
+            std::mem::swap(&mut r0, &mut tmp);
+            conditional_swap(&mut r0, &mut r1, bit);
+        }
+
+        self.to_plain(&r0, &mut scratch)
+    }
+}
+
+#[test]
+fn montgomery_matches_modpow() {
+    use num_bigint_dig::RandBigInt;
+
+    let mut rng = rand::thread_rng();
+    for bits in [2, 7, 63, 64, 65, 127, 128, 512, 1000, 2048] {
+        for _ in 0..5 {
+            let n = rng.gen_biguint(bits) | BigUint::one();
+            if n.is_one() {
+                continue;
+            }
+            let ctx = MontgomeryContext::new(&n).unwrap();
+            for exp_bits in [0, 1, 5, 17, 64, bits] {
+                let base = rng.gen_biguint(bits + 10);
+                let exponent = rng.gen_biguint(exp_bits);
+                assert_eq!(ctx.modpow(&base, &exponent), base.modpow(&exponent, &n), "{base}^{exponent} mod {n}");
+            }
+        }
+    }
+}
+
+#[test]
+fn ladder_matches_modpow() {
+    use num_bigint_dig::RandBigInt;
+
+    let mut rng = rand::thread_rng();
+    for bits in [3, 64, 65, 300, 1024] {
+        let n = rng.gen_biguint(bits) | BigUint::one() | (BigUint::one() << (bits - 1));
+        let ctx = MontgomeryContext::new(&n).unwrap();
+        for exp_bits in [0, 1, 9, bits] {
+            let base = rng.gen_biguint(bits);
+            let exponent = rng.gen_biguint(exp_bits);
+            assert_eq!(ctx.modpow_ladder(&base, &exponent, bits + 8), base.modpow(&exponent, &n));
+        }
+    }
+}
+
+#[test]
+fn montgomery_edge_cases() {
+    use num_traits::Zero;
+
+    let n = BigUint::from(u64::MAX);
+    let ctx = MontgomeryContext::new(&n).unwrap();
+    assert_eq!(ctx.modpow(&BigUint::zero(), &BigUint::zero()), BigUint::one());
+    assert_eq!(ctx.modpow(&BigUint::zero(), &BigUint::from(5u8)), BigUint::zero());
+    assert_eq!(ctx.modpow(&(&n - 1u8), &BigUint::from(2u8)), BigUint::one());
+    assert_eq!(ctx.modpow(&n, &BigUint::from(3u8)), BigUint::zero());
+
+    assert!(MontgomeryContext::new(&BigUint::from(10u8)).is_none());
+    assert!(MontgomeryContext::new(&BigUint::one()).is_none());
+}
+
+//cargo test --release -- --ignored --nocapture bench_montgomery
+#[test]
+#[ignore]
+fn bench_montgomery() {
+    use num_bigint_dig::RandBigInt;
+    use std::time::Instant;
+
+    let mut rng = rand::thread_rng();
+    for (bits, rounds) in [(1024, 200), (2048, 50), (4096, 10)] {
+        let n = rng.gen_biguint(bits) | BigUint::one() | (BigUint::one() << (bits - 1));
+        let inputs = Vec::from_iter((0..rounds).map(|_| (rng.gen_biguint(bits) % &n, rng.gen_biguint(bits))));
+
+        //Best of three, the timings are noisy on shared machines
+        let mut plain = f64::MAX;
+        let mut montgomery = f64::MAX;
+        for _ in 0..3 {
+            let now = Instant::now();
+            let expected = Vec::from_iter(inputs.iter().map(|(b, e)| b.modpow(e, &n)));
+            plain = plain.min(now.elapsed().as_secs_f64() / rounds as f64);
+
+            let now = Instant::now();
+            let ctx = MontgomeryContext::new(&n).unwrap();
+            let ours = Vec::from_iter(inputs.iter().map(|(b, e)| ctx.modpow(b, e)));
+            montgomery = montgomery.min(now.elapsed().as_secs_f64() / rounds as f64);
+
+            assert_eq!(expected, ours);
+        }
+        println!("{bits} bits: num-bigint-dig {:.2}ms, montgomery {:.2}ms per modpow, {:.1}x faster",
+            plain * 1000.0, montgomery * 1000.0, plain / montgomery);
+    }
+}
@@ -0,0 +1,107 @@
+use rand_core::CryptoRngCore;
+use sha2::{Digest, Sha256};
+
+use crate::secret::Secret;
+
+//RSAES-OAEP encoding (RFC 8017 section 7.1) with SHA-256 for both the hash and MGF1,
+//and an empty label. Padding a block this way makes RSA randomized and non-malleable.
+const HASH_LEN: usize = 32;
+
+//Longest message that fits in a k byte modulus
+pub fn max_message_len(k: usize) -> usize {
+    k.saturating_sub(2 * HASH_LEN + 2)
+}
+
+//MGF1 with SHA-256, xored into out
+pub fn mgf1_xor(out: &mut [u8], seed: &[u8]) {
+    for (counter, chunk) in out.chunks_mut(HASH_LEN).enumerate() {
+        let mask = Sha256::new()
+            .chain_update(seed)
+            .chain_update((counter as u32).to_be_bytes())
+            .finalize();
+        for (o, m) in chunk.iter_mut().zip(mask) {
+            *o ^= m;
+        }
+    }
+}
+
+//The k byte encoded message for message, None if it's too long for k
+pub fn encode<R: CryptoRngCore + ?Sized>(message: &[u8], k: usize, rng: &mut R) -> Option<Secret<Vec<u8>>> {
+    if message.len() > max_message_len(k) {
+        return None;
+    }
+
+    //EM = 0x00 || maskedSeed || maskedDB, DB = lHash || PS || 0x01 || M
+    let mut em = Secret::new(vec![0u8; k]);
+    let (seed, db) = em.expose_mut()[1..].split_at_mut(HASH_LEN);
+    rng.fill_bytes(seed);
+    db[..HASH_LEN].copy_from_slice(&Sha256::digest([]));
+    db[db.len() - message.len() - 1] = 1;
+    let message_start = db.len() - message.len();
+    db[message_start..].copy_from_slice(message);
+
+    mgf1_xor(db, seed);
+    mgf1_xor(seed, db);
+    Some(em)
+}
+
+//The message inside a k byte encoded message, None if it isn't a valid encoding. Every
+//check is folded into one flag before deciding, so the failure doesn't say which one failed.
+pub fn decode(em: &[u8], k: usize) -> Option<Secret<Vec<u8>>> {
+    if em.len() != k || k < 2 * HASH_LEN + 2 {
+        return None;
+    }
+
+    let mut em = Secret::new(em.to_vec());
+    let leading = em.expose()[0];
+    let (seed, db) = em.expose_mut()[1..].split_at_mut(HASH_LEN);
+    mgf1_xor(seed, db);
+    mgf1_xor(db, seed);
+
+    let mut bad = leading;
+    for (a, b) in db[..HASH_LEN].iter().zip(Sha256::digest([])) {
+        bad |= a ^ b;
+    }
+
+    //Position of the 0x01 after the zero padding, found without stopping early
+    let mut separator = 0usize;
+    let mut looking = 1u8;
+    for (i, byte) in db.iter().enumerate().skip(HASH_LEN) {
+        let is_one = (*byte == 1) as u8;
+        let is_zero = (*byte == 0) as u8;
+        separator |= i * (looking & is_one) as usize;
+        bad |= looking & !is_one & !is_zero & 1;
+        looking &= !is_one & 1;
+    }
+    bad |= looking;
+
+    if bad != 0 {
+        return None;
+    }
+    Some(Secret::new(db[separator + 1..].to_vec()))
+}
+
+#[test]
+fn oaep_round_trip() {
+    let mut rng = rand::thread_rng();
+    let k = 128;
+    for len in [0, 1, 32, max_message_len(k)] {
+        let message = Vec::from_iter((0..len).map(|i| i as u8));
+        let first = encode(&message, k, &mut rng).unwrap();
+        let second = encode(&message, k, &mut rng).unwrap();
+        assert_ne!(first.expose(), second.expose());
+        assert_eq!(decode(first.expose(), k).unwrap().expose(), &message);
+    }
+    assert!(encode(&[0u8; 63], k, &mut rng).is_none());
+}
+
+#[test]
+fn oaep_rejects_tampering() {
+    let mut rng = rand::thread_rng();
+    let encoded = encode(b"content key", 128, &mut rng).unwrap();
+    for i in 0..128 {
+        let mut tampered = encoded.expose().clone();
+        tampered[i] ^= 0x40;
+        assert!(decode(&tampered, 128).is_none(), "accepted a change at byte {i}");
+    }
+}
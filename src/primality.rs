@@ -0,0 +1,249 @@
+use num_bigint_dig::BigUint;
+use num_integer::Integer;
+use num_traits::{One, ToPrimitive, Zero};
+use rand_core::CryptoRngCore;
+
+use crate::millers;
+
+//Anything that can decide whether a number is (probably) prime. Randomized tests draw
+//from rng, so a seeded rng makes a run reproducible.
+pub trait PrimalityTest {
+    fn is_prime(&self, n: &BigUint, rng: &mut dyn CryptoRngCore) -> bool;
+}
+
+//Random base Miller-Rabin, see millers::is_prime_miller
+pub struct MillerRabin;
+
+//Strong base 2 Miller-Rabin followed by a strong Lucas test. No counterexample is known.
+pub struct BailliePsw;
+
+//Fixed base Miller-Rabin, which is a proof below ~3.3 * 10^24. Falls back to Baillie-PSW above that.
+pub struct Deterministic;
+
+impl PrimalityTest for MillerRabin {
+    fn is_prime(&self, n: &BigUint, rng: &mut dyn CryptoRngCore) -> bool {
+        millers::is_prime_miller_rng(n, millers::DEFAULT_ERROR_BITS, rng)
+    }
+}
+
+impl PrimalityTest for BailliePsw {
+    fn is_prime(&self, n: &BigUint, _rng: &mut dyn CryptoRngCore) -> bool {
+        if let Some(small) = small_cases(n) {
+            return small;
+        }
+        millers::strong_probable_prime(n, &BigUint::from(2u8)) && strong_lucas_probable_prime(n)
+    }
+}
+
+impl PrimalityTest for Deterministic {
+    fn is_prime(&self, n: &BigUint, rng: &mut dyn CryptoRngCore) -> bool {
+        if let Some(small) = small_cases(n) {
+            return small;
+        }
+        match millers::is_prime_miller_deterministic(n) {
+            Some(result) => result,
+            None => BailliePsw.is_prime(n, rng)
+        }
+    }
+}
+
+//Which test generate-keys should use to check candidates
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default)]
+pub enum PrimalityTestKind {
+    #[default]
+    MillerRabin,
+    BailliePsw,
+    Deterministic
+}
+
+impl PrimalityTestKind {
+    pub fn test(self) -> Box<dyn PrimalityTest + Send + Sync> {
+        match self {
+            PrimalityTestKind::MillerRabin => Box::new(MillerRabin),
+            PrimalityTestKind::BailliePsw => Box::new(BailliePsw),
+            PrimalityTestKind::Deterministic => Box::new(Deterministic)
+        }
+    }
+}
+
+//Settles n < 2 and multiples of tiny primes so the real tests only see odd n with no factor below 50
+fn small_cases(n: &BigUint) -> Option<bool> {
+    if *n < BigUint::from(2u8) {
+        return Some(false);
+    }
+    for p in [2u8, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47] {
+        if (n % BigUint::from(p)).is_zero() {
+            return Some(*n == BigUint::from(p));
+        }
+    }
+    if *n < BigUint::from(53u32 * 53) {
+        return Some(true);
+    }
+    None
+}
+
+//Jacobi symbol (a/n) for odd n
+pub fn jacobi(a: &BigUint, n: &BigUint) -> i8 {
+    let mut a = a % n;
+    let mut n = n.clone();
+    let mut result = 1i8;
+    let three = BigUint::from(3u8);
+    let eight = BigUint::from(8u8);
+
+    while !a.is_zero() {
+        while a.is_even() {
+            a >>= 1;
+            let r = (&n % &eight).to_u8().unwrap();
+            if r == 3 || r == 5 {
+                result = -result;
+            }
+        }
+        std::mem::swap(&mut a, &mut n);
+        if &a % 4u8 == three && &n % 4u8 == three {
+            result = -result;
+        }
+        a = &a % &n;
+    }
+
+    if n.is_one() {
+        result
+    } else {
+        0
+    }
+}
+
+//Halves x modulo an odd n
+fn half_mod(x: BigUint, n: &BigUint) -> BigUint {
+    if x.is_odd() {
+        (x + n) >> 1
+    } else {
+        x >> 1
+    }
+}
+
+//Strong Lucas probable prime test with Selfridge's parameters (FIPS 186-4 C.3.3).
+//Expects an odd n > 2 with no tiny factors.
+pub fn strong_lucas_probable_prime(n: &BigUint) -> bool {
+    let one = BigUint::one();
+    let two = BigUint::from(2u8);
+
+    let root = n.sqrt();
+    if &root * &root == *n {
+        return false;
+    }
+
+    //Find D in 5, -7, 9, -11, ... with (D/n) = -1, keeping D as a residue mod n
+    let mut magnitude = 5u64;
+    let mut negative = false;
+    let d = loop {
+        let abs = BigUint::from(magnitude);
+        let d = if negative { n - (&abs % n) } else { &abs % n };
+        match jacobi(&d, n) {
+            -1 => break d,
+            0 if abs < *n => return false,
+            _ => ()
+        }
+        magnitude += 2;
+        negative = !negative;
+    };
+
+    //P = 1, Q = (1 - D) / 4
+    let q = half_mod(half_mod((&one + n - &d) % n, n), n);
+
+    let n_plus_one = n + &one;
+    let mut k = n_plus_one.clone();
+    let mut s = 0u64;
+    while k.is_even() {
+        k >>= 1;
+        s += 1;
+    }
+
+    //Left to right binary ladder over the bits of k, tracking U_j, V_j and Q^j
+    let mut u = BigUint::zero();
+    let mut v = two.clone();
+    let mut q_k = BigUint::one();
+    for i in (0..k.bits()).rev() {
+        u = &u * &v % n;
+        v = (&v * &v + n * &two - (&two * &q_k) % n) % n;
+        q_k = &q_k * &q_k % n;
+        if ((&k >> i) & &one).is_one() {
+            let new_u = half_mod((&u + &v) % n, n);
+            let new_v = half_mod((&d * &u + &v) % n, n);
+            u = new_u;
+            v = new_v;
+            q_k = &q_k * &q % n;
+        }
+    }
+
+    if u.is_zero() || v.is_zero() {
+        return true;
+    }
+    for _ in 1..s {
+        v = (&v * &v + n * &two - (&two * &q_k) % n) % n;
+        if v.is_zero() {
+            return true;
+        }
+        q_k = &q_k * &q_k % n;
+    }
+    false
+}
+
+#[test]
+fn tests_agree_with_primes_crate() {
+    let mut rng = rand::thread_rng();
+    let tests: [Box<dyn PrimalityTest>; 3] = [Box::new(MillerRabin), Box::new(BailliePsw), Box::new(Deterministic)];
+    for i in 0..(1u64 << 14) {
+        let expected = primes::is_prime(i);
+        for test in &tests {
+            assert_eq!(test.is_prime(&BigUint::from(i), &mut rng), expected, "disagreement at {i}");
+        }
+    }
+}
+
+#[test]
+fn pseudoprimes_are_rejected() {
+    //Carmichael numbers, strong pseudoprimes to base 2 and strong Lucas pseudoprimes
+    let carmichael = [561u64, 1105, 1729, 2465, 2821, 6601, 8911, 10585, 15841, 29341];
+    let strong_base_2 = [2047u64, 3277, 4033, 4681, 8321, 15841, 29341, 42799, 49141, 52633, 65281, 74665];
+    let strong_lucas = [5459u64, 5777, 10877, 16109, 18971, 22499, 24569, 25199, 40309, 58519, 75077, 97439];
+    //Strong pseudoprime to every base up to 37
+    let big_spsp = 3825123056546413051u64;
+    let mut rng = rand::thread_rng();
+
+    for n in carmichael.iter().chain(&strong_base_2).chain(&strong_lucas).chain(&[big_spsp]) {
+        let n = BigUint::from(*n);
+        assert!(!BailliePsw.is_prime(&n, &mut rng), "Baillie-PSW accepted {n}");
+        assert!(!Deterministic.is_prime(&n, &mut rng), "deterministic test accepted {n}");
+    }
+
+    for n in strong_base_2 {
+        assert!(millers::strong_probable_prime(&BigUint::from(n), &BigUint::from(2u8)));
+    }
+    for n in strong_lucas {
+        assert!(strong_lucas_probable_prime(&BigUint::from(n)));
+    }
+}
+
+#[test]
+fn baillie_psw_large_primes() {
+    use std::str::FromStr;
+
+    let prime = BigUint::from_str("643808006803554439230129854961492699151386107534013432918073439524138264842370630061369715394739134090922937332590384720397133335969549256322620979036686633213903952966175107096769180017646161851573147596390153").unwrap();
+    let mersenne_127 = (BigUint::one() << 127) - BigUint::one();
+    let mut rng = rand::thread_rng();
+
+    assert!(BailliePsw.is_prime(&prime, &mut rng));
+    assert!(Deterministic.is_prime(&prime, &mut rng));
+    assert!(BailliePsw.is_prime(&mersenne_127, &mut rng));
+    assert!(!BailliePsw.is_prime(&(&prime * &mersenne_127), &mut rng));
+    assert!(!BailliePsw.is_prime(&(&mersenne_127 * &mersenne_127), &mut rng));
+}
+
+#[test]
+fn jacobi_matches_known_values() {
+    assert_eq!(jacobi(&BigUint::from(1001u32), &BigUint::from(9907u32)), -1);
+    assert_eq!(jacobi(&BigUint::from(19u32), &BigUint::from(45u32)), 1);
+    assert_eq!(jacobi(&BigUint::from(8u32), &BigUint::from(21u32)), -1);
+    assert_eq!(jacobi(&BigUint::from(5u32), &BigUint::from(21u32)), 1);
+    assert_eq!(jacobi(&BigUint::from(6u32), &BigUint::from(21u32)), 0);
+}
@@ -0,0 +1,145 @@
+use std::process::exit;
+
+use num_bigint_dig::BigUint;
+use num_traits::{Num, Zero};
+
+use crate::{base::{from_base10, to_base10}, primality::PrimalityTestKind, sieve};
+
+//How numbers are read from the command line and printed back, and how they are tested
+#[derive(clap::Args, Debug)]
+pub struct NumberArgs {
+    /// Read and print numbers as text in this alphabet, the same encoding encrypt uses for
+    /// textbook blocks. Without it numbers are decimal, or hex with a 0x prefix.
+    #[clap(long)]
+    alphabet: Option<String>,
+
+    /// Primality test to use.
+    #[clap(long, value_enum, default_value_t)]
+    primality_test: PrimalityTestKind
+}
+
+impl NumberArgs {
+    //The --alphabet, which needs at least two distinct digits to write numbers in
+    fn alphabet(&self) -> Result<Option<&str>, String> {
+        match &self.alphabet {
+            Some(alphabet) if alphabet.chars().count() < 2 => Err("The alphabet needs at least two characters".to_string()),
+            Some(alphabet) if alphabet.chars().enumerate().any(|(i, c)| alphabet.chars().skip(i + 1).any(|d| d == c)) => {
+                Err("The alphabet can't have the same character twice".to_string())
+            }
+            Some(alphabet) => Ok(Some(alphabet)),
+            None => Ok(None)
+        }
+    }
+
+    fn parse(&self, text: &str) -> Result<BigUint, String> {
+        if let Some(alphabet) = self.alphabet()? {
+            //to_base10 skips characters it doesn't know, which would turn a typo into another number
+            if let Some(c) = text.chars().find(|c| !alphabet.contains(*c)) {
+                return Err(format!("\"{text}\" has {c:?}, which isn't in the alphabet"));
+            }
+            if text.is_empty() {
+                return Err("An empty string isn't a number".to_string());
+            }
+            return Ok(to_base10(text, alphabet));
+        }
+        let parsed = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+            Some(hex) => BigUint::from_str_radix(hex, 16),
+            None => BigUint::from_str_radix(text, 10)
+        };
+        match parsed {
+            Ok(n) => Ok(n),
+            Err(e) => Err(format!("Could not parse \"{text}\" as a number! Error: {e}"))
+        }
+    }
+
+    fn value(&self, text: &str) -> BigUint {
+        match self.parse(text) {
+            Ok(n) => n,
+            Err(e) => panic!("{e}")
+        }
+    }
+
+    //n in the notation text was given in
+    fn format(&self, n: &BigUint, text: &str) -> String {
+        match self.alphabet() {
+            //from_base10 writes no digits at all for 0
+            Ok(Some(alphabet)) if n.is_zero() => alphabet.chars().take(1).collect(),
+            Ok(Some(alphabet)) => from_base10(n.clone(), alphabet),
+            Ok(None) if text.starts_with("0x") || text.starts_with("0X") => format!("0x{}", n.to_str_radix(16)),
+            Ok(None) => n.to_string(),
+            Err(e) => panic!("{e}")
+        }
+    }
+}
+
+//The is-prime subcommand. Exits with 1 if n is composite.
+pub fn is_prime(n: String, args: NumberArgs) {
+    let value = args.value(&n);
+    if args.primality_test.test().is_prime(&value, &mut rand::thread_rng()) {
+        println!("{n} is prime");
+    } else {
+        println!("{n} is composite");
+        exit(1);
+    }
+}
+
+//The next-prime subcommand, the smallest prime bigger than n
+pub fn next_prime(n: String, args: NumberArgs) {
+    let start = args.value(&n) + 1u8;
+    let prime = sieve::next_prime(&start, args.primality_test.test().as_ref(), &mut rand::thread_rng());
+    println!("{}", args.format(&prime, &n));
+}
+
+//The prev-prime subcommand, the largest prime smaller than n
+pub fn prev_prime(n: String, args: NumberArgs) {
+    let value = args.value(&n);
+    if value.is_zero() {
+        panic!("There is no prime below {n}");
+    }
+    match sieve::prev_prime(&(value - 1u8), args.primality_test.test().as_ref(), &mut rand::thread_rng()) {
+        Some(prime) => println!("{}", args.format(&prime, &n)),
+        None => panic!("There is no prime below {n}")
+    }
+}
+
+//The random-prime subcommand. Printed in hex with --hex, otherwise like --alphabet says.
+pub fn random_prime(bits: usize, hex: bool, args: NumberArgs) {
+    let prime = sieve::random_prime(bits, args.primality_test.test().as_ref(), &mut rand::thread_rng());
+    println!("{}", args.format(&prime, if hex { "0x" } else { "" }));
+}
+
+//The prime-count subcommand, the number of primes in [a, b]
+pub fn prime_count(a: String, b: String, args: NumberArgs) {
+    let (low, high) = (args.value(&a), args.value(&b));
+    println!("{}", sieve::count_primes(&low, &high, args.primality_test.test().as_ref(), &mut rand::thread_rng()));
+}
+
+#[test]
+fn numbers_round_trip_in_every_notation() {
+    let args = |alphabet: Option<&str>| NumberArgs { alphabet: alphabet.map(str::to_string), primality_test: PrimalityTestKind::default() };
+
+    let decimal = args(None);
+    for text in ["0", "7", "18446744073709551629"] {
+        assert_eq!(decimal.format(&decimal.parse(text).unwrap(), text), text);
+    }
+    for text in ["0x0", "0xff", "0x10000000000000000"] {
+        assert_eq!(decimal.format(&decimal.parse(text).unwrap(), text), text);
+    }
+    assert_eq!(decimal.parse("0XFF").unwrap(), BigUint::from(255u8));
+    assert!(decimal.parse("12a").is_err());
+    assert!(decimal.parse("").is_err());
+
+    let binary = args(Some("01"));
+    for text in ["0", "1", "1011"] {
+        assert_eq!(binary.format(&binary.parse(text).unwrap(), text), text);
+    }
+    assert_eq!(binary.parse("1011").unwrap(), BigUint::from(11u8));
+    assert!(binary.parse("1021").is_err());
+    assert!(binary.parse("").is_err());
+
+    let letters = args(Some("abc"));
+    assert_eq!(letters.parse("ba").unwrap(), BigUint::from(3u8));
+    assert_eq!(letters.format(&BigUint::zero(), ""), "a");
+    assert!(args(Some("a")).parse("a").is_err());
+    assert!(args(Some("aba")).parse("a").is_err());
+}
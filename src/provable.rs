@@ -0,0 +1,328 @@
+use std::{io::Read, process::exit, str::FromStr};
+
+use clio::Input;
+use num_bigint_dig::BigUint;
+use num_integer::Integer;
+use num_traits::{One, Zero};
+use sha2::{Digest, Sha256};
+use zeroize::Zeroize;
+
+//Output length of SHA-256 in bits, "outlen" in FIPS 186-4
+const OUTLEN: usize = 256;
+
+//Seeds are hashed as fixed width big endian strings, "seedlen" in FIPS 186-4
+const SEED_BYTES: usize = 32;
+
+//Primes below this are small enough to prove by trial division
+const SMALL_PRIME_BITS: usize = 33;
+
+//One step of a Pocklington chain. factor is a prime dividing prime - 1 with factor^2 > prime,
+//and witness^(prime - 1) = 1 while gcd(witness^((prime - 1) / factor) - 1, prime) = 1
+#[derive(Debug, Clone, PartialEq)]
+pub struct CertificateStep {
+    pub prime: BigUint,
+    pub factor: BigUint,
+    pub witness: BigUint
+}
+
+//A chain of Pocklington steps, starting from a prime small enough to check by trial division
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrimeCertificate {
+    pub base: BigUint,
+    pub steps: Vec<CertificateStep>
+}
+
+impl PrimeCertificate {
+    //The prime this certificate proves
+    pub fn prime(&self) -> &BigUint {
+        match self.steps.last() {
+            Some(step) => &step.prime,
+            None => &self.base
+        }
+    }
+
+    //Checks every link of the chain, returns true only if the whole certificate holds
+    pub fn verify(&self) -> bool {
+        if self.base.bits() > SMALL_PRIME_BITS || !is_small_prime(&self.base) {
+            return false;
+        }
+
+        let mut previous = &self.base;
+        for step in &self.steps {
+            if step.factor != *previous || !pocklington(step) {
+                return false;
+            }
+            previous = &step.prime;
+        }
+        true
+    }
+}
+
+//A certificate next to a private key holds p or q, so it gets wiped like one
+impl Zeroize for PrimeCertificate {
+    fn zeroize(&mut self) {
+        self.base.zeroize();
+        for step in self.steps.iter_mut() {
+            step.prime.zeroize();
+            step.factor.zeroize();
+            step.witness.zeroize();
+        }
+        self.steps.clear();
+    }
+}
+
+impl std::fmt::Display for PrimeCertificate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.base)?;
+        for step in &self.steps {
+            write!(f, "\n{} {} {}", step.prime, step.factor, step.witness)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for PrimeCertificate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut lines = s.lines().map(str::trim).filter(|l| !l.is_empty());
+
+        let base = match lines.next() {
+            Some(l) => parse_number(l)?,
+            None => return Err("Certificate is empty".to_string())
+        };
+
+        let mut steps = Vec::new();
+        for line in lines {
+            let fields = Vec::from_iter(line.split_whitespace());
+            if fields.len() != 3 {
+                return Err(format!("Expected \"prime factor witness\", found \"{line}\""));
+            }
+            steps.push(CertificateStep {
+                prime: parse_number(fields[0])?,
+                factor: parse_number(fields[1])?,
+                witness: parse_number(fields[2])?
+            });
+        }
+
+        Ok(PrimeCertificate { base, steps })
+    }
+}
+
+fn parse_number(s: &str) -> Result<BigUint, String> {
+    BigUint::from_str(s).map_err(|e| format!("Could not parse \"{s}\" in certificate: {e}"))
+}
+
+//Pocklington's criterion for a single step of the chain
+fn pocklington(step: &CertificateStep) -> bool {
+    let one = BigUint::one();
+    let two = BigUint::from(2u8);
+    let c = &step.prime;
+
+    if *c <= two || &step.factor * &step.factor <= *c {
+        return false;
+    }
+    if step.witness < two || step.witness >= c - &one {
+        return false;
+    }
+
+    let c_minus_one = c - &one;
+    if !(&c_minus_one % &step.factor).is_zero() {
+        return false;
+    }
+
+    let z = step.witness.modpow(&(&c_minus_one / &step.factor), c);
+    if z.is_zero() || !(&z - &one).gcd(c).is_one() {
+        return false;
+    }
+    z.modpow(&step.factor, c).is_one()
+}
+
+fn is_small_prime(n: &BigUint) -> bool {
+    let n = match n.to_string().parse::<u64>() {
+        Ok(n) => n,
+        Err(_) => return false
+    };
+    if n < 2 {
+        return false;
+    }
+    let mut i = 2u64;
+    while i * i <= n {
+        if n % i == 0 {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+fn hash(seed: &BigUint) -> BigUint {
+    let bytes = seed.to_bytes_be();
+    let mut padded = vec![0u8; SEED_BYTES.saturating_sub(bytes.len())];
+    padded.extend_from_slice(&bytes);
+    BigUint::from_bytes_be(&Sha256::digest(&padded))
+}
+
+//Concatenates iterations + 1 hashes of consecutive seeds, as in steps 19 and 26 of FIPS 186-4 C.6
+fn hash_chain(seed: &BigUint, iterations: usize) -> BigUint {
+    let mut x = BigUint::zero();
+    for i in 0..=iterations {
+        x += hash(&(seed + BigUint::from(i))) << (i * OUTLEN);
+    }
+    x
+}
+
+//Turns an arbitrary byte string (e.g. a user supplied phrase) into a Shawe-Taylor input seed
+pub fn seed_from_bytes(input: &[u8]) -> BigUint {
+    BigUint::from_bytes_be(&Sha256::digest(input))
+}
+
+//Shawe-Taylor random prime construction (FIPS 186-4 appendix C.6). Returns the certificate
+//proving the prime, and the updated prime seed. None if the construction gave up.
+pub fn shawe_taylor_prime(length: usize, input_seed: &BigUint) -> Option<(PrimeCertificate, BigUint)> {
+    if length < 2 {
+        return None;
+    }
+
+    if length < SMALL_PRIME_BITS {
+        let mut prime_seed = input_seed.clone();
+        let top_bit = BigUint::one() << (length - 1);
+        for _ in 0..(4 * length) {
+            let c = hash(&prime_seed) ^ hash(&(&prime_seed + BigUint::one()));
+            let c = &top_bit + (c % &top_bit);
+            let c = (c >> 1 << 1) + BigUint::one();
+            prime_seed += BigUint::from(2u8);
+            if is_small_prime(&c) {
+                return Some((PrimeCertificate { base: c, steps: Vec::new() }, prime_seed));
+            }
+        }
+        return None;
+    }
+
+    let (mut certificate, mut prime_seed) = shawe_taylor_prime(length.div_ceil(2) + 1, input_seed)?;
+    let c0 = certificate.prime().clone();
+
+    let one = BigUint::one();
+    let two = BigUint::from(2u8);
+    let iterations = length.div_ceil(OUTLEN) - 1;
+    let top_bit = BigUint::one() << (length - 1);
+    let two_c0 = &two * &c0;
+
+    let x = hash_chain(&prime_seed, iterations);
+    prime_seed += BigUint::from(iterations + 1);
+    let x = &top_bit + (x % &top_bit);
+
+    let mut t = Integer::div_ceil(&x, &two_c0);
+    for _ in 0..(4 * length) {
+        if &two * &t * &c0 + &one > (&top_bit << 1) {
+            t = Integer::div_ceil(&top_bit, &two_c0);
+        }
+        let c = &two * &t * &c0 + &one;
+
+        let a = hash_chain(&prime_seed, iterations);
+        prime_seed += BigUint::from(iterations + 1);
+        let a = &two + (a % (&c - BigUint::from(3u8)));
+
+        let z = a.modpow(&(&two * &t), &c);
+        if !z.is_zero() && (&z - &one).gcd(&c).is_one() && z.modpow(&c0, &c).is_one() {
+            certificate.steps.push(CertificateStep { prime: c, factor: c0, witness: a });
+            return Some((certificate, prime_seed));
+        }
+        t += &one;
+    }
+    None
+}
+
+//Reads certificates separated by blank lines, as written next to the private key
+pub fn parse_certificates(text: &str) -> Result<Vec<PrimeCertificate>, String> {
+    text.replace("\r\n", "\n")
+        .split("\n\n")
+        .filter(|block| !block.trim().is_empty())
+        .map(PrimeCertificate::from_str)
+        .collect()
+}
+
+pub fn verify_prime_certificate(mut certificate: Input, pubkey: Option<Input>) {
+    let mut text = String::new();
+    let res = certificate.read_to_string(&mut text);
+    if let Err(e) = res {
+        panic!("Could not read certificate at {}: {e}", certificate.path());
+    }
+
+    let certificates = match parse_certificates(&text) {
+        Ok(c) => c,
+        Err(e) => panic!("Could not parse certificate: {e}")
+    };
+
+    let mut all_valid = !certificates.is_empty();
+    for (i, c) in certificates.iter().enumerate() {
+        if c.verify() {
+            println!("Prime {} ({} bits) is proven prime.", i + 1, c.prime().bits());
+        } else {
+            println!("Prime {} failed verification!", i + 1);
+            all_valid = false;
+        }
+    }
+
+    if let Some(pubkey) = pubkey {
+        let n = crate::key::PublicKey::from_text(crate::mainutil::read_key(pubkey).expose()).n;
+        let product = certificates.iter().fold(BigUint::one(), |acc, c| acc * c.prime());
+        if product == n {
+            println!("The certified primes match the public key modulus.");
+        } else {
+            println!("The certified primes do not match the public key modulus!");
+            all_valid = false;
+        }
+    }
+
+    if !all_valid {
+        exit(1);
+    }
+}
+
+#[test]
+fn shawe_taylor_certificates_verify() {
+    for (i, bits) in [16usize, 64, 160, 300].iter().enumerate() {
+        let seed = seed_from_bytes(format!("seed number {i}").as_bytes());
+        let (certificate, _) = shawe_taylor_prime(*bits, &seed).unwrap();
+        assert_eq!(certificate.prime().bits(), *bits);
+        assert!(certificate.verify());
+        assert!(crate::millers::is_prime_miller(certificate.prime()));
+    }
+}
+
+#[test]
+fn shawe_taylor_is_deterministic() {
+    let seed = seed_from_bytes(b"the same seed");
+    let (a, _) = shawe_taylor_prime(128, &seed).unwrap();
+    let (b, _) = shawe_taylor_prime(128, &seed).unwrap();
+    assert_eq!(a, b);
+}
+
+#[test]
+fn certificate_round_trips_through_text() {
+    let seed = seed_from_bytes(b"round trip");
+    let (certificate, _) = shawe_taylor_prime(200, &seed).unwrap();
+    let text = format!("{certificate}\n\n{certificate}");
+    let parsed = parse_certificates(&text).unwrap();
+    assert_eq!(parsed, vec![certificate.clone(), certificate]);
+}
+
+#[test]
+fn tampered_certificate_fails() {
+    let seed = seed_from_bytes(b"tamper");
+    let (certificate, _) = shawe_taylor_prime(128, &seed).unwrap();
+
+    let mut bad_witness = certificate.clone();
+    bad_witness.steps.last_mut().unwrap().witness = BigUint::one();
+    assert!(!bad_witness.verify());
+
+    let mut composite = certificate.clone();
+    let last = composite.steps.last_mut().unwrap();
+    last.prime = &last.prime * BigUint::from(3u8);
+    assert!(!composite.verify());
+
+    let mut bad_base = certificate;
+    bad_base.base = BigUint::from(4294967295u64);
+    assert!(!bad_base.verify());
+}
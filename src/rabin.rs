@@ -0,0 +1,242 @@
+use clio::Output;
+use num_bigint_dig::{BigUint, ModInverse, RandBigInt};
+use rand_core::CryptoRngCore;
+use sha2::{Digest, Sha256};
+
+use crate::{key::{byte_len, parse_metadata, to_bytes_padded, with_metadata}, mainutil::{from_hex, parse_key_lines, to_hex, write_output}, metadata::{split_key_text, KeyMetadata}, millers, secret::Secret, sieve::SievedCandidates};
+
+//The Rabin cryptosystem: n = pq with p = q = 3 mod 4, encryption is m^2 mod n and decryption
+//takes the four square roots of c with the factors of n. Only one of them carries the
+//redundancy added when padding, which says which root was the message. Recovering m without
+//the factors is as hard as factoring n, which RSA has never been shown to be.
+//
+//Public key files hold n and private key files n, p and q, one per line, each followed by the
+//usual metadata section.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Scheme {
+    #[default]
+    Rsa,
+    Rabin
+}
+
+const BEGIN: &str = "-----BEGIN RSA_RUST RABIN MESSAGE-----";
+const END: &str = "-----END RSA_RUST RABIN MESSAGE-----";
+
+//Bytes of the hash kept as redundancy, and the least random padding a block gets
+const TAG_LEN: usize = 8;
+const MIN_PADDING: usize = 8;
+
+pub struct RabinPublicKey {
+    pub n: BigUint,
+    pub metadata: KeyMetadata
+}
+
+pub struct RabinPrivateKey {
+    pub n: BigUint,
+    p: Secret<BigUint>,
+    q: Secret<BigUint>,
+    pub metadata: KeyMetadata
+}
+
+//A prime of bits bits that is 3 mod 4, so square roots mod it are a single exponentiation
+fn blum_prime<R: CryptoRngCore + ?Sized>(bits: usize, rng: &mut R) -> BigUint {
+    let start = rng.gen_biguint(bits) | (BigUint::from(3u8) << (bits - 2));
+    SievedCandidates::new(&start)
+        .find(|c| c % 4u8 == BigUint::from(3u8) && millers::is_prime_miller(c))
+        .unwrap()
+}
+
+impl RabinPrivateKey {
+    pub fn generate<R: CryptoRngCore + ?Sized>(bits: usize, rng: &mut R) -> RabinPrivateKey {
+        loop {
+            let p = Secret::new(blum_prime(bits / 2, rng));
+            let q = Secret::new(blum_prime(bits - bits / 2, rng));
+            let n = p.expose() * q.expose();
+            if p.expose() != q.expose() && n.bits() == bits {
+                return RabinPrivateKey { n, p, q, metadata: KeyMetadata::default() };
+            }
+        }
+    }
+
+    pub fn from_text(text: &str) -> RabinPrivateKey {
+        let (numbers, metadata) = split_key_text(text);
+        let values = parse_key_lines(numbers);
+        match values.expose().as_slice() {
+            [n, p, q] if &(p * q) == n => RabinPrivateKey {
+                n: n.clone(),
+                p: Secret::new(p.clone()),
+                q: Secret::new(q.clone()),
+                metadata: parse_metadata(metadata)
+            },
+            _ => panic!("A Rabin private key file has n, p and q with p * q = n")
+        }
+    }
+
+    pub fn to_text(&self) -> Secret<String> {
+        Secret::new(with_metadata(format!("{}\n{}\n{}", self.n, self.p.expose(), self.q.expose()), &self.metadata))
+    }
+
+    pub fn public(&self) -> RabinPublicKey {
+        RabinPublicKey { n: self.n.clone(), metadata: self.metadata.clone() }
+    }
+
+    //The four square roots of c mod n, by CRT from the roots c^((r + 1) / 4) mod each prime
+    fn square_roots(&self, c: &BigUint) -> Option<Secret<[BigUint; 4]>> {
+        let (p, q, n) = (self.p.expose(), self.q.expose(), &self.n);
+        let root_p = Secret::new(c.modpow(&((p + 1u8) >> 2), p));
+        let root_q = Secret::new(c.modpow(&((q + 1u8) >> 2), q));
+        let p_inverse = Secret::new(p.mod_inverse(q)?.to_biguint()?);
+        let q_inverse = Secret::new(q.mod_inverse(p)?.to_biguint()?);
+
+        let from_p = Secret::new(q * q_inverse.expose() * root_p.expose() % n);
+        let from_q = Secret::new(p * p_inverse.expose() * root_q.expose() % n);
+        let r = Secret::new((from_p.expose() + from_q.expose()) % n);
+        let s = Secret::new((from_p.expose() + n - from_q.expose()) % n);
+        Some(Secret::new([r.expose().clone(), n - r.expose(), s.expose().clone(), n - s.expose()]))
+    }
+
+    //The message in block c, the one square root with valid padding
+    pub fn decrypt_block(&self, c: &BigUint) -> Option<Secret<Vec<u8>>> {
+        if *c >= self.n {
+            return None;
+        }
+        let k = byte_len(&self.n);
+        let roots = self.square_roots(c)?;
+        roots.expose().iter().find_map(|root| {
+            let encoded = Secret::new(to_bytes_padded(root, k));
+            decode(encoded.expose())
+        })
+    }
+}
+
+impl RabinPublicKey {
+    pub fn from_text(text: &str) -> RabinPublicKey {
+        let (numbers, metadata) = split_key_text(text);
+        let values = parse_key_lines(numbers);
+        match values.expose().as_slice() {
+            [n] => RabinPublicKey { n: n.clone(), metadata: parse_metadata(metadata) },
+            _ => panic!("A Rabin public key file has just n")
+        }
+    }
+
+    pub fn to_text(&self) -> String {
+        with_metadata(self.n.to_string(), &self.metadata)
+    }
+
+    //Longest message one block carries
+    pub fn max_block_len(&self) -> usize {
+        byte_len(&self.n).saturating_sub(2 + MIN_PADDING + TAG_LEN)
+    }
+
+    pub fn encrypt_block<R: CryptoRngCore + ?Sized>(&self, message: &[u8], rng: &mut R) -> Option<BigUint> {
+        let encoded = encode(message, byte_len(&self.n), rng)?;
+        let m = BigUint::from_bytes_be(encoded.expose());
+        Some(&m * &m % &self.n)
+    }
+}
+
+fn tag(padded: &[u8]) -> [u8; TAG_LEN] {
+    let digest = Sha256::new().chain_update("rsa_rust rabin").chain_update(padded).finalize();
+    let mut tag = [0u8; TAG_LEN];
+    tag.copy_from_slice(&digest[..TAG_LEN]);
+    tag
+}
+
+//0x00 || random nonzero bytes || 0x00 || message || tag, k bytes in all. The random bytes keep
+//m^2 well above n and equal messages from encrypting the same, the tag over everything
+//before it is the redundancy that picks out the right square root.
+fn encode<R: CryptoRngCore + ?Sized>(message: &[u8], k: usize, rng: &mut R) -> Option<Secret<Vec<u8>>> {
+    let padding_len = k.checked_sub(2 + message.len() + TAG_LEN).filter(|l| *l >= MIN_PADDING)?;
+    let mut encoded = Secret::new(vec![0u8]);
+    for _ in 0..padding_len {
+        let mut byte = [0u8];
+        while byte[0] == 0 {
+            rng.fill_bytes(&mut byte);
+        }
+        encoded.expose_mut().push(byte[0]);
+    }
+    encoded.expose_mut().push(0);
+    encoded.expose_mut().extend_from_slice(message);
+    let tag = tag(&encoded.expose()[1..]);
+    encoded.expose_mut().extend_from_slice(&tag);
+    Some(encoded)
+}
+
+fn decode(encoded: &[u8]) -> Option<Secret<Vec<u8>>> {
+    if encoded.len() < 2 + MIN_PADDING + TAG_LEN || encoded[0] != 0 {
+        return None;
+    }
+    let (body, given) = encoded[1..].split_at(encoded.len() - 1 - TAG_LEN);
+    if tag(body) != given {
+        return None;
+    }
+    let separator = body.iter().position(|b| *b == 0).filter(|s| *s >= MIN_PADDING)?;
+    Some(Secret::new(body[separator + 1..].to_vec()))
+}
+
+pub fn is_rabin_message(text: &str) -> bool {
+    text.trim_start().starts_with(BEGIN)
+}
+
+//The message split into blocks, armored with one block of hex per line
+pub fn encrypt<R: CryptoRngCore + ?Sized>(key: &RabinPublicKey, plaintext: &[u8], rng: &mut R) -> Result<String, String> {
+    let block_len = key.max_block_len();
+    if block_len == 0 {
+        return Err("The Rabin key is too small to carry any message".to_string());
+    }
+    let mut lines = Vec::new();
+    for chunk in plaintext.chunks(block_len) {
+        match key.encrypt_block(chunk, rng) {
+            Some(c) => lines.push(to_hex(&to_bytes_padded(&c, byte_len(&key.n)))),
+            None => return Err("A block didn't fit the key".to_string())
+        }
+    }
+    Ok(format!("{BEGIN}\n{}\n{END}\n", lines.join("\n")))
+}
+
+pub fn decrypt(key: &RabinPrivateKey, text: &str) -> Result<Secret<Vec<u8>>, String> {
+    let inner = match text.trim().strip_prefix(BEGIN).and_then(|t| t.strip_suffix(END)) {
+        Some(inner) => inner,
+        None => return Err("Not a Rabin encrypted message".to_string())
+    };
+    let mut plaintext = Secret::new(Vec::new());
+    for line in inner.split_whitespace() {
+        let c = match from_hex(line) {
+            Some(bytes) => BigUint::from_bytes_be(&bytes),
+            None => return Err("A block isn't valid hex".to_string())
+        };
+        match key.decrypt_block(&c) {
+            Some(block) => plaintext.expose_mut().extend_from_slice(block.expose()),
+            None => return Err("No square root of a block has valid padding, the message isn't for this key or is corrupt".to_string())
+        }
+    }
+    Ok(plaintext)
+}
+
+//generate-keys --scheme rabin
+pub fn write_keys<R: CryptoRngCore + ?Sized>(mut pubkey_file: Output, mut privkey_file: Output, bits: usize, metadata: KeyMetadata, rng: &mut R) {
+    let mut key = RabinPrivateKey::generate(bits, rng);
+    key.metadata = metadata;
+    write_output(&mut pubkey_file, &key.public().to_text());
+    write_output(&mut privkey_file, key.to_text().expose());
+}
+
+#[test]
+fn rabin_round_trip() {
+    let mut rng = rand::thread_rng();
+    let key = RabinPrivateKey::generate(512, &mut rng);
+    assert!(key.p.expose() % 4u8 == BigUint::from(3u8) && key.q.expose() % 4u8 == BigUint::from(3u8));
+    let key = RabinPrivateKey::from_text(key.to_text().expose());
+    let public = RabinPublicKey::from_text(&key.public().to_text());
+
+    let message = Vec::from_iter((0..200u32).map(|i| (i % 256) as u8));
+    let sealed = encrypt(&public, &message, &mut rng).unwrap();
+    assert_eq!(decrypt(&key, &sealed).unwrap().expose(), &message);
+
+    //Every one of the four roots squares back to the block, only one has the redundancy
+    let c = public.encrypt_block(b"hi", &mut rng).unwrap();
+    let roots = key.square_roots(&c).unwrap();
+    assert!(roots.expose().iter().all(|r| r * r % &key.n == c));
+    let k = byte_len(&key.n);
+    assert_eq!(roots.expose().iter().filter(|r| decode(&to_bytes_padded(r, k)).is_some()).count(), 1);
+}
@@ -0,0 +1,171 @@
+use std::io::Read;
+
+use clio::{Input, Output};
+use num_bigint_dig::BigUint;
+use rand_core::CryptoRngCore;
+
+use crate::{key::{PrivateKey, PublicKey}, keyring::Keyring, mainutil::{self, format_time, from_hex, read_key, to_hex}, metadata::Usage, signature};
+
+const CONTEXT: &str = "rsa_rust revocation";
+
+const BEGIN: &str = "-----BEGIN RSA_RUST REVOCATION-----";
+const END: &str = "-----END RSA_RUST REVOCATION-----";
+
+//Why a key was revoked, as in OpenPGP
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum RevocationReason {
+    #[default]
+    Unspecified,
+    KeyCompromise,
+    Superseded,
+    Retired
+}
+
+impl RevocationReason {
+    fn name(self) -> &'static str {
+        match self {
+            RevocationReason::Unspecified => "unspecified",
+            RevocationReason::KeyCompromise => "key-compromise",
+            RevocationReason::Superseded => "superseded",
+            RevocationReason::Retired => "retired"
+        }
+    }
+
+    fn from_name(name: &str) -> Option<RevocationReason> {
+        [RevocationReason::Unspecified, RevocationReason::KeyCompromise, RevocationReason::Superseded, RevocationReason::Retired]
+            .into_iter()
+            .find(|r| r.name() == name)
+    }
+}
+
+//A statement, signed by the key itself, that the key shouldn't be used any more:
+//
+//  -----BEGIN RSA_RUST REVOCATION-----
+//  key <fingerprint>
+//  reason <reason>
+//  revoked <seconds since the Unix epoch>
+//  signature <hex>
+//  -----END RSA_RUST REVOCATION-----
+//
+//The signature covers the key, reason and revoked lines.
+#[derive(Debug, Clone)]
+pub struct Revocation {
+    pub fingerprint: String,
+    pub reason: RevocationReason,
+    pub revoked: u64,
+    signature: BigUint
+}
+
+impl Revocation {
+    //Revoking is always allowed whatever the key's usage flags or expiry say, a
+    //compromised key needs it most
+    pub fn new<R: CryptoRngCore + ?Sized>(key: &PrivateKey, reason: RevocationReason, revoked: u64, rng: &mut R) -> Revocation {
+        let mut revocation = Revocation {
+            fingerprint: key.public().fingerprint(),
+            reason,
+            revoked,
+            signature: BigUint::default()
+        };
+        revocation.signature = signature::sign(key, CONTEXT, revocation.statement().as_bytes(), rng);
+        revocation
+    }
+
+    fn statement(&self) -> String {
+        format!("key {}\nreason {}\nrevoked {}\n", self.fingerprint, self.reason.name(), self.revoked)
+    }
+
+    //Whether key made this revocation for itself
+    pub fn verify(&self, key: &PublicKey) -> bool {
+        key.fingerprint() == self.fingerprint
+            && signature::verify(key, CONTEXT, self.statement().as_bytes(), &self.signature)
+    }
+
+    //"revoked on 2024-10-19 04:59 UTC (key-compromise)"
+    pub fn describe(&self) -> String {
+        format!("revoked on {} ({})", format_time(self.revoked), self.reason.name())
+    }
+}
+
+impl std::fmt::Display for Revocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{BEGIN}")?;
+        write!(f, "{}", self.statement())?;
+        writeln!(f, "signature {}", to_hex(&self.signature.to_bytes_be()))?;
+        writeln!(f, "{END}")
+    }
+}
+
+impl std::str::FromStr for Revocation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let inner = match s.trim().strip_prefix(BEGIN).and_then(|t| t.strip_suffix(END)) {
+            Some(inner) => inner,
+            None => return Err("Not a revocation certificate".to_string())
+        };
+        let (mut fingerprint, mut reason, mut revoked, mut signature) = (None, None, None, None);
+        for line in inner.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            match line.split_once(' ') {
+                Some(("key", f)) => fingerprint = Some(f.to_string()),
+                Some(("reason", r)) => match RevocationReason::from_name(r) {
+                    Some(r) => reason = Some(r),
+                    None => return Err(format!("Unknown revocation reason \"{r}\""))
+                },
+                Some(("revoked", t)) => revoked = t.parse::<u64>().ok(),
+                Some(("signature", hex)) => signature = from_hex(hex).map(|b| BigUint::from_bytes_be(&b)),
+                _ => return Err(format!("Unexpected revocation line \"{line}\""))
+            }
+        }
+        match (fingerprint, reason, revoked, signature) {
+            (Some(fingerprint), Some(reason), Some(revoked), Some(signature)) => {
+                Ok(Revocation { fingerprint, reason, revoked, signature })
+            }
+            _ => Err("The revocation certificate is missing a field".to_string())
+        }
+    }
+}
+
+//The generate-revocation subcommand. Keep the output somewhere safe, it's for when the
+//private key is lost or stolen. Only keys allowed to certify can vouch for their own
+//revocation; an expired key still can, since revoking it is still worth something.
+pub fn generate_revocation(privkey: Option<Input>, reason: RevocationReason, mut output: Output) {
+    let key = PrivateKey::from_text(read_key(mainutil::input_or_default(privkey, "./private.txt")).expose());
+    if !key.metadata.usages.contains(&Usage::Certify) {
+        panic!("Refusing to revoke {}, the key's usage flags don't include certify.", key.public().fingerprint());
+    }
+    let revocation = Revocation::new(&key, reason, mainutil::now(), &mut rand::thread_rng());
+    mainutil::write_output(&mut output, &revocation.to_string());
+}
+
+//The import-revocation subcommand
+pub fn import_revocation(mut input: Input) {
+    let mut text = String::new();
+    if let Err(e) = input.read_to_string(&mut text) {
+        panic!("Failed to read the revocation. Error: {e}");
+    }
+    let revocation: Revocation = match text.parse() {
+        Ok(r) => r,
+        Err(e) => panic!("{e}")
+    };
+    match Keyring::existing().and_then(|k| k.import_revocation(&revocation)) {
+        Ok(entry) => println!("Marked {} ({}) as {}.", entry.name, entry.fingerprint, revocation.describe()),
+        Err(e) => panic!("{e}")
+    }
+}
+
+#[test]
+fn revocations_verify_against_their_key() {
+    use crate::key::test_key;
+
+    let mut rng = rand::thread_rng();
+    let keys = Vec::from_iter((0..2).map(|_| test_key(512)));
+
+    let revocation = Revocation::new(&keys[0], RevocationReason::KeyCompromise, 1700000000, &mut rng);
+    let parsed: Revocation = revocation.to_string().parse().unwrap();
+    assert!(parsed.verify(&keys[0].public()));
+    assert!(!parsed.verify(&keys[1].public()));
+    assert_eq!(parsed.describe(), "revoked on 2023-11-14 22:13 UTC (key-compromise)");
+
+    let backdated: Revocation = revocation.to_string().replace("revoked 1700000000", "revoked 1600000000").parse().unwrap();
+    assert!(!backdated.verify(&keys[0].public()));
+}
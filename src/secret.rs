@@ -0,0 +1,56 @@
+use zeroize::Zeroize;
+
+//Holds private key material (exponents, primes, CRT values, passphrases, plaintext) and wipes it
+//when dropped. Debug never shows the contents, so a stray {:?} can't leak it.
+//
+//This is best effort: num-bigint-dig arithmetic makes temporaries we can't reach, but every
+//value we keep around goes through here.
+pub struct Secret<T: Zeroize>(T);
+
+impl<T: Zeroize> Secret<T> {
+    pub fn new(value: T) -> Secret<T> {
+        Secret(value)
+    }
+
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+
+    pub fn expose_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T: Zeroize> Drop for Secret<T> {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl<T: Zeroize + Clone> Clone for Secret<T> {
+    fn clone(&self) -> Secret<T> {
+        Secret(self.0.clone())
+    }
+}
+
+impl<T: Zeroize> std::fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Secret([REDACTED])")
+    }
+}
+
+impl<T: Zeroize> From<T> for Secret<T> {
+    fn from(value: T) -> Secret<T> {
+        Secret(value)
+    }
+}
+
+#[test]
+fn debug_hides_the_value() {
+    use num_bigint_dig::BigUint;
+
+    let secret = Secret::new(BigUint::from(123456789u32));
+    assert_eq!(format!("{secret:?}"), "Secret([REDACTED])");
+    assert!(!format!("{:?}", Some(secret.clone())).contains("123456789"));
+    assert_eq!(*secret.expose(), BigUint::from(123456789u32));
+}
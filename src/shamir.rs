@@ -0,0 +1,281 @@
+use std::{io::Read, path::PathBuf};
+
+use clio::{ClioPath, Input, Output};
+use num_bigint_dig::{BigUint, ModInverse, RandBigInt};
+use num_traits::{One, Zero};
+use rand_core::CryptoRngCore;
+use sha2::{Digest, Sha256};
+
+use crate::{key::{PrivateKey, PublicKey}, keyring::{self, Keyring}, mainutil::{self, from_hex, read_key, to_hex}, secret::Secret};
+
+//Shamir secret sharing of the private key file. The key text is cut into 64 byte chunks and
+//each chunk is the constant term of its own random polynomial of degree threshold - 1 over
+//GF(2^521 - 1), a Mersenne prime comfortably larger than any chunk. Share x holds every
+//polynomial evaluated at x. Any threshold shares interpolate the chunks back, fewer say
+//nothing about them.
+const CHUNK_LEN: usize = 64;
+const FIELD_BYTES: usize = 66;
+
+fn field_prime() -> BigUint {
+    (BigUint::one() << 521) - BigUint::one()
+}
+
+const BEGIN: &str = "-----BEGIN RSA_RUST KEY SHARE-----";
+const END: &str = "-----END RSA_RUST KEY SHARE-----";
+
+//One share, armored as
+//
+//  -----BEGIN RSA_RUST KEY SHARE-----
+//  key <fingerprint of the shared key>
+//  threshold <shares needed>
+//  share <x>
+//  length <bytes of key text>
+//  <one hex value per chunk>
+//  checksum <SHA-256 of the lines above>
+//  -----END RSA_RUST KEY SHARE-----
+#[derive(Debug)]
+pub struct KeyShare {
+    pub fingerprint: String,
+    pub threshold: u8,
+    pub x: u8,
+    length: usize,
+    values: Secret<Vec<BigUint>>
+}
+
+impl KeyShare {
+    fn body(&self) -> String {
+        let mut body = format!("key {}\nthreshold {}\nshare {}\nlength {}\n", self.fingerprint, self.threshold, self.x, self.length);
+        for value in self.values.expose() {
+            let bytes = value.to_bytes_be();
+            let mut padded = vec![0u8; FIELD_BYTES - bytes.len()];
+            padded.extend(bytes);
+            body.push_str(&to_hex(&padded));
+            body.push('\n');
+        }
+        body
+    }
+}
+
+fn checksum(body: &str) -> String {
+    to_hex(&Sha256::digest(body.as_bytes()))
+}
+
+impl std::fmt::Display for KeyShare {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let body = self.body();
+        writeln!(f, "{BEGIN}")?;
+        write!(f, "{body}")?;
+        writeln!(f, "checksum {}", checksum(&body))?;
+        writeln!(f, "{END}")
+    }
+}
+
+impl std::str::FromStr for KeyShare {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let inner = match s.trim().strip_prefix(BEGIN).and_then(|t| t.strip_suffix(END)) {
+            Some(inner) => inner,
+            None => return Err("Not a key share".to_string())
+        };
+        let lines = Vec::from_iter(inner.lines().map(str::trim).filter(|l| !l.is_empty()));
+        let (given, lines) = match lines.split_last() {
+            Some((last, rest)) => match last.strip_prefix("checksum ") {
+                Some(c) => (c, rest),
+                None => return Err("The key share has no checksum".to_string())
+            },
+            None => return Err("The key share is empty".to_string())
+        };
+        let field = |i: usize, name: &str| match lines.get(i).and_then(|l| l.split_once(' ')) {
+            Some((n, value)) if n == name => Ok(value),
+            _ => Err(format!("The key share is missing its {name} line"))
+        };
+        let number = |i: usize, name: &str| field(i, name)?.parse::<usize>().map_err(|e| format!("Bad {name} in the key share: {e}"));
+
+        let fingerprint = field(0, "key")?.to_string();
+        let threshold = number(1, "threshold")?;
+        let x = number(2, "share")?;
+        let length = number(3, "length")?;
+        let mut values = Secret::new(Vec::new());
+        for line in &lines[4..] {
+            match from_hex(line) {
+                Some(bytes) if bytes.len() == FIELD_BYTES => values.expose_mut().push(BigUint::from_bytes_be(&bytes)),
+                _ => return Err("The key share has a bad value line".to_string())
+            }
+        }
+        let (threshold, x) = match (u8::try_from(threshold), u8::try_from(x)) {
+            (Ok(t), Ok(x)) if x > 0 => (t, x),
+            _ => return Err("The key share's threshold or number is out of range".to_string())
+        };
+        if values.expose().len() != length.div_ceil(CHUNK_LEN) {
+            return Err("The key share has the wrong number of values for its length".to_string());
+        }
+
+        let share = KeyShare { fingerprint, threshold, x, length, values };
+        if checksum(&share.body()) != given.to_lowercase() {
+            return Err(format!("Key share {x} fails its checksum, it has been damaged or altered"));
+        }
+        Ok(share)
+    }
+}
+
+//count shares of secret, any threshold of which rebuild it
+pub fn split<R: CryptoRngCore + ?Sized>(secret: &[u8], fingerprint: &str, threshold: u8, count: u8, rng: &mut R) -> Vec<KeyShare> {
+    let prime = field_prime();
+    let mut shares = Vec::from_iter((1..=count).map(|x| KeyShare {
+        fingerprint: fingerprint.to_string(),
+        threshold,
+        x,
+        length: secret.len(),
+        values: Secret::new(Vec::new())
+    }));
+    for chunk in secret.chunks(CHUNK_LEN) {
+        let mut coefficients = Secret::new(vec![BigUint::from_bytes_be(chunk)]);
+        for _ in 1..threshold {
+            coefficients.expose_mut().push(rng.gen_biguint_below(&prime));
+        }
+        for share in &mut shares {
+            //Horner's rule
+            let x = BigUint::from(share.x);
+            let mut y = Secret::new(BigUint::zero());
+            for c in coefficients.expose().iter().rev() {
+                *y.expose_mut() = (y.expose() * &x + c) % &prime;
+            }
+            share.values.expose_mut().push(y.expose().clone());
+        }
+    }
+    shares
+}
+
+//The secret shared out by split, from at least threshold of its shares
+pub fn combine(shares: &[KeyShare]) -> Result<Secret<Vec<u8>>, String> {
+    let first = match shares.first() {
+        Some(s) => s,
+        None => return Err("No key shares given".to_string())
+    };
+    if shares.iter().any(|s| s.fingerprint != first.fingerprint || s.threshold != first.threshold || s.length != first.length) {
+        return Err("The key shares come from different splits".to_string());
+    }
+    let mut xs = Vec::from_iter(shares.iter().map(|s| s.x));
+    xs.sort_unstable();
+    xs.dedup();
+    if xs.len() != shares.len() {
+        return Err("The same key share was given twice".to_string());
+    }
+    if shares.len() < first.threshold as usize {
+        return Err(format!("The key needs {} shares, only {} given", first.threshold, shares.len()));
+    }
+
+    //Lagrange interpolation at 0: the weight of share j is the product of x_m / (x_m - x_j)
+    let prime = field_prime();
+    let shares = &shares[..first.threshold as usize];
+    let mut weights = Vec::new();
+    for share in shares {
+        let mut numerator = BigUint::one();
+        let mut denominator = BigUint::one();
+        for other in shares.iter().filter(|o| o.x != share.x) {
+            numerator = numerator * BigUint::from(other.x) % &prime;
+            denominator = denominator * ((&prime + BigUint::from(other.x) - BigUint::from(share.x)) % &prime) % &prime;
+        }
+        let inverse = match denominator.mod_inverse(&prime).and_then(|i| i.to_biguint()) {
+            Some(i) => i,
+            None => return Err("The key shares can't be combined".to_string())
+        };
+        weights.push(numerator * inverse % &prime);
+    }
+
+    let mut secret = Secret::new(Vec::with_capacity(first.length));
+    for i in 0..first.values.expose().len() {
+        let mut chunk = Secret::new(BigUint::zero());
+        for (share, weight) in shares.iter().zip(&weights) {
+            *chunk.expose_mut() = (chunk.expose() + &share.values.expose()[i] * weight) % &prime;
+        }
+        let chunk_len = CHUNK_LEN.min(first.length - i * CHUNK_LEN);
+        let bytes = Secret::new(chunk.expose().to_bytes_be());
+        if bytes.expose().len() > chunk_len {
+            return Err("The key shares don't combine to a key".to_string());
+        }
+        secret.expose_mut().extend(std::iter::repeat_n(0u8, chunk_len - bytes.expose().len()));
+        secret.expose_mut().extend_from_slice(bytes.expose());
+    }
+    Ok(secret)
+}
+
+//The split-key subcommand, writing share-1.txt to share-<shares>.txt
+pub fn split_key(privkey: Option<Input>, threshold: u8, count: u8, share_dir: Option<ClioPath>) {
+    if threshold > count {
+        panic!("The threshold ({threshold}) can't be more than the number of shares ({count})");
+    }
+    let key = PrivateKey::from_text(read_key(mainutil::input_or_default(privkey, "./private.txt")).expose());
+    let text = key.to_text();
+    let fingerprint = key.public().fingerprint();
+    let dir = match share_dir {
+        Some(d) => d.path().to_path_buf(),
+        None => PathBuf::from(".")
+    };
+
+    for share in split(text.expose().as_bytes(), &fingerprint, threshold, count, &mut rand::thread_rng()) {
+        let path = dir.join(format!("share-{}.txt", share.x));
+        match keyring::write_private(&path, &share.to_string()) {
+            Ok(_) => println!("Wrote {}", path.display()),
+            Err(e) => panic!("Could not write {}: {e}", path.display())
+        }
+    }
+    println!("Any {threshold} of the {count} shares rebuild the key, give each to a different person.");
+}
+
+//The combine-key subcommand. The rebuilt key is checked against the public key, given with
+//-p or else found in the keyring by fingerprint.
+pub fn combine_key(inputs: Vec<Input>, pubkey: Option<Input>, mut output: Output) {
+    let shares = Vec::from_iter(inputs.into_iter().map(|mut input| {
+        let mut text = String::new();
+        if let Err(e) = input.read_to_string(&mut text) {
+            panic!("Failed to read {}. Error: {e}", input.path());
+        }
+        match text.parse::<KeyShare>() {
+            Ok(share) => share,
+            Err(e) => panic!("{}: {e}", input.path())
+        }
+    }));
+    let secret = match combine(&shares) {
+        Ok(s) => s,
+        Err(e) => panic!("{e}")
+    };
+    let text = match std::str::from_utf8(secret.expose()) {
+        Ok(t) => t,
+        Err(_) => panic!("The key shares don't combine to a key")
+    };
+    let key = PrivateKey::from_text(text);
+
+    let fingerprint = &shares[0].fingerprint;
+    let public = match pubkey {
+        Some(pubkey) => PublicKey::from_text(read_key(pubkey).expose()),
+        None => match Keyring::existing().and_then(|k| k.find(fingerprint)) {
+            Ok(entry) => entry.public,
+            Err(_) => panic!("Key {fingerprint} isn't in the keyring, give its public key with -p to check the rebuilt key against")
+        }
+    };
+    if public.fingerprint() != *fingerprint {
+        panic!("The shares are of key {fingerprint}, not the given public key");
+    }
+    if let Err(e) = key.validate(&public, &mut rand::thread_rng()) {
+        panic!("The rebuilt key is not valid, {e}");
+    }
+    mainutil::write_output(&mut output, key.to_text().expose());
+}
+
+#[test]
+fn any_threshold_shares_rebuild_the_secret() {
+    let mut rng = rand::thread_rng();
+    let secret = Vec::from_iter((0..200u32).map(|i| (i * 37 % 251) as u8));
+    let shares = split(&secret, "ab", 3, 5, &mut rng);
+
+    for picked in [[0, 1, 2], [4, 2, 0], [1, 3, 4]] {
+        let subset = Vec::from_iter(picked.iter().map(|&i| shares[i].to_string().parse::<KeyShare>().unwrap()));
+        assert_eq!(combine(&subset).unwrap().expose(), &secret);
+    }
+    assert!(combine(&shares[..2]).unwrap_err().contains("needs 3"));
+
+    let damaged = shares[0].to_string().replacen("key ab", "key ac", 1);
+    assert!(damaged.parse::<KeyShare>().unwrap_err().contains("checksum"));
+}
@@ -0,0 +1,445 @@
+use std::sync::{atomic::{AtomicU64, Ordering}, Mutex, OnceLock};
+
+use num_bigint_dig::BigUint;
+use num_integer::Integer;
+use num_traits::{One, ToPrimitive};
+use num_bigint_dig::RandBigInt;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use rand_core::CryptoRngCore;
+
+use crate::primality::PrimalityTest;
+
+//Candidates are trial divided by every odd prime below this
+const SIEVE_LIMIT: u32 = 1 << 16;
+
+//Odd numbers per unit of work handed to a search thread. About one in ten survives the
+//sieve, so a block is a handful of full primality tests.
+const BLOCK_CANDIDATES: u64 = 64;
+
+//Odd primes below SIEVE_LIMIT, built once with the sieve of Eratosthenes
+pub fn small_primes() -> &'static [u32] {
+    static PRIMES: OnceLock<Vec<u32>> = OnceLock::new();
+    PRIMES.get_or_init(|| {
+        let limit = SIEVE_LIMIT as usize;
+        let mut composite = vec![false; limit];
+        let mut primes = Vec::new();
+        for i in 3..limit {
+            if composite[i] || i.is_even() {
+                continue;
+            }
+            primes.push(i as u32);
+            for multiple in (i * i..limit).step_by(2 * i) {
+                composite[multiple] = true;
+            }
+        }
+        primes
+    })
+}
+
+//Remainder of a big endian number by a small one, without allocating a BigUint per prime
+fn small_rem(bytes: &[u8], m: u32) -> u32 {
+    let m = m as u64;
+    bytes.iter().fold(0u64, |acc, byte| ((acc << 8) | *byte as u64) % m) as u32
+}
+
+//Odd numbers >= start that have no factor below SIEVE_LIMIT (or are themselves a small prime).
+//Keeps the residue of the starting point modulo every small prime, so stepping forward
+//only touches machine words and no bignum work happens for a rejected candidate.
+#[derive(Clone)]
+pub struct SievedCandidates {
+    base: BigUint,
+    base_small: Option<u64>,
+    residues: Vec<u32>,
+    offset: u64,
+    yield_two: bool
+}
+
+impl SievedCandidates {
+    pub fn new(start: &BigUint) -> SievedCandidates {
+        let mut base = start.clone();
+        if base.is_even() {
+            base += 1u8;
+        }
+
+        let bytes = base.to_bytes_be();
+        let residues = small_primes().iter().map(|p| small_rem(&bytes, *p)).collect();
+        SievedCandidates {
+            base_small: base.to_u64(),
+            base,
+            residues,
+            offset: 0,
+            yield_two: *start <= BigUint::from(2u8)
+        }
+    }
+
+    fn survives(&self) -> bool {
+        for (p, r) in small_primes().iter().zip(&self.residues) {
+            if (*r as u64 + self.offset).is_multiple_of(*p as u64) {
+                //Divisible, which only keeps it alive if it is that prime
+                return self.base_small.map(|b| b + self.offset) == Some(*p as u64);
+            }
+        }
+        //1 has no small factors but isn't prime
+        self.base_small.map(|b| b + self.offset) != Some(1)
+    }
+}
+
+impl Iterator for SievedCandidates {
+    type Item = BigUint;
+
+    fn next(&mut self) -> Option<BigUint> {
+        if self.yield_two {
+            self.yield_two = false;
+            return Some(BigUint::from(2u8));
+        }
+        while !self.survives() {
+            self.offset += 2;
+        }
+        let candidate = &self.base + BigUint::from(self.offset);
+        self.offset += 2;
+        Some(candidate)
+    }
+}
+
+//Smallest prime >= start according to the given test
+pub fn next_prime(start: &BigUint, test: &dyn PrimalityTest, rng: &mut dyn CryptoRngCore) -> BigUint {
+    SievedCandidates::new(start).find(|c| test.is_prime(c, rng)).unwrap()
+}
+
+//Smallest safe prime 2q + 1 >= 2 * start + 1, one where q is prime too. Candidates for q come
+//off the sieve and 2q + 1 is trial divided the same way before either gets a full test.
+pub fn next_safe_prime(start: &BigUint, test: &dyn PrimalityTest, rng: &mut dyn CryptoRngCore) -> BigUint {
+    SievedCandidates::new(start)
+        .map(|q| (BigUint::from(2u8) * &q + 1u8, q))
+        .find(|(p, q)| {
+            let bytes = p.to_bytes_be();
+            small_primes().iter().all(|r| small_rem(&bytes, *r) != 0 || p == &BigUint::from(*r))
+                && test.is_prime(q, rng)
+                && test.is_prime(p, rng)
+        })
+        .unwrap()
+        .0
+}
+
+//Largest prime <= start according to the given test, None below 2. Walks down over odd
+//numbers with the same residue trick as SievedCandidates, so a candidate with a small factor
+//costs no bignum work.
+pub fn prev_prime(start: &BigUint, test: &dyn PrimalityTest, rng: &mut dyn CryptoRngCore) -> Option<BigUint> {
+    if *start < BigUint::from(3u8) {
+        return Some(BigUint::from(2u8)).filter(|two| start >= two);
+    }
+    let mut base = start.clone();
+    if base.is_even() {
+        base -= 1u8;
+    }
+    let bytes = base.to_bytes_be();
+    let residues = Vec::from_iter(small_primes().iter().map(|p| small_rem(&bytes, *p)));
+    let base_small = base.to_u64();
+    let mut offset = 0u64;
+    loop {
+        let candidate_small = base_small.map(|b| b - offset);
+        if candidate_small == Some(1) {
+            return Some(BigUint::from(2u8));
+        }
+        //base - offset is divisible by p exactly when offset = base mod p
+        let survives = small_primes().iter().zip(&residues)
+            .all(|(p, r)| offset % *p as u64 != *r as u64 || candidate_small == Some(*p as u64));
+        if survives {
+            let candidate = &base - offset;
+            if test.is_prime(&candidate, rng) {
+                return Some(candidate);
+            }
+        }
+        offset += 2;
+    }
+}
+
+//A random prime of exactly bits bits, bits >= 2
+pub fn random_prime(bits: usize, test: &dyn PrimalityTest, rng: &mut dyn CryptoRngCore) -> BigUint {
+    loop {
+        let start = rng.gen_biguint(bits) | (BigUint::one() << (bits - 1));
+        let prime = next_prime(&start, test, rng);
+        if prime.bits() == bits {
+            return prime;
+        }
+    }
+}
+
+//Odd primes up to limit, sieve of Eratosthenes
+pub fn primes_up_to(limit: u64) -> Vec<u64> {
+    let mut composite = vec![false; limit as usize + 1];
+    let mut primes = Vec::new();
+    for i in (3..=limit).step_by(2) {
+        if composite[i as usize] {
+            continue;
+        }
+        primes.push(i);
+        for multiple in (i * i..=limit).step_by(2 * i as usize) {
+            composite[multiple as usize] = true;
+        }
+    }
+    primes
+}
+
+//Largest high count_primes sieves. Its base primes go up to sqrt(high) in one array, which
+//stays around 16 MB here but would be gigabytes near 2^64.
+const MAX_SIEVED: u64 = 1 << 48;
+
+//Numbers per segment of count_primes, small enough to stay in cache
+const SEGMENT_LEN: u64 = 1 << 18;
+
+//Primes in [low, high] with a segmented sieve of Eratosthenes: the primes up to sqrt(high)
+//cross off their multiples one segment at a time, so memory stays at sqrt(high) plus a
+//segment however long the range is
+fn count_primes_sieved(low: u64, high: u64) -> u64 {
+    let low = low.max(2);
+    if high < low {
+        return 0;
+    }
+    let mut count = (low == 2) as u64;
+    let base = primes_up_to(high.isqrt());
+    let mut start = low;
+    while start <= high {
+        let end = high.min(start.saturating_add(SEGMENT_LEN - 1));
+        let mut composite = vec![false; (end - start + 1) as usize];
+        for &p in base.iter().take_while(|p| *p * *p <= end) {
+            let first = (p * p).max(start.div_ceil(p) * p);
+            for multiple in (first..=end).step_by(p as usize) {
+                composite[(multiple - start) as usize] = true;
+            }
+        }
+        count += (start..=end).filter(|i| i % 2 == 1 && *i > 1 && !composite[(i - start) as usize]).count() as u64;
+        match end.checked_add(1) {
+            Some(next) if end < high => start = next,
+            _ => break
+        }
+    }
+    count
+}
+
+//Whether count_primes sieves [low, high] instead of testing candidates one by one
+fn worth_sieving(low: u64, high: u64) -> bool {
+    high <= MAX_SIEVED && high - low >= high.isqrt()
+}
+
+//Number of primes in [low, high]. Ranges at least sqrt(high) long up to MAX_SIEVED are sieved
+//and the count is exact, shorter or bigger ones test each survivor of the small prime sieve
+//with the given test instead.
+pub fn count_primes(low: &BigUint, high: &BigUint, test: &dyn PrimalityTest, rng: &mut dyn CryptoRngCore) -> u64 {
+    if low > high {
+        return 0;
+    }
+    match (low.to_u64(), high.to_u64()) {
+        (Some(a), Some(b)) if worth_sieving(a, b) => count_primes_sieved(a, b),
+        _ => SievedCandidates::new(low)
+            .take_while(|c| c <= high)
+            .filter(|c| test.is_prime(c, rng))
+            .count() as u64
+    }
+}
+
+//Shared state of one next_prime search spread over several threads
+struct Search {
+    candidates: SievedCandidates,
+    //Each block tests with its own ChaCha20 stream under this key, so the randomness
+    //a candidate sees doesn't depend on which thread got to it
+    seed: [u8; 32],
+    next_block: AtomicU64,
+    //Lowest block known to hold a prime, and that prime
+    found: Mutex<Option<(u64, BigUint)>>
+}
+
+impl Search {
+    fn found_before(&self, block: u64) -> bool {
+        matches!(*self.found.lock().unwrap(), Some((b, _)) if b < block)
+    }
+
+    //Tests one block, giving up as soon as some earlier block is known to hold a prime
+    fn run_block(&self, block: u64, test: &dyn PrimalityTest) {
+        let mut candidates = self.candidates.clone();
+        candidates.offset = 2 * BLOCK_CANDIDATES * block;
+        candidates.yield_two &= block == 0;
+        let end = &candidates.base + BigUint::from(2 * BLOCK_CANDIDATES * (block + 1));
+        let mut rng = ChaCha20Rng::from_seed(self.seed);
+        rng.set_stream(block);
+
+        for candidate in candidates.take_while(|c| *c < end) {
+            if self.found_before(block) {
+                return;
+            }
+            if test.is_prime(&candidate, &mut rng) {
+                let mut found = self.found.lock().unwrap();
+                if found.as_ref().is_none_or(|(b, _)| block < *b) {
+                    *found = Some((block, candidate));
+                }
+                return;
+            }
+        }
+    }
+}
+
+//The same primes next_prime would return for each start, with blocks of candidates from all
+//of the searches shared between worker threads. Blocks are claimed in order and a search only
+//stops once every block before the lowest one holding a prime has been tested, so the answer
+//never depends on the thread count or on scheduling.
+pub fn parallel_next_primes(starts: &[BigUint], test: &(dyn PrimalityTest + Sync), threads: usize, rng: &mut dyn CryptoRngCore) -> Vec<BigUint> {
+    //Either way one seed per start comes out of rng, so what rng produces next doesn't
+    //depend on the thread count either
+    if threads <= 1 {
+        return Vec::from_iter(starts.iter().map(|s| next_prime(s, test, &mut ChaCha20Rng::from_seed(rng.gen()))));
+    }
+
+    let searches = Vec::from_iter(starts.iter().map(|s| Search {
+        candidates: SievedCandidates::new(s),
+        seed: rng.gen(),
+        next_block: AtomicU64::new(0),
+        found: Mutex::new(None)
+    }));
+
+    std::thread::scope(|scope| {
+        for worker in 0..threads {
+            let searches = &searches;
+            scope.spawn(move || {
+                //Spread the workers over the searches, moving on once one runs dry
+                let mut i = worker % searches.len();
+                let mut idle = 0;
+                while idle < searches.len() {
+                    let search = &searches[i];
+                    let block = search.next_block.fetch_add(1, Ordering::SeqCst);
+                    if search.found_before(block) {
+                        idle += 1;
+                        i = (i + 1) % searches.len();
+                        continue;
+                    }
+                    idle = 0;
+                    search.run_block(block, test);
+                }
+            });
+        }
+    });
+
+    Vec::from_iter(searches.into_iter().map(|s| s.found.into_inner().unwrap().unwrap().1))
+}
+
+#[test]
+fn small_primes_match_primes_crate() {
+    let expected = Vec::from_iter((3..SIEVE_LIMIT as u64).filter(|i| primes::is_prime(*i)).map(|i| i as u32));
+    assert_eq!(small_primes(), expected.as_slice());
+}
+
+#[test]
+fn sieved_candidates_skip_only_composites() {
+    let survivors = Vec::from_iter(SievedCandidates::new(&BigUint::from(0u8)).take(1000));
+    let expected = Vec::from_iter((0u64..).filter(|i| primes::is_prime(*i)).take(1000).map(BigUint::from));
+    assert_eq!(survivors, expected);
+}
+
+#[test]
+fn next_prime_matches_plain_search() {
+    use crate::primality::MillerRabin;
+    use num_bigint_dig::RandBigInt;
+
+    let mut rng = rand::thread_rng();
+    for bits in [64, 256, 512] {
+        let start = rng.gen_biguint(bits);
+        let mut plain = start.clone();
+        if plain.is_even() {
+            plain += 1u8;
+        }
+        while !crate::millers::is_prime_miller(&plain) {
+            plain += 2u8;
+        }
+        assert_eq!(next_prime(&start, &MillerRabin, &mut rng), plain);
+    }
+}
+
+#[test]
+fn parallel_search_matches_sequential() {
+    use crate::primality::MillerRabin;
+    use num_bigint_dig::RandBigInt;
+
+    let mut rng = rand::thread_rng();
+    let starts = Vec::from_iter((0..3).map(|_| rng.gen_biguint(256)));
+    let expected = Vec::from_iter(starts.iter().map(|s| next_prime(s, &MillerRabin, &mut rng)));
+    for threads in [1, 2, 3, 8] {
+        assert_eq!(parallel_next_primes(&starts, &MillerRabin, threads, &mut rng), expected);
+    }
+    assert_eq!(parallel_next_primes(&[BigUint::from(0u8), BigUint::from(90u8)], &MillerRabin, 4, &mut rng),
+        vec![BigUint::from(2u8), BigUint::from(97u8)]);
+}
+
+#[test]
+fn safe_primes_have_prime_halves() {
+    use crate::primality::MillerRabin;
+    use num_bigint_dig::RandBigInt;
+
+    let mut rng = rand::thread_rng();
+    let found = Vec::from_iter([0u8, 3, 4, 6, 12].map(|s| next_safe_prime(&BigUint::from(s), &MillerRabin, &mut rng)));
+    assert_eq!(found, [5u8, 7, 11, 23, 47].map(BigUint::from));
+
+    let p = next_safe_prime(&rng.gen_biguint(128), &MillerRabin, &mut rng);
+    assert!(MillerRabin.is_prime(&p, &mut rng) && MillerRabin.is_prime(&(&p >> 1), &mut rng));
+}
+
+#[test]
+fn prime_searches_match_primes_crate() {
+    use crate::primality::MillerRabin;
+
+    let mut rng = rand::thread_rng();
+    for n in [0u64, 1, 2, 3, 4, 9, 1_000, 65_521, 65_538, 1_000_000, 4_294_967_296] {
+        let expected = (2..=n).rev().find(|i| primes::is_prime(*i)).map(BigUint::from);
+        assert_eq!(prev_prime(&BigUint::from(n), &MillerRabin, &mut rng), expected);
+    }
+
+    for bits in [2, 3, 17, 100] {
+        assert_eq!(random_prime(bits, &MillerRabin, &mut rng).bits(), bits);
+    }
+
+    let count = |a: u64, b: u64| (a..=b).filter(|i| primes::is_prime(*i)).count() as u64;
+    for (a, b) in [(0, 1), (0, 2), (0, 100), (10, 10), (97, 97), (1_000, 600_000), (5, 3)] {
+        assert_eq!(count_primes(&BigUint::from(a), &BigUint::from(b), &MillerRabin, &mut rng), count(a, b));
+    }
+    //Too short to sieve, so every candidate gets a full test instead
+    let (a, b) = (1_000_000_000_000, 1_000_000_010_000);
+    assert_eq!(count_primes(&BigUint::from(a), &BigUint::from(b), &MillerRabin, &mut rng), count_primes_sieved(a, b));
+    assert!(!worth_sieving(a, b));
+    assert!(worth_sieving(0, MAX_SIEVED));
+    assert!(!worth_sieving(0, u64::MAX));
+}
+
+//cargo test --release -- --ignored --nocapture bench_prime_search
+#[test]
+#[ignore]
+fn bench_prime_search() {
+    use crate::primality::MillerRabin;
+    use num_bigint_dig::RandBigInt;
+    use std::time::Instant;
+
+    let mut rng = rand::thread_rng();
+    for (bits, samples) in [(1024, 8), (2048, 3), (4096, 1)] {
+        let mut plain_time = 0f64;
+        let mut sieved_time = 0f64;
+        for _ in 0..samples {
+            let start = rng.gen_biguint(bits) | (BigUint::from(1u8) << (bits - 1));
+
+            let now = Instant::now();
+            let mut plain = start.clone();
+            if plain.is_even() {
+                plain += 1u8;
+            }
+            while !crate::millers::is_prime_miller(&plain) {
+                plain += 2u8;
+            }
+            plain_time += now.elapsed().as_secs_f64();
+
+            let now = Instant::now();
+            let sieved = next_prime(&start, &MillerRabin, &mut rng);
+            sieved_time += now.elapsed().as_secs_f64();
+
+            assert_eq!(plain, sieved);
+        }
+        println!("{bits} bits: plain {:.3}s, sieved {:.3}s per prime, {:.1}x faster",
+            plain_time / samples as f64, sieved_time / samples as f64, plain_time / sieved_time);
+    }
+}
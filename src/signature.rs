@@ -0,0 +1,170 @@
+use std::{io::Read, process::exit};
+
+use clio::{Input, Output};
+use num_bigint_dig::BigUint;
+use rand_core::CryptoRngCore;
+use sha2::{Digest, Sha256};
+
+use crate::{key::{PrivateKey, PublicKey}, keyring::{self, Keyring}, mainutil::{self, from_hex, read_key, to_hex}, metadata::Usage, oaep, InputArgGroup};
+
+//RSA full domain hash signatures: the message is hashed with SHA-256 and stretched with MGF1
+//to one bit less than n, and the signature is that number to the d. The context string
+//keeps a signature made for one purpose (a message, a revocation) from passing as another.
+pub const MESSAGE_CONTEXT: &str = "rsa_rust message";
+
+pub fn full_domain_hash(context: &str, message: &[u8], n: &BigUint) -> BigUint {
+    let digest = Sha256::new()
+        .chain_update(context.as_bytes())
+        .chain_update([0u8])
+        .chain_update(message)
+        .finalize();
+    let bits = n.bits() - 1;
+    let mut expanded = vec![0u8; bits.div_ceil(8)];
+    oaep::mgf1_xor(&mut expanded, &digest);
+    BigUint::from_bytes_be(&expanded) >> (expanded.len() * 8 - bits)
+}
+
+pub fn sign<R: CryptoRngCore + ?Sized>(key: &PrivateKey, context: &str, message: &[u8], rng: &mut R) -> BigUint {
+    key.private_op(&full_domain_hash(context, message, &key.n), rng).expose().clone()
+}
+
+pub fn verify(key: &PublicKey, context: &str, message: &[u8], signature: &BigUint) -> bool {
+    *signature < key.n && key.encrypt_block(signature) == full_domain_hash(context, message, &key.n)
+}
+
+//A detached message signature, armored as
+//
+//  -----BEGIN RSA_RUST SIGNATURE-----
+//  key <fingerprint of the signing key>
+//  signature <hex>
+//  -----END RSA_RUST SIGNATURE-----
+pub struct DetachedSignature {
+    pub fingerprint: String,
+    pub signature: BigUint
+}
+
+const BEGIN: &str = "-----BEGIN RSA_RUST SIGNATURE-----";
+const END: &str = "-----END RSA_RUST SIGNATURE-----";
+
+impl std::fmt::Display for DetachedSignature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{BEGIN}")?;
+        writeln!(f, "key {}", self.fingerprint)?;
+        writeln!(f, "signature {}", to_hex(&self.signature.to_bytes_be()))?;
+        writeln!(f, "{END}")
+    }
+}
+
+impl std::str::FromStr for DetachedSignature {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let inner = match s.trim().strip_prefix(BEGIN).and_then(|t| t.strip_suffix(END)) {
+            Some(inner) => inner,
+            None => return Err("Not a signature".to_string())
+        };
+        let mut fingerprint = None;
+        let mut signature = None;
+        for line in inner.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            match line.split_once(' ') {
+                Some(("key", f)) => fingerprint = Some(f.to_string()),
+                Some(("signature", hex)) => signature = from_hex(hex).map(|b| BigUint::from_bytes_be(&b)),
+                _ => return Err(format!("Unexpected signature line \"{line}\""))
+            }
+        }
+        match (fingerprint, signature) {
+            (Some(fingerprint), Some(signature)) => Ok(DetachedSignature { fingerprint, signature }),
+            _ => Err("The signature is missing its key or value".to_string())
+        }
+    }
+}
+
+//Panics if key is revoked, or has expired or isn't marked for signing and force isn't set
+pub fn check_signing_key(key: &PrivateKey, force: bool) {
+    let fingerprint = key.public().fingerprint();
+    match keyring::revocation_for(&fingerprint) {
+        Ok(Some(revocation)) => panic!("Refusing to sign with {fingerprint}, the key was {}.", revocation.describe()),
+        Ok(None) => (),
+        Err(e) => panic!("{e}")
+    }
+    if let Err(e) = key.metadata.check(Usage::Sign, mainutil::now()) {
+        if force {
+            eprintln!("Warning: signing with {fingerprint} anyway, {e}.");
+        } else {
+            panic!("Refusing to sign with {fingerprint}, {e}. Use --force to sign anyway.");
+        }
+    }
+}
+
+//The sign subcommand
+pub fn sign_message(input: InputArgGroup, mut output: Output, privkey: Option<Input>, force: bool) {
+    let message = mainutil::parse_input_group(input);
+    let key = PrivateKey::from_text(read_key(mainutil::input_or_default(privkey, "./private.txt")).expose());
+    let fingerprint = key.public().fingerprint();
+    check_signing_key(&key, force);
+
+    let signature = sign(&key, MESSAGE_CONTEXT, message.as_bytes(), &mut rand::thread_rng());
+    mainutil::write_output(&mut output, &DetachedSignature { fingerprint, signature }.to_string());
+}
+
+//The verify subcommand. Exits with 1 when the signature doesn't check out.
+pub fn verify_message(input: InputArgGroup, mut signature: Input, pubkey: Option<Input>) {
+    let message = mainutil::parse_input_group(input);
+    let mut signature_text = String::new();
+    if let Err(e) = signature.read_to_string(&mut signature_text) {
+        panic!("Failed to read the signature. Error: {e}");
+    }
+    let signature: DetachedSignature = match signature_text.parse() {
+        Ok(s) => s,
+        Err(e) => panic!("{e}")
+    };
+
+    //Without -p, the signing key comes from the keyring
+    let (key, signer) = match pubkey {
+        Some(pubkey) => (PublicKey::from_text(read_key(pubkey).expose()), signature.fingerprint.clone()),
+        None => match Keyring::existing().and_then(|k| k.find(&signature.fingerprint)) {
+            Ok(entry) => (entry.public, entry.name),
+            Err(e) => panic!("{e}. Give the signer's public key with -p.")
+        }
+    };
+    if key.fingerprint() != signature.fingerprint {
+        println!("Bad signature: it was made by {}, not the given key.", signature.fingerprint);
+        exit(1);
+    }
+
+    match keyring::revocation_for(&signature.fingerprint) {
+        Ok(Some(revocation)) => {
+            println!("Not accepting the signature, the key of {signer} was {}.", revocation.describe());
+            exit(1);
+        }
+        Ok(None) => (),
+        Err(e) => panic!("{e}")
+    }
+
+    if verify(&key, MESSAGE_CONTEXT, message.as_bytes(), &signature.signature) {
+        println!("Good signature from {signer}.");
+    } else {
+        println!("Bad signature from {signer}.");
+        exit(1);
+    }
+}
+
+#[test]
+fn signatures_verify_only_the_signed_message() {
+    use crate::key::test_key;
+
+    let mut rng = rand::thread_rng();
+    let key = test_key(512);
+    let public = key.public();
+
+    let signature = sign(&key, MESSAGE_CONTEXT, b"attack at dawn", &mut rng);
+    assert!(verify(&public, MESSAGE_CONTEXT, b"attack at dawn", &signature));
+    assert!(!verify(&public, MESSAGE_CONTEXT, b"attack at dusk", &signature));
+    assert!(!verify(&public, "rsa_rust revocation", b"attack at dawn", &signature));
+    assert!(full_domain_hash(MESSAGE_CONTEXT, b"", &key.n).bits() < key.n.bits());
+
+    let detached = DetachedSignature { fingerprint: public.fingerprint(), signature };
+    let parsed: DetachedSignature = detached.to_string().parse().unwrap();
+    assert_eq!(parsed.fingerprint, detached.fingerprint);
+    assert_eq!(parsed.signature, detached.signature);
+}
@@ -0,0 +1,498 @@
+use std::{io::Write, path::PathBuf};
+
+use clio::{ClioPath, Input, Output};
+use num_bigint_dig::{BigInt, BigUint, ModInverse, RandBigInt, Sign, ToBigInt};
+use num_integer::Integer;
+use num_traits::{One, Signed, Zero};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use rand_core::CryptoRngCore;
+use sha2::{Digest, Sha256};
+
+use crate::{envelope, generate::E, key::{self, PublicKey}, keyring, mainutil::{self, armored_fields, parse_hex, read_armored, to_hex}, metadata::KeyMetadata, oaep, primality::{MillerRabin, PrimalityTest}, secret::Secret, sieve, signature::{self, DetachedSignature}, InputArgGroup};
+
+//Threshold RSA after Shoup, "Practical Threshold Signatures" (Eurocrypt 2000). n is a product
+//of safe primes p = 2p' + 1 and q = 2q' + 1, and d = e^-1 mod m for m = p'q' is handed out as
+//Shamir shares s_i = f(i) mod m of a random polynomial with f(0) = d. The dealer forgets d,
+//p and q. Shareholder i raises x to 2 * delta * s_i, delta = l! for l shares, and proves with
+//the verification keys v and v_i = v^s_i that it used its real share. Any threshold of the
+//partial results combine to x^d without d ever existing in one place again.
+
+//Bits of the proof challenge
+const CHALLENGE_BITS: usize = 256;
+
+//The public side of a threshold key, armored as
+//
+//  -----BEGIN RSA_RUST THRESHOLD KEY-----
+//  n <hex>
+//  e <hex>
+//  threshold <partial results needed>
+//  shares <number of shares>
+//  v <hex>
+//  verification <i> <hex>
+//  ...
+//  -----END RSA_RUST THRESHOLD KEY-----
+#[derive(Debug)]
+pub struct ThresholdKey {
+    pub n: BigUint,
+    pub e: BigUint,
+    pub threshold: u8,
+    pub shares: u8,
+    v: BigUint,
+    verification: Vec<BigUint>
+}
+
+//Shareholder i's secret, armored as
+//
+//  -----BEGIN RSA_RUST THRESHOLD SHARE-----
+//  key <fingerprint>
+//  share <i>
+//  secret <hex>
+//  -----END RSA_RUST THRESHOLD SHARE-----
+#[derive(Debug)]
+pub struct ThresholdShare {
+    pub fingerprint: String,
+    pub index: u8,
+    secret: Secret<BigUint>
+}
+
+//x^(2 * delta * s_i) from shareholder i with its proof of correctness, armored as
+//
+//  -----BEGIN RSA_RUST PARTIAL RESULT-----
+//  key <fingerprint>
+//  share <i>
+//  value <hex>
+//  proof <challenge hex> <response hex>
+//  -----END RSA_RUST PARTIAL RESULT-----
+#[derive(Debug)]
+pub struct Partial {
+    pub fingerprint: String,
+    pub index: u8,
+    value: BigUint,
+    challenge: BigUint,
+    response: BigUint
+}
+
+fn factorial(l: u8) -> BigUint {
+    (1..=l as u32).fold(BigUint::one(), |acc, i| acc * i)
+}
+
+//base^exponent mod n for exponents of either sign. None when a negative exponent needs an
+//inverse that doesn't exist.
+fn pow_signed(base: &BigUint, exponent: &BigInt, n: &BigUint) -> Option<BigUint> {
+    let magnitude = exponent.abs().to_biguint()?;
+    let result = base.modpow(&magnitude, n);
+    match exponent.sign() {
+        Sign::Minus => result.mod_inverse(n)?.to_biguint(),
+        _ => Some(result)
+    }
+}
+
+//The Fiat-Shamir challenge for a proof that log_v(v_i) = log_x~(x_i^2)
+fn challenge(values: [&BigUint; 6]) -> BigUint {
+    let mut hash = Sha256::new();
+    for value in values {
+        hash.update(to_hex(&value.to_bytes_be()));
+        hash.update("\n");
+    }
+    BigUint::from_bytes_be(&hash.finalize())
+}
+
+//A fresh threshold key of about bits bits and one share for each of count shareholders
+pub fn generate<R: CryptoRngCore + ?Sized>(bits: usize, threshold: u8, count: u8, test: &dyn PrimalityTest, rng: &mut R) -> (ThresholdKey, Vec<ThresholdShare>) {
+    let e = BigUint::from(E);
+    let (n, m) = loop {
+        let half = |rng: &mut R, size: usize| {
+            let start = rng.gen_biguint(size - 1) | (BigUint::from(3u8) << (size - 3));
+            Secret::new(sieve::next_safe_prime(&start, test, &mut ChaCha20Rng::from_seed(rng.gen())))
+        };
+        let p = half(rng, bits / 2);
+        let q = half(rng, bits - bits / 2);
+        let n = p.expose() * q.expose();
+        if p.expose() != q.expose() && n.bits() == bits {
+            break (n, Secret::new((p.expose() >> 1) * (q.expose() >> 1)));
+        }
+    };
+    let m = m.expose();
+    //e is a prime bigger than any l, so it's coprime to m = p'q' unless it is p' or q'
+    let d = match (&e).mod_inverse(m).and_then(|d| d.to_biguint()) {
+        Some(d) => Secret::new(d),
+        None => return generate(bits, threshold, count, test, rng)
+    };
+
+    let mut coefficients = Secret::new(vec![d.expose().clone()]);
+    for _ in 1..threshold {
+        coefficients.expose_mut().push(rng.gen_biguint_below(m));
+    }
+    let shares = Vec::from_iter((1..=count).map(|index| {
+        let x = BigUint::from(index);
+        let mut secret = Secret::new(BigUint::zero());
+        for c in coefficients.expose().iter().rev() {
+            *secret.expose_mut() = (secret.expose() * &x + c) % m;
+        }
+        secret
+    }));
+
+    //v generates the squares mod n with overwhelming probability
+    let v = rng.gen_biguint_below(&n).modpow(&BigUint::from(2u8), &n);
+    let verification = Vec::from_iter(shares.iter().map(|s| v.modpow(s.expose(), &n)));
+    let key = ThresholdKey { n, e, threshold, shares: count, v, verification };
+    let fingerprint = key.public().fingerprint();
+    let shares = Vec::from_iter(shares.into_iter().enumerate().map(|(i, secret)| ThresholdShare {
+        fingerprint: fingerprint.clone(),
+        index: i as u8 + 1,
+        secret
+    }));
+    (key, shares)
+}
+
+impl ThresholdKey {
+    pub fn public(&self) -> PublicKey {
+        PublicKey::new(self.n.clone(), self.e.clone())
+    }
+
+    fn delta(&self) -> BigUint {
+        factorial(self.shares)
+    }
+
+    //x^4delta, the base the partial results are proven against
+    fn proof_base(&self, x: &BigUint) -> BigUint {
+        x.modpow(&(BigUint::from(4u8) * self.delta()), &self.n)
+    }
+
+    //Whether the partial result is x^(2 * delta * s_i) for the real share i
+    pub fn check_partial(&self, x: &BigUint, partial: &Partial) -> bool {
+        let n = &self.n;
+        let verification = match (partial.index as usize).checked_sub(1).and_then(|i| self.verification.get(i)) {
+            Some(v) => v,
+            None => return false
+        };
+        let base = self.proof_base(x);
+        let squared = partial.value.modpow(&BigUint::from(2u8), n);
+        let response = partial.response.to_bigint().unwrap();
+        let negated = -partial.challenge.to_bigint().unwrap();
+        let commitments = (
+            pow_signed(&self.v, &response, n).zip(pow_signed(verification, &negated, n)),
+            pow_signed(&base, &response, n).zip(pow_signed(&squared, &negated, n))
+        );
+        match commitments {
+            (Some((a, b)), Some((c, d))) => {
+                let (v_commitment, x_commitment) = (a * b % n, c * d % n);
+                partial.challenge == challenge([&self.v, &base, verification, &squared, &v_commitment, &x_commitment])
+            }
+            _ => false
+        }
+    }
+
+    //x^d mod n from at least threshold partial results, which should have passed check_partial
+    pub fn combine(&self, x: &BigUint, partials: &[Partial]) -> Result<BigUint, String> {
+        let mut partials = Vec::from_iter(partials.iter());
+        partials.sort_by_key(|p| p.index);
+        partials.dedup_by_key(|p| p.index);
+        if partials.len() < self.threshold as usize {
+            return Err(format!("The key needs {} partial results, only {} given", self.threshold, partials.len()));
+        }
+        let partials = &partials[..self.threshold as usize];
+        let n = &self.n;
+        let delta = self.delta().to_bigint().unwrap();
+
+        //w = product of x_i^(2 * lambda_i) with the integer Lagrange coefficients
+        //lambda_i = delta * product of j / (j - i), so that w^e = x^(4 * delta^2)
+        let mut w = BigUint::one();
+        for partial in partials {
+            let i = BigInt::from(partial.index);
+            let mut numerator = delta.clone();
+            let mut denominator = BigInt::one();
+            for other in partials.iter().filter(|o| o.index != partial.index) {
+                let j = BigInt::from(other.index);
+                numerator *= &j;
+                denominator *= &j - &i;
+            }
+            let lambda = numerator / denominator;
+            match pow_signed(&partial.value, &(lambda * 2), n) {
+                Some(factor) => w = w * factor % n,
+                None => return Err(format!("Partial result {} can't be used", partial.index))
+            }
+        }
+
+        //a * 4 * delta^2 + b * e = 1, and y = w^a * x^b is then the e-th root of x
+        let exponent = BigInt::from(4u8) * &delta * &delta;
+        let gcd = exponent.extended_gcd(&self.e.to_bigint().unwrap());
+        if !gcd.gcd.is_one() {
+            return Err("e shares a factor with the number of shares, which should never happen".to_string());
+        }
+        let y = match (pow_signed(&w, &gcd.x, n), pow_signed(x, &gcd.y, n)) {
+            (Some(a), Some(b)) => a * b % n,
+            _ => return Err("The partial results don't combine".to_string())
+        };
+        if y.modpow(&self.e, n) != x % n {
+            return Err("The partial results don't combine to a valid result".to_string());
+        }
+        Ok(y)
+    }
+}
+
+impl ThresholdShare {
+    //This shareholder's partial result on x with a proof that log_v(v_i) = log_x~(x_i^2)
+    pub fn partial<R: CryptoRngCore + ?Sized>(&self, key: &ThresholdKey, x: &BigUint, rng: &mut R) -> Result<Partial, String> {
+        if key.public().fingerprint() != self.fingerprint {
+            return Err(format!("This share belongs to key {}, not to the given threshold key", self.fingerprint));
+        }
+        let verification = match key.verification.get(self.index as usize - 1) {
+            Some(v) => v,
+            None => return Err(format!("The threshold key has no share {}", self.index))
+        };
+        let n = &key.n;
+        let s = self.secret.expose();
+        let value = x.modpow(&(BigUint::from(2u8) * key.delta() * s), n);
+
+        let base = key.proof_base(x);
+        let r = Secret::new(rng.gen_biguint(n.bits() + 2 * CHALLENGE_BITS));
+        let v_commitment = key.v.modpow(r.expose(), n);
+        let x_commitment = base.modpow(r.expose(), n);
+        let squared = value.modpow(&BigUint::from(2u8), n);
+        let challenge = challenge([&key.v, &base, verification, &squared, &v_commitment, &x_commitment]);
+        let response = s * &challenge + r.expose();
+        Ok(Partial { fingerprint: self.fingerprint.clone(), index: self.index, value, challenge, response })
+    }
+}
+
+fn parse_index(value: &str) -> Result<u8, String> {
+    match value.parse::<u8>() {
+        Ok(i) if i > 0 => Ok(i),
+        _ => Err(format!("Bad share number \"{value}\""))
+    }
+}
+
+fn hex(x: &BigUint) -> String {
+    to_hex(&x.to_bytes_be())
+}
+
+impl std::fmt::Display for ThresholdKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "-----BEGIN RSA_RUST THRESHOLD KEY-----")?;
+        writeln!(f, "n {}\ne {}\nthreshold {}\nshares {}\nv {}", hex(&self.n), hex(&self.e), self.threshold, self.shares, hex(&self.v))?;
+        for (i, v) in self.verification.iter().enumerate() {
+            writeln!(f, "verification {} {}", i + 1, hex(v))?;
+        }
+        writeln!(f, "-----END RSA_RUST THRESHOLD KEY-----")
+    }
+}
+
+impl std::str::FromStr for ThresholdKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (mut n, mut e, mut threshold, mut shares, mut v) = (None, None, None, None, None);
+        let mut verification = Vec::new();
+        for (field, value) in armored_fields(s, "THRESHOLD KEY")? {
+            match field {
+                "n" => n = Some(parse_hex(value)?),
+                "e" => e = Some(parse_hex(value)?),
+                "threshold" => threshold = Some(parse_index(value)?),
+                "shares" => shares = Some(parse_index(value)?),
+                "v" => v = Some(parse_hex(value)?),
+                "verification" => match value.split_once(' ') {
+                    Some((i, key)) if parse_index(i)? as usize == verification.len() + 1 => verification.push(parse_hex(key)?),
+                    _ => return Err("The verification keys are out of order".to_string())
+                },
+                _ => return Err(format!("Unexpected threshold key line \"{field}\""))
+            }
+        }
+        match (n, e, threshold, shares, v) {
+            (Some(n), Some(e), Some(threshold), Some(shares), Some(v)) if verification.len() == shares as usize && threshold <= shares => {
+                Ok(ThresholdKey { n, e, threshold, shares, v, verification })
+            }
+            _ => Err("The threshold key is missing a field or verification key".to_string())
+        }
+    }
+}
+
+impl std::fmt::Display for ThresholdShare {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "-----BEGIN RSA_RUST THRESHOLD SHARE-----")?;
+        writeln!(f, "key {}\nshare {}\nsecret {}", self.fingerprint, self.index, hex(self.secret.expose()))?;
+        writeln!(f, "-----END RSA_RUST THRESHOLD SHARE-----")
+    }
+}
+
+impl std::str::FromStr for ThresholdShare {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (mut fingerprint, mut index, mut secret) = (None, None, None);
+        for (field, value) in armored_fields(s, "THRESHOLD SHARE")? {
+            match field {
+                "key" => fingerprint = Some(value.to_string()),
+                "share" => index = Some(parse_index(value)?),
+                "secret" => secret = Some(Secret::new(parse_hex(value)?)),
+                _ => return Err(format!("Unexpected threshold share line \"{field}\""))
+            }
+        }
+        match (fingerprint, index, secret) {
+            (Some(fingerprint), Some(index), Some(secret)) => Ok(ThresholdShare { fingerprint, index, secret }),
+            _ => Err("The threshold share is missing a field".to_string())
+        }
+    }
+}
+
+impl std::fmt::Display for Partial {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "-----BEGIN RSA_RUST PARTIAL RESULT-----")?;
+        writeln!(f, "key {}\nshare {}\nvalue {}", self.fingerprint, self.index, hex(&self.value))?;
+        writeln!(f, "proof {} {}", hex(&self.challenge), hex(&self.response))?;
+        writeln!(f, "-----END RSA_RUST PARTIAL RESULT-----")
+    }
+}
+
+impl std::str::FromStr for Partial {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (mut fingerprint, mut index, mut value, mut proof) = (None, None, None, None);
+        for (field, line) in armored_fields(s, "PARTIAL RESULT")? {
+            match field {
+                "key" => fingerprint = Some(line.to_string()),
+                "share" => index = Some(parse_index(line)?),
+                "value" => value = Some(parse_hex(line)?),
+                "proof" => match line.split_once(' ') {
+                    Some((c, z)) => proof = Some((parse_hex(c)?, parse_hex(z)?)),
+                    None => return Err("The proof needs a challenge and a response".to_string())
+                },
+                _ => return Err(format!("Unexpected partial result line \"{field}\""))
+            }
+        }
+        match (fingerprint, index, value, proof) {
+            (Some(fingerprint), Some(index), Some(value), Some((challenge, response))) => {
+                Ok(Partial { fingerprint, index, value, challenge, response })
+            }
+            _ => Err("The partial result is missing a field".to_string())
+        }
+    }
+}
+
+//What a threshold operation works on: the wrapped content key of an envelope, or the full
+//domain hash of a message to sign
+fn operand(message: &str, key: &ThresholdKey, sign: bool) -> BigUint {
+    if sign {
+        return signature::full_domain_hash(signature::MESSAGE_CONTEXT, message.as_bytes(), &key.n);
+    }
+    match envelope::wrapped_key(message, &key.public().fingerprint()) {
+        Ok(wrapped) => BigUint::from_bytes_be(&wrapped),
+        Err(e) => panic!("{e}")
+    }
+}
+
+//The generate-threshold-key subcommand. Writes public.txt for encrypting and verifying as
+//usual, threshold.txt for the shareholders and combiner, and threshold-share-<i>.txt.
+pub fn generate_threshold_key(bits: usize, threshold: u8, count: u8, key_dir: Option<ClioPath>) {
+    if threshold > count {
+        panic!("The threshold ({threshold}) can't be more than the number of shares ({count})");
+    }
+    let dir = match key_dir {
+        Some(d) => d.path().to_path_buf(),
+        None => PathBuf::from(".")
+    };
+    println!("Searching for safe primes, this takes a while.");
+    let (key, shares) = generate(bits, threshold, count, &MillerRabin, &mut rand::thread_rng());
+
+    let mut public = key.public();
+    public.metadata = KeyMetadata { created: Some(mainutil::now()), ..KeyMetadata::default() };
+    let write = |name: String, text: &str, private: bool| {
+        let path = dir.join(name);
+        let res = if private { keyring::write_private(&path, text) } else { std::fs::write(&path, text) };
+        match res {
+            Ok(_) => println!("Wrote {}", path.display()),
+            Err(e) => panic!("Could not write {}: {e}", path.display())
+        }
+    };
+    write("public.txt".to_string(), &public.to_text(), false);
+    write("threshold.txt".to_string(), &key.to_string(), false);
+    for share in &shares {
+        write(format!("threshold-share-{}.txt", share.index), &share.to_string(), true);
+    }
+    println!("Any {threshold} of the {count} shareholders can decrypt or sign together, give each share to a different person.");
+}
+
+//The partial-decrypt subcommand, run by each shareholder
+pub fn partial_decrypt(input: InputArgGroup, share: Input, threshold_key: Input, sign: bool, mut output: Output) {
+    let message = mainutil::parse_input_group(input);
+    let key: ThresholdKey = read_armored(threshold_key);
+    let share: ThresholdShare = read_armored(share);
+    let x = operand(&message, &key, sign);
+    match share.partial(&key, &x, &mut rand::thread_rng()) {
+        Ok(partial) => mainutil::write_output(&mut output, &partial.to_string()),
+        Err(e) => panic!("{e}")
+    }
+}
+
+//The combine-partials subcommand. Partial results that fail their proofs are left out.
+pub fn combine_partials(input: InputArgGroup, partials: Vec<Input>, threshold_key: Input, sign: bool, mut output: Output) {
+    let message = mainutil::parse_input_group(input);
+    let key: ThresholdKey = read_armored(threshold_key);
+    let x = operand(&message, &key, sign);
+
+    let mut valid = Vec::new();
+    for input in partials {
+        let partial: Partial = read_armored(input);
+        if key.check_partial(&x, &partial) {
+            valid.push(partial);
+        } else {
+            eprintln!("Warning: leaving out partial result {}, its proof doesn't check out.", partial.index);
+        }
+    }
+    let y = match key.combine(&x, &valid) {
+        Ok(y) => Secret::new(y),
+        Err(e) => panic!("{e}")
+    };
+
+    let fingerprint = key.public().fingerprint();
+    if sign {
+        let signature = DetachedSignature { fingerprint, signature: y.expose().clone() };
+        mainutil::write_output(&mut output, &signature.to_string());
+        return;
+    }
+    let k = key::byte_len(&key.n);
+    let content_key = match oaep::decode(&key::to_bytes_padded(y.expose(), k), k) {
+        Some(c) => c,
+        None => panic!("The combined result isn't a wrapped content key")
+    };
+    match envelope::open_with_content_key(&message, content_key.expose()) {
+        Ok(plaintext) => match output.write_all(plaintext.expose()) {
+            Ok(_) => (),
+            Err(e) => panic!("Failed to write to the output. Error: {e}")
+        },
+        Err(e) => panic!("Could not decrypt: {e}")
+    }
+}
+
+#[test]
+fn any_threshold_partials_decrypt_and_sign() {
+    let mut rng = rand::thread_rng();
+    let (key, shares) = generate(256, 3, 5, &MillerRabin, &mut rng);
+    let key: ThresholdKey = key.to_string().parse().unwrap();
+    let shares = Vec::from_iter(shares.iter().map(|s| s.to_string().parse::<ThresholdShare>().unwrap()));
+    let public = key.public();
+
+    let m = rng.gen_biguint_below(&key.n);
+    let c = public.encrypt_block(&m);
+    for picked in [[0, 1, 2], [4, 1, 3]] {
+        let partials = Vec::from_iter(picked.iter().map(|&i| {
+            let partial = shares[i].partial(&key, &c, &mut rng).unwrap();
+            assert!(key.check_partial(&c, &partial));
+            partial.to_string().parse::<Partial>().unwrap()
+        }));
+        assert_eq!(key.combine(&c, &partials).unwrap(), m);
+        assert!(key.combine(&c, &partials[..2]).unwrap_err().contains("needs 3"));
+    }
+
+    //A partial result from the wrong share fails its proof
+    let mut forged = shares[0].partial(&key, &c, &mut rng).unwrap();
+    forged.index = 2;
+    assert!(!key.check_partial(&c, &forged));
+
+    let x = signature::full_domain_hash(signature::MESSAGE_CONTEXT, b"launch", &key.n);
+    let partials = Vec::from_iter(shares[2..].iter().map(|s| s.partial(&key, &x, &mut rng).unwrap()));
+    let signature = key.combine(&x, &partials).unwrap();
+    assert!(signature::verify(&public, signature::MESSAGE_CONTEXT, b"launch", &signature));
+}
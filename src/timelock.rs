@@ -0,0 +1,213 @@
+use std::{io::Write, time::{Duration, Instant}};
+
+use chacha20poly1305::{aead::{Aead, Payload}, ChaCha20Poly1305, Key, KeyInit, Nonce};
+use clio::Output;
+use num_bigint_dig::{BigUint, RandBigInt};
+use num_traits::One;
+use rand_core::CryptoRngCore;
+use sha2::{Digest, Sha256};
+
+use crate::{generate, key, mainutil::{self, from_hex, parse_hex, to_hex}, montgomery::MontgomeryContext, primality::MillerRabin, secret::Secret, InputArgGroup};
+
+//Rivest-Shamir-Wagner time-lock puzzles. The content is encrypted under a key derived from
+//a^(2^t) mod n. Whoever made n knows phi(n) and gets there with one exponentiation by
+//2^t mod phi(n), everyone else has to do the t squarings one after the other. The puzzle
+//looks like
+//
+//  -----BEGIN RSA_RUST TIMELOCK PUZZLE-----
+//  n <hex>
+//  a <hex>
+//  squarings <t>
+//  nonce <hex>
+//
+//  <ChaCha20-Poly1305 ciphertext in hex>
+//  -----END RSA_RUST TIMELOCK PUZZLE-----
+//
+//with the header lines authenticated along with the ciphertext.
+const BEGIN: &str = "-----BEGIN RSA_RUST TIMELOCK PUZZLE-----";
+const END: &str = "-----END RSA_RUST TIMELOCK PUZZLE-----";
+
+//Hex digits per line of ciphertext
+const LINE_LEN: usize = 64;
+
+//Squarings done between progress reports, as one exponentiation by 2^BATCH
+const BATCH: u64 = 10_000;
+
+struct Puzzle {
+    header: String,
+    n: BigUint,
+    a: BigUint,
+    squarings: u64,
+    nonce: Vec<u8>,
+    ciphertext: Vec<u8>
+}
+
+fn content_key(b: &BigUint, n: &BigUint) -> Secret<Vec<u8>> {
+    let padded = Secret::new(key::to_bytes_padded(b, key::byte_len(n)));
+    Secret::new(Sha256::new().chain_update("rsa_rust timelock").chain_update(padded.expose()).finalize().to_vec())
+}
+
+//x^(2^squarings) mod n the slow way, BATCH squarings at a time with progress reported after
+//each batch
+fn square_repeatedly(x: &BigUint, n: &BigUint, squarings: u64, progress: &mut dyn FnMut(u64)) -> BigUint {
+    let ctx = MontgomeryContext::new(n);
+    let square = |x: &BigUint, count: u64| {
+        let exponent = BigUint::one() << count as usize;
+        match &ctx {
+            Some(ctx) => ctx.modpow(x, &exponent),
+            None => x.modpow(&exponent, n)
+        }
+    };
+    let mut x = x % n;
+    let mut done = 0;
+    while done < squarings {
+        let count = BATCH.min(squarings - done);
+        x = square(&x, count);
+        done += count;
+        progress(done);
+    }
+    x
+}
+
+//Squarings per second modulo a random bits sized modulus, measured for about a second
+pub fn calibrate<R: CryptoRngCore + ?Sized>(bits: usize, rng: &mut R) -> u64 {
+    let n = rng.gen_biguint(bits) | (BigUint::one() << (bits - 1)) | BigUint::one();
+    let mut x = rng.gen_biguint_below(&n);
+    let start = Instant::now();
+    let mut done = 0;
+    while start.elapsed() < Duration::from_secs(1) {
+        x = square_repeatedly(&x, &n, BATCH, &mut |_| ());
+        done += BATCH;
+    }
+    (done as f64 / start.elapsed().as_secs_f64()) as u64
+}
+
+//A puzzle hiding plaintext behind that many sequential squarings modulo a fresh bits sized n
+pub fn lock<R: CryptoRngCore>(plaintext: &[u8], bits: usize, squarings: u64, rng: &mut R) -> Result<String, String> {
+    let primes = generate::random_primes(bits, 2, &MillerRabin, 1, rng);
+    let (p, q) = (&primes.expose()[0], &primes.expose()[1]);
+    let n = p * q;
+    let phi = Secret::new((p - BigUint::one()) * (q - BigUint::one()));
+    let a = rng.gen_biguint_range(&BigUint::from(2u8), &n);
+
+    //The trapdoor: a^(2^t) = a^(2^t mod phi(n)) since a is a unit mod n
+    let exponent = Secret::new(BigUint::from(2u8).modpow(&BigUint::from(squarings), phi.expose()));
+    let b = Secret::new(a.modpow(exponent.expose(), &n));
+    let content_key = content_key(b.expose(), &n);
+
+    let mut nonce = [0u8; 12];
+    rng.fill_bytes(&mut nonce);
+    let header = format!("n {}\na {}\nsquarings {squarings}\nnonce {}\n", to_hex(&n.to_bytes_be()), to_hex(&a.to_bytes_be()), to_hex(&nonce));
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(content_key.expose()));
+    let ciphertext = match cipher.encrypt(Nonce::from_slice(&nonce), Payload { msg: plaintext, aad: header.as_bytes() }) {
+        Ok(c) => to_hex(&c),
+        Err(e) => return Err(format!("Could not encrypt: {e}"))
+    };
+    let lines = Vec::from_iter(ciphertext.as_bytes().chunks(LINE_LEN).map(|l| String::from_utf8_lossy(l).into_owned()));
+    Ok(format!("{BEGIN}\n{header}\n{}\n{END}\n", lines.join("\n")))
+}
+
+fn parse(text: &str) -> Result<Puzzle, String> {
+    let text = text.replace("\r\n", "\n");
+    let inner = match text.trim().strip_prefix(BEGIN).and_then(|t| t.strip_suffix(END)) {
+        Some(inner) => inner.trim_start_matches('\n'),
+        None => return Err("Not a time-lock puzzle".to_string())
+    };
+    let (header, body) = match inner.split_once("\n\n") {
+        Some(parts) => parts,
+        None => return Err("The puzzle has no body".to_string())
+    };
+    let header = format!("{header}\n");
+
+    let (mut n, mut a, mut squarings, mut nonce) = (None, None, None, None);
+    for line in header.lines() {
+        match line.split_once(' ') {
+            Some(("n", value)) => n = Some(parse_hex(value)?),
+            Some(("a", value)) => a = Some(parse_hex(value)?),
+            Some(("squarings", value)) => squarings = value.parse::<u64>().ok(),
+            Some(("nonce", value)) => nonce = from_hex(value).filter(|n| n.len() == 12),
+            _ => return Err(format!("Unexpected header line \"{line}\""))
+        }
+    }
+    let ciphertext = match from_hex(&body.split_whitespace().collect::<String>()) {
+        Some(c) => c,
+        None => return Err("The ciphertext isn't valid hex".to_string())
+    };
+    match (n, a, squarings, nonce) {
+        (Some(n), Some(_), Some(_), Some(_)) if n <= BigUint::one() => Err("The puzzle's modulus has to be bigger than 1".to_string()),
+        (Some(n), Some(a), Some(squarings), Some(nonce)) => Ok(Puzzle { header, n, a, squarings, nonce, ciphertext }),
+        _ => Err("The puzzle is missing n, a, the number of squarings or the nonce".to_string())
+    }
+}
+
+//The content of a puzzle, after doing all of its squarings. progress hears how many are
+//done out of how many.
+pub fn solve(text: &str, progress: &mut dyn FnMut(u64, u64)) -> Result<Secret<Vec<u8>>, String> {
+    let puzzle = parse(text)?;
+    let squarings = puzzle.squarings;
+    let b = Secret::new(square_repeatedly(&puzzle.a, &puzzle.n, squarings, &mut |done| progress(done, squarings)));
+    let content_key = content_key(b.expose(), &puzzle.n);
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(content_key.expose()));
+    match cipher.decrypt(Nonce::from_slice(&puzzle.nonce), Payload { msg: &puzzle.ciphertext, aad: puzzle.header.as_bytes() }) {
+        Ok(plaintext) => Ok(Secret::new(plaintext)),
+        Err(_) => Err("The puzzle has been tampered with or is corrupt".to_string())
+    }
+}
+
+//The timelock-create subcommand
+pub fn timelock_create(input: InputArgGroup, seconds: u64, bits: usize, mut output: Output) {
+    let plaintext = Secret::new(mainutil::parse_input_group(input));
+    let mut rng = rand::thread_rng();
+    let rate = calibrate(bits, &mut rng);
+    let squarings = rate.saturating_mul(seconds).max(1);
+    eprintln!("This machine does about {rate} squarings a second, locking behind {squarings} of them.");
+
+    match lock(plaintext.expose().as_bytes(), bits, squarings, &mut rng) {
+        Ok(puzzle) => mainutil::write_output(&mut output, &puzzle),
+        Err(e) => panic!("{e}")
+    }
+}
+
+//The timelock-solve subcommand, reporting progress on stderr
+pub fn timelock_solve(input: InputArgGroup, mut output: Output) {
+    let text = mainutil::parse_input_group(input);
+    let start = Instant::now();
+    let mut last_report = Instant::now();
+    let plaintext = solve(&text, &mut |done, total| {
+        if last_report.elapsed() < Duration::from_secs(1) && done < total {
+            return;
+        }
+        last_report = Instant::now();
+        let elapsed = start.elapsed().as_secs_f64();
+        let remaining = elapsed / done as f64 * (total - done) as f64;
+        eprint!("\rSquaring: {:.1}% ({done} of {total}), about {remaining:.0}s left   ", done as f64 * 100.0 / total as f64);
+    });
+    eprintln!();
+
+    match plaintext {
+        Ok(plaintext) => match output.write_all(plaintext.expose()) {
+            Ok(_) => (),
+            Err(e) => panic!("Failed to write to the output. Error: {e}")
+        },
+        Err(e) => panic!("{e}")
+    }
+}
+
+#[test]
+fn puzzles_open_after_their_squarings() {
+    let mut rng = rand::thread_rng();
+    let puzzle = lock(b"open me later", 512, 25_000, &mut rng).unwrap();
+    let mut reports = Vec::new();
+    assert_eq!(solve(&puzzle, &mut |done, total| reports.push((done, total))).unwrap().expose(), b"open me later");
+    assert_eq!(reports, [(10_000, 25_000), (20_000, 25_000), (25_000, 25_000)]);
+
+    //Fewer squarings give the wrong key
+    let shortcut = puzzle.replace("squarings 25000", "squarings 24999");
+    assert!(solve(&shortcut, &mut |_, _| ()).is_err());
+
+    //A broken modulus is an error, not a panic
+    for n in ["00", "01"] {
+        let broken = Vec::from_iter(puzzle.lines().map(|l| if l.starts_with("n ") { format!("n {n}") } else { l.to_string() })).join("\n");
+        assert_eq!(solve(&broken, &mut |_, _| ()).unwrap_err(), "The puzzle's modulus has to be bigger than 1");
+    }
+}
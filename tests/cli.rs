@@ -0,0 +1,179 @@
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+//Runs the built binary with args inside dir, which also stands in for the home directory
+//so the real keyring is never touched
+fn run(dir: &PathBuf, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_rsa_rust"))
+        .current_dir(dir)
+        .env("HOME", dir)
+        .env_remove("XDG_DATA_HOME")
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+//A fresh empty directory for one test
+fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rsa_rust-{name}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+//Runs the binary and returns its stdout, panicking with stderr if it failed
+fn stdout(dir: &PathBuf, args: &[&str]) -> String {
+    let out = run(dir, args);
+    assert!(out.status.success(), "{args:?} failed: {}", String::from_utf8_lossy(&out.stderr));
+    String::from_utf8(out.stdout).unwrap()
+}
+
+//Writes a seeded 1024-bit key pair into dir
+fn key_pair(dir: &PathBuf) {
+    stdout(dir, &["generate-keys", "-d", ".", "--bits", "1024", "--seed", "1"]);
+}
+
+#[test]
+fn generate_keys_rejects_small_keys() {
+    let dir = scratch("tiny-primes");
+    for args in [["--bits", "200", "--primes", "4"], ["--bits", "127", "--scheme", "rabin"]] {
+        let out = run(&dir, &[&["generate-keys", "-d", "."], &args[..]].concat());
+        assert_eq!(out.status.code(), Some(2));
+        assert!(String::from_utf8_lossy(&out.stderr).contains("at least 64 bits"));
+    }
+    let out = run(&dir, &["generate-keys", "-d", ".", "--bits", "512"]);
+    assert_eq!(out.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("at least 784 bits"));
+    let out = run(&dir, &["generate-keys", "-d", ".", "--bits", "784", "--primes", "4", "--seed", "1"]);
+    assert!(out.status.success());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn encrypt_decrypt_through_stdout() {
+    let dir = scratch("encrypt");
+    key_pair(&dir);
+    let encrypted = stdout(&dir, &["encrypt", "-i", "hello there", "-p", "public.txt", "--seed", "2"]);
+    std::fs::write(dir.join("msg.txt"), &encrypted).unwrap();
+    assert_eq!(stdout(&dir, &["decrypt", "-f", "msg.txt", "-P", "private.txt"]), "hello there");
+
+    //Stray text in front of the armor, or input that isn't blocks, is an error and not garbage
+    std::fs::write(dir.join("bad.txt"), format!("Read 900 bytes\n{encrypted}")).unwrap();
+    assert!(!run(&dir, &["decrypt", "-f", "bad.txt", "-P", "private.txt"]).status.success());
+    assert!(!run(&dir, &["decrypt", "-i", "not-a-message", "-P", "private.txt"]).status.success());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn sign_verify_through_stdout() {
+    let dir = scratch("sign");
+    key_pair(&dir);
+    std::fs::write(dir.join("sig.txt"), stdout(&dir, &["sign", "-i", "hello", "-P", "private.txt"])).unwrap();
+    assert!(stdout(&dir, &["verify", "-i", "hello", "-s", "sig.txt", "-p", "public.txt"]).contains("Good signature"));
+    assert_eq!(run(&dir, &["verify", "-i", "hellO", "-s", "sig.txt", "-p", "public.txt"]).status.code(), Some(1));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn plain_key_files_leave_the_keyring_alone() {
+    let dir = scratch("no-keyring");
+    key_pair(&dir);
+
+    //Without a home directory there's no keyring, which is fine when keys are given as files
+    let out = Command::new(env!("CARGO_BIN_EXE_rsa_rust"))
+        .current_dir(&dir)
+        .env_remove("HOME")
+        .env_remove("XDG_DATA_HOME")
+        .args(["sign", "-i", "hello", "-P", "private.txt"])
+        .output()
+        .unwrap();
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+
+    //Encrypting and signing don't create one either
+    stdout(&dir, &["encrypt", "-i", "hello", "-p", "public.txt"]);
+    stdout(&dir, &["sign", "-i", "hello", "-P", "private.txt"]);
+    let keyring = dir.join(".local").join("share").join("rsa_rust");
+    assert!(!keyring.exists());
+
+    //A broken entry for some other key doesn't get in the way
+    std::fs::create_dir_all(keyring.join("00ff")).unwrap();
+    std::fs::write(keyring.join("00ff").join("meta.txt"), "name: broken").unwrap();
+    std::fs::write(keyring.join("00ff").join("public.txt"), "not a key").unwrap();
+    stdout(&dir, &["encrypt", "-i", "hello", "-p", "public.txt"]);
+    stdout(&dir, &["sign", "-i", "hello", "-P", "private.txt"]);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn threshold_decrypt_and_sign_through_the_binary() {
+    let dir = scratch("threshold");
+    for bits in ["4", "512"] {
+        let out = run(&dir, &["generate-threshold-key", "--bits", bits, "-t", "2", "-s", "3"]);
+        assert_eq!(out.status.code(), Some(2));
+    }
+    stdout(&dir, &["generate-threshold-key", "--bits", "784", "-t", "2", "-s", "3"]);
+    std::fs::write(dir.join("msg.txt"), stdout(&dir, &["encrypt", "-i", "meet at noon", "-p", "public.txt"])).unwrap();
+
+    for share in ["1", "3"] {
+        let partial = stdout(&dir, &["partial-decrypt", "-f", "msg.txt", "-S", &format!("threshold-share-{share}.txt")]);
+        std::fs::write(dir.join(format!("partial-{share}.txt")), partial).unwrap();
+        let partial = stdout(&dir, &["partial-decrypt", "-i", "hello", "--sign", "-S", &format!("threshold-share-{share}.txt")]);
+        std::fs::write(dir.join(format!("signed-{share}.txt")), partial).unwrap();
+    }
+    assert_eq!(stdout(&dir, &["combine-partials", "-f", "msg.txt", "partial-1.txt", "partial-3.txt"]), "meet at noon");
+    assert!(!run(&dir, &["combine-partials", "-f", "msg.txt", "partial-1.txt"]).status.success());
+
+    let signature = stdout(&dir, &["combine-partials", "-i", "hello", "--sign", "signed-1.txt", "signed-3.txt"]);
+    std::fs::write(dir.join("sig.txt"), signature).unwrap();
+    assert!(stdout(&dir, &["verify", "-i", "hello", "-s", "sig.txt", "-p", "public.txt"]).contains("Good signature"));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn blind_sign_unblind_through_stdout() {
+    let dir = scratch("blind");
+    key_pair(&dir);
+    std::fs::write(dir.join("blinded.txt"), stdout(&dir, &["blind", "-i", "ballot 17"])).unwrap();
+    std::fs::write(dir.join("blind-sig.txt"), stdout(&dir, &["sign-blinded", "-f", "blinded.txt"])).unwrap();
+    std::fs::write(dir.join("sig.txt"), stdout(&dir, &["unblind", "-i", "ballot 17", "-s", "blind-sig.txt"])).unwrap();
+    assert!(stdout(&dir, &["verify", "-i", "ballot 17", "-s", "sig.txt", "-p", "public.txt"]).contains("Good signature"));
+    assert!(!run(&dir, &["unblind", "-i", "ballot 18", "-s", "blind-sig.txt"]).status.success());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn textbook_blocks_multiply_homomorphically() {
+    let dir = scratch("textbook");
+    key_pair(&dir);
+    //In the textbook alphabet c is 10 and b is 9, and 90 is written ",m"
+    std::fs::write(dir.join("c.txt"), stdout(&dir, &["encrypt", "--textbook", "-i", "c", "-p", "public.txt"])).unwrap();
+    std::fs::write(dir.join("b.txt"), stdout(&dir, &["encrypt", "--textbook", "-i", "b", "-p", "public.txt"])).unwrap();
+    std::fs::write(dir.join("product.txt"), stdout(&dir, &["homomorphic-multiply", "c.txt", "b.txt"])).unwrap();
+    assert_eq!(stdout(&dir, &["decrypt", "-f", "product.txt", "-P", "private.txt"]), ",m");
+
+    //Longer messages are split over several blocks
+    let long = "The quick brown fox jumps over the lazy dog. ".repeat(10);
+    std::fs::write(dir.join("long.txt"), stdout(&dir, &["encrypt", "--textbook", "-i", &long, "-p", "public.txt"])).unwrap();
+    assert_eq!(stdout(&dir, &["decrypt", "-f", "long.txt", "-P", "private.txt"]), long);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn timelock_create_rejects_tiny_moduli() {
+    let dir = scratch("timelock");
+    let out = run(&dir, &["timelock-create", "--seconds", "1", "--bits", "3", "-i", "hi"]);
+    assert_eq!(out.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("at least 64 bits"));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn revocations_need_the_certify_usage() {
+    let dir = scratch("certify");
+    key_pair(&dir);
+    assert!(stdout(&dir, &["generate-revocation"]).contains("BEGIN RSA_RUST REVOCATION"));
+
+    stdout(&dir, &["generate-keys", "-d", ".", "--bits", "1024", "--seed", "1", "--usage", "encrypt,sign"]);
+    assert!(!run(&dir, &["generate-revocation"]).status.success());
+    std::fs::remove_dir_all(&dir).unwrap();
+}